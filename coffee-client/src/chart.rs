@@ -0,0 +1,177 @@
+// Bits for drawing charts in the terminal. Everything is drawn as plain text
+// when stdout isn't a terminal, so the output can be piped somewhere sensible.
+
+use ansi_term::{Colour, Style as Ansi};
+
+// Used when we can't ask the terminal how wide it is.
+const DEFAULT_WIDTH: usize = 80;
+// Bars never get narrower than this, however long the labels are.
+const MIN_BAR_WIDTH: usize = 10;
+
+static SPARKS: &[char] = &['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+static PLAIN_SPARKS: &[char] = &['_', '.', '-', '~', '=', '+', '*', '#'];
+// Eighths of a block, for the end of a bar.
+static PARTIAL_BLOCKS: &[char] = &[' ', '▏', '▎', '▍', '▌', '▋', '▊', '▉'];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Style {
+    pub width: usize,
+    pub colour: bool,
+    // Whether to draw with block characters rather than plain ASCII.
+    pub unicode: bool,
+}
+
+impl Style {
+    // Works out what stdout can handle. NO_COLOR turns colour off even on a
+    // terminal.
+    pub fn detect() -> Self {
+        if atty::is(atty::Stream::Stdout) {
+            Style {
+                // Filling the last column makes some terminals wrap.
+                width: term_size::dimensions_stdout()
+                    .map(|(w, _)| w.saturating_sub(1))
+                    .unwrap_or(DEFAULT_WIDTH),
+                colour: std::env::var_os("NO_COLOR").is_none(),
+                unicode: true,
+            }
+        } else {
+            Style::plain()
+        }
+    }
+
+    pub fn plain() -> Self {
+        Style {
+            width: DEFAULT_WIDTH,
+            colour: false,
+            unicode: false,
+        }
+    }
+
+    fn paint(&self, style: Ansi, s: &str) -> String {
+        if self.colour {
+            style.paint(s).to_string()
+        } else {
+            s.to_string()
+        }
+    }
+}
+
+pub fn heading(title: &str, style: &Style) -> String {
+    if style.colour {
+        style.paint(Ansi::new().bold().underline(), title)
+    } else {
+        format!("{}\n{}", title, "-".repeat(title.chars().count()))
+    }
+}
+
+// One character per value, scaled between zero and the largest.
+pub fn sparkline(values: &[i64], style: &Style) -> String {
+    let sparks = if style.unicode { SPARKS } else { PLAIN_SPARKS };
+    let max = values.iter().copied().max().unwrap_or(0);
+    let line: String = values
+        .iter()
+        .map(|&v| {
+            if max <= 0 || v <= 0 {
+                sparks[0]
+            } else {
+                sparks[((v * (sparks.len() as i64 - 1) + max - 1) / max) as usize]
+            }
+        })
+        .collect();
+    style.paint(Colour::Yellow.normal(), &line)
+}
+
+// A horizontal bar for each labelled value, scaled to fit the width.
+pub fn bar_chart(rows: &[(String, i64)], style: &Style) -> String {
+    let label_width = rows
+        .iter()
+        .map(|(l, _)| l.chars().count())
+        .max()
+        .unwrap_or(0);
+    let value_width = rows
+        .iter()
+        .map(|(_, v)| v.to_string().len())
+        .max()
+        .unwrap_or(0);
+    let bar_width = style
+        .width
+        .saturating_sub(label_width + value_width + 2)
+        .max(MIN_BAR_WIDTH);
+    let max = rows.iter().map(|(_, v)| *v).max().unwrap_or(0);
+
+    let mut out = String::new();
+    for (label, value) in rows {
+        let bar = bar(*value, max, bar_width, style);
+        out.push_str(&format!(
+            "{:>lw$} {} {:>vw$}\n",
+            label,
+            style.paint(Colour::Cyan.normal(), &bar),
+            value,
+            lw = label_width,
+            vw = value_width
+        ));
+    }
+    out
+}
+
+// A bar `width` long for `max`, padded so the values after it line up.
+fn bar(value: i64, max: i64, width: usize, style: &Style) -> String {
+    if max <= 0 || value <= 0 {
+        return " ".repeat(width);
+    }
+    let mut bar = if style.unicode {
+        let eighths = (value as usize * width * 8) / max as usize;
+        let mut bar = "█".repeat(eighths / 8);
+        let partial = eighths % 8;
+        if partial > 0 {
+            bar.push(PARTIAL_BLOCKS[partial]);
+        }
+        bar
+    } else {
+        "#".repeat(value as usize * width / max as usize)
+    };
+    // Anything above zero gets at least a sliver.
+    if bar.is_empty() {
+        bar.push(if style.unicode { '▏' } else { '#' });
+    }
+    let pad = width.saturating_sub(bar.chars().count());
+    bar + &" ".repeat(pad)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_sparkline() {
+        let style = Style::plain();
+        assert_eq!(sparkline(&[0, 1, 7, 0], &style), "_.#_");
+        assert_eq!(sparkline(&[], &style), "");
+        let style = Style {
+            unicode: true,
+            ..style
+        };
+        assert_eq!(sparkline(&[0, 4, 8], &style), "▁▅█");
+    }
+
+    #[test]
+    pub fn test_bar_chart_fits() {
+        let rows = vec![("Mon".to_string(), 10), ("Tuesday".to_string(), 5)];
+        for &unicode in &[true, false] {
+            let style = Style {
+                width: 40,
+                colour: false,
+                unicode,
+            };
+            let chart = bar_chart(&rows, &style);
+            for line in chart.lines() {
+                assert_eq!(line.chars().count(), 40, "{:?}", line);
+            }
+        }
+
+        // Plain output has nothing a pipe wouldn't want.
+        let chart = bar_chart(&rows, &Style::plain());
+        assert!(chart.is_ascii());
+        assert!(chart.starts_with("    Mon ###"));
+    }
+}
@@ -0,0 +1,212 @@
+// `coffee completions <SHELL>`, clap's completion script for the command line
+// with a little added so profile names and drinks complete too. clap's scripts
+// only know what's in the App, so each gets a wrapper that first passes the
+// two words before the cursor to the hidden `coffee complete`, unless a flag
+// is being typed. That prints the names if they're what goes next, or exits
+// non-zero to leave it to clap.
+
+use crate::config::CoffeeConfig;
+
+use clap::{App, AppSettings, ArgMatches, Shell};
+use coffee_sdk::{Client, RetryPolicy};
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+// Completion happens as you type, so the server only gets this long to say
+// what drinks there are.
+const DRINKS_TIMEOUT: Duration = Duration::from_secs(2);
+
+const BASH: &str = r#"
+_coffee_names() {
+    local names
+    if (( COMP_CWORD >= 2 )) && [[ ${COMP_WORDS[COMP_CWORD]} != -* ]] \
+        && names=$(coffee complete -- "${COMP_WORDS[COMP_CWORD-2]}" "${COMP_WORDS[COMP_CWORD-1]}" 2>/dev/null); then
+        COMPREPLY=($(compgen -W "${names}" -- "${COMP_WORDS[COMP_CWORD]}"))
+    else
+        _coffee "$@"
+    fi
+}
+
+complete -F _coffee_names -o bashdefault -o default coffee
+"#;
+
+const ZSH: &str = r#"
+_coffee() {
+    local names
+    if (( CURRENT > 2 )) && [[ $PREFIX != -* ]] \
+        && names=$(coffee complete -- "${words[CURRENT-2]}" "${words[CURRENT-1]}" 2>/dev/null); then
+        compadd -- ${(f)names}
+    else
+        _coffee_clap "$@"
+    fi
+}
+
+_coffee "$@"
+"#;
+
+// The condition keeps the names for the completion to use, so `coffee
+// complete` only runs once per Tab.
+const FISH: &str = r#"
+function __coffee_names
+    set -l words (commandline -opc)
+    test (count $words) -ge 2; or return 1
+    set -g __coffee_name_list (coffee complete -- $words[-2] $words[-1] 2>/dev/null)
+end
+
+complete -c coffee -f -n '__coffee_names' -a '$__coffee_name_list'
+"#;
+
+/// What goes after `before prev`, when it's something only the config or the
+/// server knows.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Names {
+    Profiles,
+    Drinks,
+}
+
+fn names_after(before: &str, prev: &str) -> Option<Names> {
+    match (before, prev) {
+        (_, "-p") | (_, "--profile") => Some(Names::Profiles),
+        ("profile", "use") | ("profile", "remove") => Some(Names::Profiles),
+        (_, "--preset") | (_, "--default-drink") => Some(Names::Drinks),
+        ("drinks", "rm") => Some(Names::Drinks),
+        // `drinks add` and `profile add` are naming something new.
+        ("drinks", "add") | ("profile", "add") => None,
+        (_, "add") => Some(Names::Drinks),
+        _ => None,
+    }
+}
+
+/// Writes the completion script for `shell`. PowerShell and Elvish only get
+/// clap's, without the names.
+pub fn generate(mut app: App, shell: Shell, out: &mut impl Write) -> std::io::Result<()> {
+    // clap 2 leaves hidden subcommands in, and `complete` isn't for people.
+    app.p
+        .subcommands
+        .retain(|s| !s.p.is_set(AppSettings::Hidden));
+    let mut script = Vec::new();
+    app.gen_completions_to("coffee", shell, &mut script);
+    let script = String::from_utf8_lossy(&script);
+
+    match shell {
+        Shell::Bash => write!(out, "{}{}", script, BASH),
+        // clap's _coffee is renamed so ours can be the one zsh calls, and its
+        // call at the end is left to ours.
+        Shell::Zsh => {
+            let script = script
+                .replacen("\n_coffee() {", "\n_coffee_clap() {", 1)
+                .trim_end()
+                .trim_end_matches("_coffee \"$@\"")
+                .to_string();
+            write!(out, "{}{}", script, ZSH)
+        }
+        Shell::Fish => write!(out, "{}{}", script, FISH),
+        Shell::PowerShell | Shell::Elvish => write!(out, "{}", script),
+    }
+}
+
+/// `coffee complete -- <BEFORE> <PREV>`, printing one name a line. Exits with 1
+/// when the words aren't followed by a name, and otherwise prints what it can
+/// find, which may be nothing.
+pub async fn complete(config_path: &Path, matches: &ArgMatches<'_>, cmd: &ArgMatches<'_>) {
+    let what = names_after(
+        cmd.value_of("BEFORE").unwrap_or_default(),
+        cmd.value_of("PREV").unwrap_or_default(),
+    );
+    let what = match what {
+        Some(w) => w,
+        None => std::process::exit(1),
+    };
+    let config = CoffeeConfig::load(config_path).unwrap_or_default();
+
+    let names = match what {
+        Names::Profiles => config.profiles.keys().cloned().collect(),
+        Names::Drinks => {
+            let profile = config.profile(matches.value_of("profile"));
+            let mut names = tokio::time::timeout(DRINKS_TIMEOUT, drinks(matches, cmd, &config))
+                .await
+                .unwrap_or_default();
+            // Offline there's still the profile's usual.
+            if let Some(drink) = profile.and_then(|p| p.default_drink.clone()) {
+                if !names.contains(&drink) {
+                    names.push(drink);
+                }
+            }
+            names
+        }
+    };
+    for name in names {
+        println!("{}", name);
+    }
+}
+
+// The drinks the server has for us, or none if it can't be asked.
+async fn drinks(
+    matches: &ArgMatches<'_>,
+    cmd: &ArgMatches<'_>,
+    config: &CoffeeConfig,
+) -> Vec<String> {
+    let profile = config.profile(matches.value_of("profile"));
+    let mut server = match crate::server_for(matches, profile) {
+        Ok(s) => s,
+        Err(_) => return Vec::new(),
+    };
+    server.timeout = Some(DRINKS_TIMEOUT);
+    server.retry = RetryPolicy::never();
+    let api_key = match crate::get_api_key(profile, cmd) {
+        Ok(k) => k,
+        Err(_) => return Vec::new(),
+    };
+    let client = match Client::connect(&server).await {
+        Ok(c) => c.with_api_key(api_key),
+        Err(_) => return Vec::new(),
+    };
+    client
+        .list_drinks()
+        .await
+        .map(|drinks| drinks.into_iter().map(|d| d.name).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_names_after() {
+        assert_eq!(names_after("coffee", "add"), Some(Names::Drinks));
+        assert_eq!(names_after("work", "add"), Some(Names::Drinks));
+        assert_eq!(names_after("drinks", "add"), None);
+        assert_eq!(names_after("drinks", "rm"), Some(Names::Drinks));
+        assert_eq!(names_after("coffee", "-p"), Some(Names::Profiles));
+        assert_eq!(names_after("profile", "use"), Some(Names::Profiles));
+        assert_eq!(names_after("add", "--at"), None);
+        assert_eq!(names_after("coffee", "list"), None);
+    }
+
+    #[test]
+    pub fn test_generate() {
+        let mut zsh = Vec::new();
+        generate(crate::cli(), Shell::Zsh, &mut zsh).unwrap();
+        let zsh = String::from_utf8(zsh).unwrap();
+        assert!(zsh.contains("\n_coffee_clap() {"));
+        assert!(zsh.trim_end().ends_with("_coffee \"$@\""));
+        assert_eq!(zsh.matches("\n_coffee() {").count(), 1);
+        assert!(!zsh.contains("(complete)"));
+
+        let mut bash = Vec::new();
+        generate(crate::cli(), Shell::Bash, &mut bash).unwrap();
+        let bash = String::from_utf8(bash).unwrap();
+        assert!(bash
+            .trim_end()
+            .ends_with("complete -F _coffee_names -o bashdefault -o default coffee"));
+
+        let mut fish = Vec::new();
+        generate(crate::cli(), Shell::Fish, &mut fish).unwrap();
+        let fish = String::from_utf8(fish).unwrap();
+        // The server is only asked once per Tab.
+        assert_eq!(fish.matches("(coffee complete --").count(), 1);
+        assert_eq!(fish.matches("(__coffee_names)").count(), 0);
+    }
+}
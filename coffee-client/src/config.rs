@@ -0,0 +1,286 @@
+// The client config at ~/.coffee, a set of named profiles so one client can
+// talk to several servers, e.g. one at work and one at home.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// The profile that a config from before profiles existed is moved into.
+pub static DEFAULT_PROFILE: &str = "default";
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TlsSettings {
+    // A PEM file to trust the server's certificate with, for servers using
+    // their own CA.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_cert: Option<String>,
+    // The name to check the server's certificate against, if it isn't the
+    // host we connect to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+}
+
+impl TlsSettings {
+    pub fn enabled(&self) -> bool {
+        self.ca_cert.is_some() || self.domain.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Profile {
+    // host:port, or a full http(s):// URL.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    pub tls: TlsSettings,
+    // What `add` adds when not told.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_drink: Option<String>,
+    // Seconds to wait on each call to the server before giving up on it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    // How many times a call that failed for a passing reason is tried again.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u32>,
+    // How `coffee prompt` shows today's coffee, and how often, in seconds,
+    // it has the server asked again.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_refresh: Option<u64>,
+    // Shots a day. The add that goes over it runs the limit_exceeded hook.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub daily_limit: Option<i32>,
+    #[serde(skip_serializing_if = "Hooks::is_empty")]
+    pub hooks: Hooks,
+}
+
+// Shell commands to run after things happen, see hooks.rs.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Hooks {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub add: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub undo: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit_exceeded: Option<String>,
+    // Seconds a hook can run for before it's stopped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+}
+
+impl Hooks {
+    pub fn is_empty(&self) -> bool {
+        *self == Hooks::default()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CoffeeConfig {
+    // The profile used when --profile isn't given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current: Option<String>,
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+impl CoffeeConfig {
+    /// Reads the config at `path`, which doesn't have to exist yet. A config
+    /// from before profiles is moved into the default profile and saved back.
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        if !path.exists() {
+            return Ok(CoffeeConfig::default());
+        }
+        let value: Value = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        match migrate(&value) {
+            Some(config) => {
+                config.save(path)?;
+                Ok(config)
+            }
+            None => Ok(serde_json::from_value(value)?),
+        }
+    }
+
+    /// Saves the config readable only by the user, as it holds API keys. It's
+    /// written to a temporary file first so a crash can't leave half a config
+    /// behind.
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let mut tmp = path.to_path_buf().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+
+        let mut writer = BufWriter::new(create_private(&tmp)?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        drop(writer);
+
+        fs::rename(&tmp, path)
+    }
+
+    /// The profile `name`, or the current one when there's no name.
+    pub fn profile(&self, name: Option<&str>) -> Option<&Profile> {
+        self.profiles.get(name.or(self.current.as_deref())?)
+    }
+
+    /// Like `profile`, but creates the profile if it doesn't exist, making it
+    /// the current one if there wasn't one already.
+    pub fn profile_mut(&mut self, name: Option<&str>) -> &mut Profile {
+        let name = name
+            .or(self.current.as_deref())
+            .unwrap_or(DEFAULT_PROFILE)
+            .to_string();
+        if self.current.is_none() {
+            self.current = Some(name.clone());
+        }
+        self.profiles.entry(name).or_default()
+    }
+
+    /// Saves `api_key` in the profile `name`, which is made if need be. A new
+    /// profile also remembers the `server` the key was checked against.
+    pub fn log_in(&mut self, name: Option<&str>, api_key: String, server: Option<&str>) {
+        let p = self.profile_mut(name);
+        p.api_key = Some(api_key);
+        if p.server.is_none() {
+            p.server = server.map(String::from);
+        }
+    }
+
+    /// Removes the API key from the profile `name`, returning whether it had
+    /// one.
+    pub fn log_out(&mut self, name: Option<&str>) -> bool {
+        let name = name.or(self.current.as_deref()).map(String::from);
+        match name.and_then(|n| self.profiles.get_mut(&n)) {
+            Some(p) => p.api_key.take().is_some(),
+            None => false,
+        }
+    }
+}
+
+// The old config was just {"api_key": "..."}.
+fn migrate(value: &Value) -> Option<CoffeeConfig> {
+    let api_key = value.get("api_key")?.as_str()?;
+    if value.get("profiles").is_some() {
+        return None;
+    }
+
+    let mut config = CoffeeConfig::default();
+    config.profile_mut(Some(DEFAULT_PROFILE)).api_key = Some(api_key.into());
+    Some(config)
+}
+
+// Creates `path`, or empties it, readable and writable only by the user.
+#[cfg(unix)]
+fn create_private(path: &Path) -> std::io::Result<File> {
+    use std::fs::{OpenOptions, Permissions};
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // The mode is only used when the file is created.
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    Ok(file)
+}
+
+// On Windows the file takes on the access rules of the folder it's in, and
+// the home folder is already private to the user.
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> std::io::Result<File> {
+    File::create(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_migrate() {
+        let old = serde_json::json!({ "api_key": "abc" });
+        let config = migrate(&old).unwrap();
+        assert_eq!(config.current.as_deref(), Some(DEFAULT_PROFILE));
+        let profile = config.profile(None).unwrap();
+        assert_eq!(profile.api_key.as_deref(), Some("abc"));
+        assert_eq!(profile.server, None);
+
+        // A config with profiles is left alone.
+        let new = serde_json::to_value(&config).unwrap();
+        assert!(migrate(&new).is_none());
+    }
+
+    #[test]
+    pub fn test_profiles() {
+        let mut config = CoffeeConfig::default();
+        assert!(config.profile(None).is_none());
+
+        config.profile_mut(Some("work")).server = Some("coffee.work:50051".into());
+        config.profile_mut(Some("home")).api_key = Some("def".into());
+        // The first profile made becomes the current one.
+        assert_eq!(config.current.as_deref(), Some("work"));
+        assert_eq!(
+            config.profile(None).unwrap().server.as_deref(),
+            Some("coffee.work:50051")
+        );
+        assert_eq!(
+            config.profile(Some("home")).unwrap().api_key.as_deref(),
+            Some("def")
+        );
+        assert!(config.profile(Some("nope")).is_none());
+    }
+
+    #[test]
+    pub fn test_save_is_private() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("coffee_config_test_{}", std::process::id()));
+
+        let mut config = CoffeeConfig::default();
+        config.profile_mut(None).api_key = Some("secret".into());
+        config.save(&path).unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert_eq!(CoffeeConfig::load(&path).unwrap(), config);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    pub fn test_login() {
+        let mut config = CoffeeConfig::default();
+
+        // A new profile takes the server the key was checked against...
+        config.log_in(Some("work"), "abc".into(), Some("coffee.work:50051"));
+        let work = config.profile(Some("work")).unwrap();
+        assert_eq!(work.api_key.as_deref(), Some("abc"));
+        assert_eq!(work.server.as_deref(), Some("coffee.work:50051"));
+        assert_eq!(config.current.as_deref(), Some("work"));
+
+        // ...while one that has a server keeps it, and just gets the new key.
+        config.log_in(None, "def".into(), Some("elsewhere:50051"));
+        let work = config.profile(None).unwrap();
+        assert_eq!(work.api_key.as_deref(), Some("def"));
+        assert_eq!(work.server.as_deref(), Some("coffee.work:50051"));
+
+        assert!(config.log_out(None));
+        assert_eq!(config.profile(Some("work")).unwrap().api_key, None);
+        assert_eq!(
+            config.profile(Some("work")).unwrap().server.as_deref(),
+            Some("coffee.work:50051")
+        );
+        assert!(!config.log_out(None));
+        assert!(!config.log_out(Some("home")));
+    }
+}
@@ -0,0 +1,652 @@
+// The full-screen `coffee tui` dashboard. It keeps the same view of the
+// coffees as `list` does, the server's along with anything still in the
+// journal, and redraws whenever the server says something new was logged.
+//
+// Adds made here wait in the journal for a few seconds before being sent, so
+// an accidental one can be taken back before the server ever sees it. After
+// that, undo asks the server to take back the last change, as `coffee undo`
+// does.
+
+use crate::error::ClientError;
+use crate::journal::{Journal, PendingCoffee};
+use crate::{
+    describe_coffee, describe_undo, flush_journal, is_offline, journal_path, pending_coffee,
+};
+
+use coffee_sdk::{Client, WatchEvent};
+
+use chrono::prelude::*;
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use std::io::Stdout;
+use std::panic::PanicHookInfo;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::stream::StreamExt;
+use tokio::sync::mpsc;
+use tui::backend::{Backend, CrosstermBackend};
+use tui::layout::{Constraint, Direction, Layout, Rect};
+use tui::style::{Color, Modifier, Style};
+use tui::text::{Span, Spans};
+use tui::widgets::{BarChart, Block, Borders, Gauge, List, ListItem, Paragraph};
+use tui::{Frame, Terminal};
+
+// Days the chart can cover, cycled through with the arrow keys.
+static RANGES: &[i64] = &[7, 30, 90];
+const DEFAULT_RANGE: usize = 1;
+
+// How long an add waits to be sent to the server.
+const UNDO_WINDOW: Duration = Duration::from_secs(5);
+const TICK: Duration = Duration::from_millis(250);
+// Refreshed this often even without hearing from the server, so the day rolls
+// over and the caffeine level keeps falling.
+const REFRESH: Duration = Duration::from_secs(60);
+const RECENT_ENTRIES: usize = 10;
+
+// A rough figure for a single espresso shot, and how long the body takes to
+// get rid of half of it.
+const CAFFEINE_PER_SHOT_MG: f64 = 63.0;
+const CAFFEINE_HALF_LIFE_HOURS: f64 = 5.0;
+// The commonly quoted safe daily amount for adults, used to scale the gauge.
+const CAFFEINE_DAILY_LIMIT_MG: f64 = 400.0;
+
+/// Roughly how much caffeine is still in the system at `now` from `coffees`,
+/// given as (time, shots).
+pub fn caffeine_level(coffees: impl Iterator<Item = (i64, i32)>, now: i64) -> f64 {
+    coffees
+        .filter(|(t, _)| *t <= now)
+        .map(|(t, shots)| {
+            let hours = (now - t) as f64 / 3600.0;
+            shots as f64 * CAFFEINE_PER_SHOT_MG * 0.5f64.powf(hours / CAFFEINE_HALF_LIFE_HOURS)
+        })
+        .sum()
+}
+
+// What a key press asks for.
+#[derive(Debug, PartialEq)]
+enum Action {
+    Quit,
+    Add,
+    Undo,
+    Refresh,
+    // The range was changed, so there's more or less to fetch.
+    Range,
+    Nothing,
+}
+
+// What's on screen, kept apart from the connection so it can be drawn and
+// steered without one.
+struct View {
+    // Shots, and the drink they're from if it was given by name.
+    preset: (i32, Option<String>),
+    range: usize,
+    // (time, shots, still pending) in time order.
+    coffees: Vec<(i64, i32, bool)>,
+    status: String,
+}
+
+impl View {
+    fn preset_name(&self) -> String {
+        match &self.preset {
+            (shots, Some(drink)) => format!("{} ({} shot(s))", drink, shots),
+            (shots, None) => format!("{} shot(s)", shots),
+        }
+    }
+
+    fn days(&self) -> i64 {
+        RANGES[self.range]
+    }
+
+    fn action(&mut self, key: KeyEvent) -> Action {
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => Action::Quit,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => Action::Quit,
+            KeyCode::Char('a') => Action::Add,
+            KeyCode::Char('u') => Action::Undo,
+            KeyCode::Char('r') => Action::Refresh,
+            KeyCode::Left | KeyCode::Char('[') if self.range > 0 => {
+                self.range -= 1;
+                Action::Range
+            }
+            KeyCode::Right | KeyCode::Char(']') if self.range + 1 < RANGES.len() => {
+                self.range += 1;
+                Action::Range
+            }
+            _ => Action::Nothing,
+        }
+    }
+}
+
+struct App {
+    client: Client,
+    journal: Journal,
+    // The journal id of the last add, while it's held back from the server.
+    undoable: Option<(String, Instant)>,
+    refreshed: Instant,
+    view: View,
+}
+
+impl App {
+    async fn refresh(&mut self) -> Result<(), ClientError> {
+        let first_day = Local::today() - chrono::Duration::days(self.view.days() - 1);
+        let mut coffees: Vec<_> = self
+            .client
+            .list_coffees(first_day.and_hms(0, 0, 0).timestamp(), 0)
+            .await?
+            .iter()
+            .map(|c| (c.utc_time, c.shots, false))
+            .collect();
+        coffees.extend(
+            self.journal
+                .entries()
+                .iter()
+                .map(|p| (p.utc_time, p.shots, true)),
+        );
+        coffees.sort();
+        self.view.coffees = coffees;
+        self.refreshed = Instant::now();
+        Ok(())
+    }
+
+    // Sends the journal once nothing in it can still be undone.
+    async fn flush(&mut self) -> Result<(), ClientError> {
+        if let Some((_, added)) = &self.undoable {
+            if added.elapsed() < UNDO_WINDOW {
+                return Ok(());
+            }
+            self.undoable = None;
+        }
+        if !self.journal.entries().is_empty() {
+            flush_journal(&self.client, &mut self.journal).await?;
+            self.refresh().await?;
+        }
+        Ok(())
+    }
+
+    async fn tick(&mut self) -> Result<(), ClientError> {
+        self.flush().await?;
+        if self.refreshed.elapsed() >= REFRESH {
+            self.refresh().await?;
+        }
+        Ok(())
+    }
+
+    async fn add(&mut self) -> Result<(), ClientError> {
+        let (shots, drink) = self.view.preset.clone();
+        let entry = PendingCoffee::new(Utc::now().timestamp(), shots, drink);
+        let id = entry.id.clone();
+        self.journal.push(entry)?;
+        self.undoable = Some((id, Instant::now()));
+        self.view.status = format!("Added {}, press u to undo", self.view.preset_name());
+        self.refresh().await
+    }
+
+    // Like `coffee undo`, anything not sent yet is the newest and is just
+    // dropped from the journal, otherwise the server takes back the last
+    // change.
+    async fn undo(&mut self) -> Result<(), ClientError> {
+        self.undoable = None;
+        if let Some(p) = self.journal.entries().last().cloned() {
+            self.journal.remove(&p.id)?;
+            self.view.status = format!(
+                "Removed {} from the unsent coffees.",
+                describe_coffee(&pending_coffee(&p))
+            );
+            return self.refresh().await;
+        }
+        match self.client.undo_last().await.map_err(ClientError::from) {
+            Ok(undone) => {
+                self.view.status = describe_undo(&undone.change);
+                self.refresh().await
+            }
+            // Nothing to undo, or it's changed since, comes back as the
+            // server's explanation, which is no reason to quit.
+            Err(e) if !is_offline(&e) => {
+                self.view.status = e.to_string();
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // Handles a key press, returning false once it's time to quit.
+    async fn key(&mut self, key: KeyEvent) -> Result<bool, ClientError> {
+        match self.view.action(key) {
+            Action::Quit => return Ok(false),
+            Action::Add => self.add().await?,
+            Action::Undo => self.undo().await?,
+            Action::Refresh => {
+                self.refresh().await?;
+                self.view.status = "Refreshed".into();
+            }
+            Action::Range => self.refresh().await?,
+            Action::Nothing => {}
+        }
+        Ok(true)
+    }
+}
+
+/// Runs the dashboard until the user quits. Anything added but not yet sent
+/// is flushed on the way out, or left in the journal if the server is gone.
+pub async fn run(
+    client: Client,
+    profile_key: &str,
+    preset: (i32, Option<String>),
+) -> Result<(), ClientError> {
+    let mut app = App {
+        client,
+        journal: Journal::open(&journal_path(), profile_key)?,
+        undoable: None,
+        refreshed: Instant::now(),
+        view: View {
+            preset,
+            range: DEFAULT_RANGE,
+            coffees: Vec::new(),
+            status: "Press a to add a coffee, q to quit".into(),
+        },
+    };
+    app.refresh().await?;
+
+    let (mut term, default_hook) = setup_terminal()?;
+    let res = event_loop(&mut term, &mut app).await;
+    restore_terminal(&mut term, default_hook)?;
+    res?;
+
+    app.undoable = None;
+    match app.flush().await {
+        Err(e) if is_offline(&e) => {
+            println!("Could not reach the server, unsent coffees were saved locally. Run `coffee sync` once it's back.");
+            Ok(())
+        }
+        res => res,
+    }
+}
+
+async fn event_loop(
+    term: &mut Terminal<CrosstermBackend<Stdout>>,
+    app: &mut App,
+) -> Result<(), ClientError> {
+    let mut events = EventStream::new();
+    let mut ticks = tokio::time::interval(TICK);
+    let mut updates = watch(app.client.clone());
+
+    loop {
+        term.draw(|f| draw(f, &app.view))?;
+        let res = tokio::select! {
+            e = events.next() => match e {
+                Some(Ok(Event::Key(key))) => app.key(key).await,
+                Some(Ok(_)) => Ok(true),
+                Some(Err(e)) => Err(e.into()),
+                None => Ok(false),
+            },
+            _ = ticks.tick() => app.tick().await.map(|_| true),
+            Some(()) = updates.recv() => app.refresh().await.map(|_| true),
+        };
+        match res {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            // Losing the server for a while is no reason to quit, what's
+            // added in the meantime stays in the journal.
+            Err(e) if is_offline(&e) => {
+                app.view.status = "Could not reach the server, retrying...".into();
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Lets the dashboard know whenever the server hears about a new coffee.
+fn watch(client: Client) -> mpsc::Receiver<()> {
+    let (mut tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        // Without a watch the periodic refresh still keeps things up to date.
+        let mut watch = match client.watch(0).await {
+            Ok(w) => w,
+            Err(_) => return,
+        };
+        while let Ok(Some(event)) = watch.next().await {
+            if let WatchEvent::Coffee(_) = event {
+                if tx.send(()).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    rx
+}
+
+type PanicHook = Box<dyn Fn(&PanicHookInfo<'_>) + Sync + Send + 'static>;
+
+// Also hands back the panic hook that was there before, for
+// `restore_terminal` to put back.
+fn setup_terminal() -> Result<(Terminal<CrosstermBackend<Stdout>>, Arc<PanicHook>), ClientError> {
+    terminal::enable_raw_mode()?;
+    let mut stdout = std::io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    let term = Terminal::new(CrosstermBackend::new(stdout))?;
+
+    // Put the terminal back if we panic, or the shell is left unusable.
+    let default_hook = Arc::new(std::panic::take_hook());
+    let hook = default_hook.clone();
+    std::panic::set_hook(Box::new(move |info| {
+        let _ = terminal::disable_raw_mode();
+        let _ = execute!(std::io::stdout(), LeaveAlternateScreen);
+        hook(info);
+    }));
+
+    Ok((term, default_hook))
+}
+
+fn restore_terminal(
+    term: &mut Terminal<CrosstermBackend<Stdout>>,
+    default_hook: Arc<PanicHook>,
+) -> Result<(), ClientError> {
+    // Once ours is dropped the old hook is the only copy left, and goes back
+    // as it was.
+    drop(std::panic::take_hook());
+    match Arc::try_unwrap(default_hook) {
+        Ok(hook) => std::panic::set_hook(hook),
+        Err(hook) => std::panic::set_hook(Box::new(move |info| hook(info))),
+    }
+
+    terminal::disable_raw_mode()?;
+    execute!(term.backend_mut(), LeaveAlternateScreen)?;
+    term.show_cursor()?;
+    Ok(())
+}
+
+fn draw<B: Backend>(f: &mut Frame<B>, view: &View) {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(5),
+            Constraint::Min(8),
+            Constraint::Length(RECENT_ENTRIES as u16 + 2),
+            Constraint::Length(1),
+        ])
+        .split(f.size());
+    let top = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+        .split(rows[0]);
+
+    draw_today(f, view, top[0]);
+    draw_caffeine(f, view, top[1]);
+    draw_chart(f, view, rows[1]);
+    draw_recent(f, view, rows[2]);
+
+    let help = "a: add  u: undo  ←/→: range  r: refresh  q: quit";
+    let status = Paragraph::new(Spans::from(vec![
+        Span::styled(help, Style::default().add_modifier(Modifier::DIM)),
+        Span::raw("   "),
+        Span::raw(view.status.as_str()),
+    ]));
+    f.render_widget(status, rows[3]);
+}
+
+fn draw_today<B: Backend>(f: &mut Frame<B>, view: &View, area: Rect) {
+    let today = Local::today();
+    let todays: Vec<_> = view
+        .coffees
+        .iter()
+        .filter(|(t, _, _)| Utc.timestamp(*t, 0).with_timezone(&Local).date() == today)
+        .collect();
+    let shots: i32 = todays.iter().map(|(_, s, _)| s).sum();
+    let last = todays
+        .last()
+        .map(|(t, _, _)| {
+            Utc.timestamp(*t, 0)
+                .with_timezone(&Local)
+                .format("%H:%M")
+                .to_string()
+        })
+        .unwrap_or_else(|| "-".into());
+
+    let text = vec![
+        Spans::from(vec![
+            Span::styled(
+                shots.to_string(),
+                Style::default().add_modifier(Modifier::BOLD),
+            ),
+            Span::raw(" shot(s) today"),
+        ]),
+        Spans::from(format!("{} coffee(s), the last at {}", todays.len(), last)),
+        Spans::from(format!("Preset: {}", view.preset_name())),
+    ];
+    let block = Block::default().borders(Borders::ALL).title("Today");
+    f.render_widget(Paragraph::new(text).block(block), area);
+}
+
+fn draw_caffeine<B: Backend>(f: &mut Frame<B>, view: &View, area: Rect) {
+    let level = caffeine_level(
+        view.coffees.iter().map(|(t, s, _)| (*t, *s)),
+        Utc::now().timestamp(),
+    );
+    let gauge = Gauge::default()
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("Caffeine (estimate)"),
+        )
+        .gauge_style(Style::default().fg(Color::Yellow))
+        .ratio((level / CAFFEINE_DAILY_LIMIT_MG).clamp(0.0, 1.0))
+        .label(format!("{:.0} mg", level));
+    f.render_widget(gauge, area);
+}
+
+// How many days each bar covers and how wide the bars are, for `days` to fit
+// in a chart `width` wide with a gap between the bars. Once there isn't room
+// for a bar a day, the days are put together.
+fn chart_layout(days: i64, width: u16) -> (i64, u16) {
+    let inner = i64::from(width.saturating_sub(2)).max(1);
+    let bars = |per_bar: i64| (days + per_bar - 1) / per_bar;
+    let per_bar = (1..days)
+        .find(|n| 2 * bars(*n) - 1 <= inner)
+        .unwrap_or(days);
+    let bar_width = ((inner + 1) / bars(per_bar) - 1).max(1);
+    (per_bar, bar_width as u16)
+}
+
+// The shots on each bar, oldest first, each labelled with its last day. The
+// last bar always ends today, so only the first can be short of days.
+fn chart_bars(
+    coffees: &[(i64, i32, bool)],
+    today: Date<Local>,
+    days: i64,
+    per_bar: i64,
+) -> Vec<(String, u64)> {
+    let bars = (days + per_bar - 1) / per_bar;
+    let mut data: Vec<(String, u64)> = (0..bars)
+        .map(|i| {
+            let day = today - chrono::Duration::days((bars - 1 - i) * per_bar);
+            (day.format("%d").to_string(), 0)
+        })
+        .collect();
+    for (t, s, _) in coffees {
+        let day = Utc.timestamp(*t, 0).with_timezone(&Local).date();
+        let back = (today - day).num_days();
+        if back >= 0 && back < days {
+            data[(bars - 1 - back / per_bar) as usize].1 += (*s).max(0) as u64;
+        }
+    }
+    data
+}
+
+fn draw_chart<B: Backend>(f: &mut Frame<B>, view: &View, area: Rect) {
+    let days = view.days();
+    let (per_bar, bar_width) = chart_layout(days, area.width);
+    let bars = chart_bars(&view.coffees, Local::today(), days, per_bar);
+    let data: Vec<(&str, u64)> = bars.iter().map(|(l, s)| (l.as_str(), *s)).collect();
+
+    let mut title = format!("Shots, last {} days", days);
+    if per_bar > 1 {
+        title += &format!(", {} to a bar", per_bar);
+    }
+    let chart = BarChart::default()
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .data(&data)
+        .bar_width(bar_width)
+        .bar_gap(1)
+        .bar_style(Style::default().fg(Color::Cyan))
+        .value_style(Style::default().fg(Color::Black).bg(Color::Cyan));
+    f.render_widget(chart, area);
+}
+
+fn draw_recent<B: Backend>(f: &mut Frame<B>, view: &View, area: Rect) {
+    let items: Vec<ListItem> = view
+        .coffees
+        .iter()
+        .rev()
+        .take(RECENT_ENTRIES)
+        .map(|(t, s, pending)| {
+            let t = Utc.timestamp(*t, 0).with_timezone(&Local);
+            let mut line = vec![Span::raw(format!(
+                "{}  {} shot(s)",
+                t.format("%a %d %b %H:%M"),
+                s
+            ))];
+            if *pending {
+                line.push(Span::styled(
+                    "  (pending)",
+                    Style::default().fg(Color::Yellow),
+                ));
+            }
+            ListItem::new(Spans::from(line))
+        })
+        .collect();
+    let list = List::new(items).block(Block::default().borders(Borders::ALL).title("Recent"));
+    f.render_widget(list, area);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tui::backend::TestBackend;
+
+    fn view(range: usize, coffees: Vec<(i64, i32, bool)>) -> View {
+        View {
+            preset: (2, Some("latte".into())),
+            range,
+            coffees,
+            status: String::new(),
+        }
+    }
+
+    fn press(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    #[test]
+    pub fn test_keys() {
+        let mut v = view(DEFAULT_RANGE, vec![]);
+        assert_eq!(v.action(press(KeyCode::Char('q'))), Action::Quit);
+        assert_eq!(v.action(press(KeyCode::Esc)), Action::Quit);
+        assert_eq!(
+            v.action(KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL)),
+            Action::Quit
+        );
+        assert_eq!(v.action(press(KeyCode::Char('c'))), Action::Nothing);
+        assert_eq!(v.action(press(KeyCode::Char('a'))), Action::Add);
+        assert_eq!(v.action(press(KeyCode::Char('u'))), Action::Undo);
+        assert_eq!(v.action(press(KeyCode::Char('r'))), Action::Refresh);
+
+        // The range stops at either end, without fetching again.
+        assert_eq!(v.action(press(KeyCode::Left)), Action::Range);
+        assert_eq!(v.days(), 7);
+        assert_eq!(v.action(press(KeyCode::Char('['))), Action::Nothing);
+        assert_eq!(v.days(), 7);
+        assert_eq!(v.action(press(KeyCode::Right)), Action::Range);
+        assert_eq!(v.action(press(KeyCode::Char(']'))), Action::Range);
+        assert_eq!(v.days(), 90);
+        assert_eq!(v.action(press(KeyCode::Right)), Action::Nothing);
+        assert_eq!(v.days(), 90);
+    }
+
+    #[test]
+    pub fn test_chart_layout() {
+        for &days in RANGES {
+            for width in 0..=200 {
+                let (per_bar, bar_width) = chart_layout(days, width);
+                let bars = (days + per_bar - 1) / per_bar;
+                let used = bars * (i64::from(bar_width) + 1) - 1;
+                assert!(
+                    used <= i64::from(width.saturating_sub(2)).max(1),
+                    "{} days in {} columns",
+                    days,
+                    width
+                );
+            }
+        }
+        // A day to a bar while there's room for it.
+        assert_eq!(chart_layout(7, 80), (1, 10));
+        assert_eq!(chart_layout(30, 80), (1, 1));
+        assert_eq!(chart_layout(90, 80), (3, 1));
+        assert_eq!(chart_layout(90, 200), (1, 1));
+    }
+
+    #[test]
+    pub fn test_chart_bars() {
+        let today = Local::today();
+        let at = |days_ago: i64| {
+            (today - chrono::Duration::days(days_ago))
+                .and_hms(12, 0, 0)
+                .timestamp()
+        };
+        let coffees = vec![
+            (at(95), 9, false),
+            (at(89), 1, false),
+            (at(4), 2, false),
+            (at(0), 3, true),
+        ];
+
+        let bars = chart_bars(&coffees, today, 7, 1);
+        assert_eq!(bars.len(), 7);
+        assert_eq!(bars[2].1, 2);
+        assert_eq!(bars[6], (today.format("%d").to_string(), 3));
+
+        // Too old for the range is left out, and the rest is put together
+        // three days at a time.
+        let bars = chart_bars(&coffees, today, 90, 3);
+        assert_eq!(bars.len(), 30);
+        assert_eq!(bars[0].1, 1);
+        assert_eq!(bars[28].1, 2);
+        assert_eq!(bars[29].1, 3);
+        assert_eq!(bars.iter().map(|(_, s)| s).sum::<u64>(), 6);
+    }
+
+    #[test]
+    pub fn test_draw() {
+        let today = Local::today().and_hms(12, 0, 0).timestamp();
+        for (range, &days) in RANGES.iter().enumerate() {
+            let mut term = Terminal::new(TestBackend::new(80, 30)).unwrap();
+            let v = view(range, vec![(today, 4, false)]);
+            term.draw(|f| draw(f, &v)).unwrap();
+
+            // Today's bar is the last, and the tallest so it reaches the top
+            // of the chart, which sits below the 5 rows of the top panels.
+            let (per_bar, bar_width) = chart_layout(days, 80);
+            let bars = (days + per_bar - 1) / per_bar;
+            let x = 1 + (bars - 1) as u16 * (bar_width + 1);
+            let buffer = term.backend().buffer();
+            assert_eq!(buffer.get(x, 6).symbol, "█", "{} days", days);
+            assert_eq!(buffer.get(x + bar_width, 6).symbol, " ");
+        }
+    }
+
+    #[test]
+    pub fn test_caffeine_level() {
+        let now = 100_000;
+        let half_life = (CAFFEINE_HALF_LIFE_HOURS * 3600.0) as i64;
+
+        assert_eq!(caffeine_level(vec![].into_iter(), now), 0.0);
+        // A double just now is all still there...
+        let level = caffeine_level(vec![(now, 2)].into_iter(), now);
+        assert!((level - 2.0 * CAFFEINE_PER_SHOT_MG).abs() < 1e-9);
+        // ...and half of it is gone after a half-life.
+        let level = caffeine_level(vec![(now - half_life, 2)].into_iter(), now);
+        assert!((level - CAFFEINE_PER_SHOT_MG).abs() < 1e-9);
+        // Coffees in the future haven't been drunk yet.
+        assert_eq!(caffeine_level(vec![(now + 60, 1)].into_iter(), now), 0.0);
+    }
+}
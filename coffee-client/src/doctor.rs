@@ -0,0 +1,314 @@
+// `coffee doctor`, which works through everything between the config file and
+// the server in order, so when something's broken it's clear which part. Each
+// check needs the ones before it, so after the first failure the rest are
+// skipped.
+
+use crate::chart::Style;
+use crate::config::CoffeeConfig;
+use crate::error::ClientError;
+
+use ansi_term::Colour;
+use chrono::Utc;
+use clap::ArgMatches;
+use coffee_sdk::{Client, RetryPolicy, FUTURE_TOLERANCE_SECS};
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+// How long to give the DNS and TCP checks when there's no timeout configured.
+const DEFAULT_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+// Clocks further apart than this are worth a mention, though they still work.
+const SKEW_WARNING_SECS: i64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    Pass,
+    Warn,
+    Fail,
+}
+
+// Prints each check's outcome as it's made, so a check that hangs is obvious.
+struct Report {
+    colour: bool,
+    failed: usize,
+}
+
+impl Report {
+    fn check(&mut self, outcome: Outcome, name: &str, detail: impl Display) {
+        let (label, colour) = match outcome {
+            Outcome::Pass => ("ok", Colour::Green),
+            Outcome::Warn => ("warn", Colour::Yellow),
+            Outcome::Fail => ("FAIL", Colour::Red),
+        };
+        let label = format!("{:>4}", label);
+        let label = if self.colour {
+            colour.bold().paint(label).to_string()
+        } else {
+            label
+        };
+        println!("{}  {:10} {}", label, name, detail);
+        if outcome == Outcome::Fail {
+            self.failed += 1;
+        }
+    }
+
+    fn pass(&mut self, name: &str, detail: impl Display) {
+        self.check(Outcome::Pass, name, detail);
+    }
+
+    fn warn(&mut self, name: &str, detail: impl Display) {
+        self.check(Outcome::Warn, name, detail);
+    }
+
+    fn fail(&mut self, name: &str, detail: impl Display) {
+        self.check(Outcome::Fail, name, detail);
+    }
+
+    // Reports the failure along with what to do about it, if we know.
+    fn error(&mut self, name: &str, e: &ClientError) {
+        self.check(Outcome::Fail, name, e);
+        if let Some(hint) = e.hint() {
+            println!("{:16}{}", "", hint);
+        }
+    }
+
+    fn skip(&self, names: &[&str]) {
+        for name in names {
+            println!("{:>4}  {:10} skipped", "-", name);
+        }
+    }
+}
+
+pub async fn run(
+    config_path: &Path,
+    matches: &ArgMatches<'_>,
+    cmd: &ArgMatches<'_>,
+) -> Result<(), ClientError> {
+    let mut report = Report {
+        colour: Style::detect().colour,
+        failed: 0,
+    };
+    let res = checks(&mut report, config_path, matches, cmd).await;
+    println!();
+    res?;
+    if report.failed > 0 {
+        return Err(ClientError::ChecksFailed(report.failed));
+    }
+    println!("Everything looks fine.");
+    Ok(())
+}
+
+async fn checks(
+    report: &mut Report,
+    config_path: &Path,
+    matches: &ArgMatches<'_>,
+    cmd: &ArgMatches<'_>,
+) -> Result<(), ClientError> {
+    let all = [
+        "config", "dns", "tcp", "connect", "health", "api key", "clock",
+    ];
+
+    let config = if !config_path.exists() {
+        report.warn(
+            "config",
+            format!("{} doesn't exist, using defaults", config_path.display()),
+        );
+        CoffeeConfig::default()
+    } else {
+        match CoffeeConfig::load(config_path) {
+            Ok(c) => c,
+            Err(e) => {
+                report.error("config", &ClientError::Config(e));
+                report.skip(&all[1..]);
+                return Ok(());
+            }
+        }
+    };
+    let profile_name = matches.value_of("profile");
+    let profile = config.profile(profile_name);
+    if let (Some(name), None) = (profile_name, profile) {
+        report.fail("config", format!("no profile named {}", name));
+        report.skip(&all[1..]);
+        return Ok(());
+    }
+    if let Some(ca) = profile.and_then(|p| p.tls.ca_cert.as_ref()) {
+        if let Err(e) = std::fs::read(ca) {
+            report.fail(
+                "config",
+                format!("cannot read the CA certificate {}: {}", ca, e),
+            );
+            report.skip(&all[1..]);
+            return Ok(());
+        }
+    }
+    let mut server = crate::server_for(matches, profile)?;
+    if config_path.exists() {
+        let using = match profile_name.or(config.current.as_deref()) {
+            Some(name) if profile.is_some() => format!(", profile {}", name),
+            _ => String::new(),
+        };
+        report.pass("config", format!("{}{}", config_path.display(), using));
+    }
+
+    // Retrying would only hide what's wrong.
+    server.retry = RetryPolicy::never();
+    let timeout = server.timeout.unwrap_or(DEFAULT_CHECK_TIMEOUT);
+
+    let authority = match authority(&server.url()) {
+        Some(a) => a,
+        None => {
+            report.fail("dns", format!("cannot make sense of {}", server.url()));
+            report.skip(&all[2..]);
+            return Ok(());
+        }
+    };
+    let addrs: Vec<SocketAddr> =
+        match tokio::time::timeout(timeout, tokio::net::lookup_host(authority.as_str())).await {
+            Ok(Ok(addrs)) => addrs.collect(),
+            Ok(Err(e)) => {
+                report.error("dns", &ClientError::Io(e));
+                report.skip(&all[2..]);
+                return Ok(());
+            }
+            Err(_) => {
+                report.error("dns", &ClientError::Sdk(coffee_sdk::Error::Timeout));
+                report.skip(&all[2..]);
+                return Ok(());
+            }
+        };
+    let addr = match addrs.first() {
+        Some(a) => *a,
+        None => {
+            report.fail("dns", format!("{} has no addresses", authority));
+            report.skip(&all[2..]);
+            return Ok(());
+        }
+    };
+    report.pass("dns", format!("{} is {}", authority, addr.ip()));
+
+    match tokio::time::timeout(timeout, TcpStream::connect(addr)).await {
+        Ok(Ok(_)) => report.pass("tcp", format!("connected to {}", addr)),
+        Ok(Err(e)) => {
+            report.error("tcp", &ClientError::Io(e));
+            report.skip(&all[3..]);
+            return Ok(());
+        }
+        Err(_) => {
+            report.error("tcp", &ClientError::Sdk(coffee_sdk::Error::Timeout));
+            report.skip(&all[3..]);
+            return Ok(());
+        }
+    }
+
+    // Connecting is where TLS is negotiated, if the server uses it.
+    let client = match Client::connect(&server).await {
+        Ok(c) => c,
+        Err(e) => {
+            report.error("connect", &e.into());
+            report.skip(&all[4..]);
+            return Ok(());
+        }
+    };
+    let tls = if server.url().starts_with("https://") {
+        "TLS"
+    } else {
+        "plaintext, no TLS"
+    };
+    report.pass("connect", format!("{} ({})", server.url(), tls));
+
+    match client.health().await {
+        Ok(true) => report.pass("health", "the server is serving"),
+        Ok(false) => {
+            report.fail("health", "the server says it isn't serving");
+        }
+        // Older servers don't have a health service, which is no reason to stop.
+        Err(e) if e.code() == Some(coffee_sdk::Code::Unimplemented) => {
+            report.warn("health", "the server has no health service")
+        }
+        Err(e) => report.error("health", &e.into()),
+    }
+
+    let api_key = crate::get_api_key(profile, cmd);
+    let api_key = match api_key {
+        Ok(k) => k,
+        Err(e) => {
+            report.error("api key", &e);
+            report.skip(&all[6..]);
+            return Ok(());
+        }
+    };
+    let sent_at = Utc::now().timestamp();
+    let server_time = match client.with_api_key(api_key).verify_key().await {
+        Ok(t) => t,
+        Err(e) => {
+            report.error("api key", &e.into());
+            report.skip(&all[6..]);
+            return Ok(());
+        }
+    };
+    report.pass("api key", "the server knows it");
+
+    // Near enough, the server read its clock between sending and now.
+    let skew = server_time - (sent_at + Utc::now().timestamp()) / 2;
+    let detail = match skew {
+        0 => "in step with the server".to_string(),
+        s if s > 0 => format!("{}s behind the server", s),
+        s => format!("{}s ahead of the server", -s),
+    };
+    if skew.abs() > FUTURE_TOLERANCE_SECS {
+        report.fail(
+            "clock",
+            format!(
+                "your clock is {}, coffees added now could be refused or misdated",
+                detail
+            ),
+        );
+    } else if skew.abs() > SKEW_WARNING_SECS {
+        report.warn("clock", detail);
+    } else {
+        report.pass("clock", detail);
+    }
+    Ok(())
+}
+
+// The host:port part of a URL, with the scheme's port if it doesn't give one.
+fn authority(url: &str) -> Option<String> {
+    let (scheme, rest) = url.split_at(url.find("://")?);
+    let authority = rest[3..].split('/').next()?;
+    if authority.is_empty() {
+        return None;
+    }
+    // An IPv6 address has colons of its own, so look after its brackets.
+    let host_end = authority.rfind(']').unwrap_or(0);
+    if authority[host_end..].contains(':') {
+        return Some(authority.to_string());
+    }
+    let port = if scheme == "https" { 443 } else { 80 };
+    Some(format!("{}:{}", authority, port))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_authority() {
+        assert_eq!(
+            authority("http://[::1]:50051").as_deref(),
+            Some("[::1]:50051")
+        );
+        assert_eq!(
+            authority("https://coffee.example.com").as_deref(),
+            Some("coffee.example.com:443")
+        );
+        assert_eq!(
+            authority("http://10.0.0.1:8080/").as_deref(),
+            Some("10.0.0.1:8080")
+        );
+        assert_eq!(authority("http://[::1]").as_deref(), Some("[::1]:80"));
+        assert_eq!(authority("coffee.example.com"), None);
+        assert_eq!(authority("http://"), None);
+    }
+}
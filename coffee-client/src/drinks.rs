@@ -0,0 +1,110 @@
+// A local copy of each profile's drinks, refreshed whenever the server is
+// asked for them, so a drink can still be added by name while the server
+// can't be reached.
+
+use coffee_sdk::Drink;
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// Only what an add needs.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CachedDrink {
+    pub name: String,
+    pub shots: i32,
+}
+
+/// Each profile's drinks, as a file that's fine not to exist yet.
+#[derive(Debug)]
+pub struct DrinkCache {
+    path: PathBuf,
+    drinks: BTreeMap<String, Vec<CachedDrink>>,
+}
+
+impl DrinkCache {
+    // A cache that can't be read is just filled in again next time.
+    pub fn open(path: &Path) -> Self {
+        let drinks = File::open(path)
+            .ok()
+            .and_then(|f| serde_json::from_reader(BufReader::new(f)).ok())
+            .unwrap_or_default();
+        DrinkCache {
+            path: path.into(),
+            drinks,
+        }
+    }
+
+    /// `profile`'s drink called `name`, as the server last listed it.
+    pub fn find(&self, profile: &str, name: &str) -> Option<CachedDrink> {
+        let name = name.to_lowercase();
+        self.drinks
+            .get(profile)?
+            .iter()
+            .find(|d| d.name == name)
+            .cloned()
+    }
+
+    pub fn set(&mut self, profile: &str, drinks: &[Drink]) -> std::io::Result<()> {
+        let drinks = drinks
+            .iter()
+            .map(|d| CachedDrink {
+                name: d.name.clone(),
+                shots: d.shots,
+            })
+            .collect();
+        self.drinks.insert(profile.into(), drinks);
+        self.save()
+    }
+
+    // Written to a temporary file first, so nothing reads half a cache.
+    fn save(&self) -> std::io::Result<()> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(format!(".{}.tmp", std::process::id()));
+        let tmp = PathBuf::from(tmp);
+
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut writer, &self.drinks)?;
+        writer.flush()?;
+        drop(writer);
+
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn drink(name: &str, shots: i32) -> Drink {
+        Drink {
+            name: name.into(),
+            shots,
+            caffeine_mg: None,
+            size_ml: None,
+            price_cents: None,
+            global: false,
+        }
+    }
+
+    #[test]
+    pub fn test_drinks_per_profile() {
+        let path = std::env::temp_dir().join(format!("coffee_drinks_test_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut cache = DrinkCache::open(&path);
+        assert_eq!(cache.find("home", "latte"), None);
+        cache.set("home", &[drink("latte", 2)]).unwrap();
+        cache.set("work", &[drink("latte", 1)]).unwrap();
+
+        // It's read back, by either case, and each profile has its own.
+        let cache = DrinkCache::open(&path);
+        assert_eq!(cache.find("home", "Latte").map(|d| d.shots), Some(2));
+        assert_eq!(cache.find("work", "latte").map(|d| d.shots), Some(1));
+        assert_eq!(cache.find("home", "mocha"), None);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
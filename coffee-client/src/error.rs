@@ -1,19 +1,122 @@
+// What the CLI tells the user when a command fails: a message, a hint at what
+// to do about it where there's one to give, and an exit code scripts can
+// check. The exit codes are listed in the README, keep it in step.
+
+use coffee_sdk::Code;
+
+// Anything not covered below, e.g. the server failing in a way we don't expect.
+pub const EXIT_FAILURE: i32 = 1;
+// The command line, or a value given on it, doesn't make sense.
+pub const EXIT_USAGE: i32 = 2;
+// There's no API key, or the server doesn't know it.
+pub const EXIT_AUTH: i32 = 3;
+// The server couldn't be reached, or didn't answer in time.
+pub const EXIT_UNREACHABLE: i32 = 4;
+// The server wouldn't do it, e.g. there's nothing to undo.
+pub const EXIT_REFUSED: i32 = 5;
+// The server is rate limiting us and didn't ease off in time.
+pub const EXIT_RATE_LIMITED: i32 = 6;
+// A local file, like the config or the journal, couldn't be read or written.
+pub const EXIT_IO: i32 = 7;
+
 #[derive(Debug)]
 pub enum ClientError {
     NoApiKey,
-    RegistrationError,
-    AddFailed,
+    UnknownApiKey,
+    // The config file exists but couldn't be read.
+    Config(std::io::Error),
     Io(std::io::Error),
-    TonicStatus(tonic::Status),
-    TonicTransport(tonic::transport::Error),
-    BadArgument,
+    Sdk(coffee_sdk::Error),
+    // Says what was wrong with the argument.
+    BadArgument(String),
+    // How many of `coffee doctor`'s checks failed.
+    ChecksFailed(usize),
 }
 
-impl std::error::Error for ClientError {}
+impl ClientError {
+    // The server's status code, if it got that far.
+    fn code(&self) -> Option<Code> {
+        match self {
+            ClientError::Sdk(e) => e.code(),
+            _ => None,
+        }
+    }
+
+    fn is_offline(&self) -> bool {
+        match self {
+            ClientError::Sdk(e) => e.is_offline(),
+            _ => false,
+        }
+    }
+
+    /// What the process should exit with.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ClientError::NoApiKey | ClientError::UnknownApiKey => EXIT_AUTH,
+            ClientError::Config(_) | ClientError::Io(_) => EXIT_IO,
+            ClientError::BadArgument(_) => EXIT_USAGE,
+            ClientError::Sdk(coffee_sdk::Error::InvalidAddress(_)) => EXIT_USAGE,
+            ClientError::Sdk(coffee_sdk::Error::Io(_)) => EXIT_IO,
+            e if e.is_offline() => EXIT_UNREACHABLE,
+            e => match e.code() {
+                Some(Code::Unauthenticated) => EXIT_AUTH,
+                Some(Code::InvalidArgument) => EXIT_USAGE,
+                Some(Code::NotFound) | Some(Code::FailedPrecondition) => EXIT_REFUSED,
+                Some(Code::ResourceExhausted) => EXIT_RATE_LIMITED,
+                _ => EXIT_FAILURE,
+            },
+        }
+    }
+
+    /// A suggestion for how to put things right, if we have one.
+    pub fn hint(&self) -> Option<&'static str> {
+        match self.exit_code() {
+            EXIT_AUTH if matches!(self, ClientError::NoApiKey) => {
+                Some("run `coffee login`, or `coffee register <EMAIL>` to get a key")
+            }
+            EXIT_AUTH => Some(
+                "check the key with `coffee profile list`, or save another with `coffee login`",
+            ),
+            EXIT_UNREACHABLE => Some(
+                "check the server address (--server or `coffee profile list`) and that it's up, \
+                 or give it longer with --timeout",
+            ),
+            EXIT_RATE_LIMITED => Some("wait a minute and try again"),
+            _ if matches!(self, ClientError::Config(_)) => {
+                Some("fix or move the config file, or use another with --config")
+            }
+            _ => None,
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Config(e) | ClientError::Io(e) => Some(e),
+            ClientError::Sdk(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl std::fmt::Display for ClientError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
-        write!(f, "{:?}", self)
+        match self {
+            ClientError::NoApiKey => write!(f, "no API key"),
+            ClientError::UnknownApiKey => write!(f, "the server doesn't know that API key"),
+            ClientError::Config(e) => write!(f, "could not read the config file: {}", e),
+            ClientError::Io(e) => write!(f, "{}", e),
+            ClientError::BadArgument(msg) => write!(f, "{}", msg),
+            ClientError::ChecksFailed(n) => write!(f, "{} check(s) failed", n),
+            ClientError::Sdk(coffee_sdk::Error::Status(s)) => match s.code() {
+                Code::Unauthenticated => write!(f, "the server doesn't know that API key"),
+                Code::ResourceExhausted => write!(f, "the server is rate limiting you"),
+                // The server's own words say it best.
+                _ => write!(f, "{}", s.message()),
+            },
+            ClientError::Sdk(e) => write!(f, "{}", e),
+        }
     }
 }
 
@@ -23,14 +126,61 @@ impl From<std::io::Error> for ClientError {
     }
 }
 
-impl From<tonic::Status> for ClientError {
-    fn from(e: tonic::Status) -> Self {
-        ClientError::TonicStatus(e)
+impl From<coffee_sdk::Error> for ClientError {
+    fn from(e: coffee_sdk::Error) -> Self {
+        ClientError::Sdk(e)
     }
 }
 
-impl From<tonic::transport::Error> for ClientError {
-    fn from(e: tonic::transport::Error) -> Self {
-        ClientError::TonicTransport(e)
+#[cfg(test)]
+mod test {
+    use super::*;
+    use coffee_sdk::Error;
+
+    fn status(code: Code) -> ClientError {
+        ClientError::Sdk(Error::Status(tonic::Status::new(code, "from the server")))
+    }
+
+    #[test]
+    pub fn test_exit_codes() {
+        assert_eq!(ClientError::NoApiKey.exit_code(), EXIT_AUTH);
+        assert_eq!(status(Code::Unauthenticated).exit_code(), EXIT_AUTH);
+        assert_eq!(
+            ClientError::BadArgument("no".into()).exit_code(),
+            EXIT_USAGE
+        );
+        assert_eq!(status(Code::InvalidArgument).exit_code(), EXIT_USAGE);
+        assert_eq!(
+            ClientError::Sdk(Error::Timeout).exit_code(),
+            EXIT_UNREACHABLE
+        );
+        assert_eq!(status(Code::Unavailable).exit_code(), EXIT_UNREACHABLE);
+        let gave_up = Error::GaveUp {
+            attempts: 4,
+            last: Box::new(Error::Timeout),
+        };
+        assert_eq!(ClientError::Sdk(gave_up).exit_code(), EXIT_UNREACHABLE);
+        assert_eq!(status(Code::NotFound).exit_code(), EXIT_REFUSED);
+        assert_eq!(
+            status(Code::ResourceExhausted).exit_code(),
+            EXIT_RATE_LIMITED
+        );
+        assert_eq!(status(Code::Internal).exit_code(), EXIT_FAILURE);
+        let io = std::io::Error::other("disk full");
+        assert_eq!(ClientError::Io(io).exit_code(), EXIT_IO);
+    }
+
+    #[test]
+    pub fn test_messages() {
+        assert_eq!(status(Code::NotFound).to_string(), "from the server");
+        assert!(status(Code::NotFound).hint().is_none());
+        assert!(ClientError::NoApiKey
+            .hint()
+            .unwrap()
+            .contains("coffee login"));
+        assert_eq!(
+            ClientError::Sdk(Error::Timeout).to_string(),
+            "the server took too long to answer"
+        );
     }
 }
@@ -0,0 +1,231 @@
+// Scripts the user has run after things happen, e.g. to update a status bar
+// or post to a chat. Each is run with `sh -c` and gets the event as JSON on
+// stdin. A hook failing, or taking too long and being stopped, is reported but
+// never fails what it was run for.
+
+use crate::config::Hooks;
+
+use coffee_sdk::Coffee;
+use serde::Serialize;
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+// How long a hook gets when the config doesn't say.
+const DEFAULT_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EventCoffee {
+    pub utc_time: i64,
+    pub shots: i32,
+    pub drink: Option<String>,
+}
+
+impl From<&Coffee> for EventCoffee {
+    fn from(c: &Coffee) -> Self {
+        EventCoffee {
+            utc_time: c.utc_time,
+            shots: c.shots,
+            drink: c.drink.clone(),
+        }
+    }
+}
+
+// What's been logged so far today, pending coffees included.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Today {
+    pub shots: i32,
+    pub coffees: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    // `pending` if it's waiting in the journal for the server to come back.
+    Add {
+        coffee: EventCoffee,
+        pending: bool,
+        today: Today,
+    },
+    // `change` is add_coffee, update_coffee, delete_coffee, save_drink or
+    // delete_drink, with the coffee or the name of the drink that was put
+    // back. `pending` if it was taken out of the journal before it reached
+    // the server.
+    Undo {
+        change: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        coffee: Option<EventCoffee>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        drink: Option<String>,
+        pending: bool,
+    },
+    // An add took today's shots over the profile's daily limit.
+    LimitExceeded {
+        limit: i32,
+        today: Today,
+    },
+}
+
+// What the hook is given, the event along with the profile it happened in.
+#[derive(Serialize)]
+struct Envelope<'a> {
+    #[serde(flatten)]
+    event: &'a Event,
+    profile: &'a str,
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Event::Add { .. } => "add",
+            Event::Undo { .. } => "undo",
+            Event::LimitExceeded { .. } => "limit_exceeded",
+        }
+    }
+
+    fn command<'a>(&self, hooks: &'a Hooks) -> Option<&'a str> {
+        match self {
+            Event::Add { .. } => hooks.add.as_deref(),
+            Event::Undo { .. } => hooks.undo.as_deref(),
+            Event::LimitExceeded { .. } => hooks.limit_exceeded.as_deref(),
+        }
+    }
+}
+
+/// Whether adding `shots` is what took `today` over `limit`, so the limit
+/// hook fires once a day rather than on every add after.
+pub fn crosses_limit(limit: i32, today: &Today, shots: i32) -> bool {
+    today.shots - shots <= limit && limit < today.shots
+}
+
+/// Runs the hook for `event`, if there is one, and waits for it to finish.
+pub async fn run(hooks: &Hooks, profile: &str, event: &Event) {
+    let command = match event.command(hooks) {
+        Some(c) => c,
+        None => return,
+    };
+    let input =
+        serde_json::to_vec(&Envelope { event, profile }).expect("Could not serialize a hook event");
+    let secs = hooks.timeout.unwrap_or(DEFAULT_TIMEOUT_SECS);
+
+    match tokio::time::timeout(Duration::from_secs(secs), spawn(command, input)).await {
+        Ok(Ok(status)) if status.success() => {}
+        Ok(Ok(status)) => eprintln!("Warning: the {} hook failed, {}", event.name(), status),
+        Ok(Err(e)) => eprintln!("Warning: could not run the {} hook: {}", event.name(), e),
+        Err(_) => eprintln!(
+            "Warning: the {} hook took longer than {}s and was stopped",
+            event.name(),
+            secs
+        ),
+    }
+}
+
+// Dropping the returned future kills the hook, which is how the timeout works.
+async fn spawn(command: &str, input: Vec<u8>) -> std::io::Result<ExitStatus> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A hook doesn't have to read what it's given.
+        let _ = stdin.write_all(&input).await;
+    }
+    child.await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_event_json() {
+        let event = Event::Add {
+            coffee: EventCoffee {
+                utc_time: 1591689600,
+                shots: 2,
+                drink: Some("flatwhite".into()),
+            },
+            pending: false,
+            today: Today {
+                shots: 4,
+                coffees: 2,
+            },
+        };
+        let json = serde_json::to_value(&Envelope {
+            event: &event,
+            profile: "work",
+        })
+        .unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "event": "add",
+                "profile": "work",
+                "coffee": {"utc_time": 1591689600, "shots": 2, "drink": "flatwhite"},
+                "pending": false,
+                "today": {"shots": 4, "coffees": 2},
+            })
+        );
+
+        let event = Event::Undo {
+            change: "delete_drink",
+            coffee: None,
+            drink: Some("mocha".into()),
+            pending: false,
+        };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["event"], "undo");
+        assert!(json.get("coffee").is_none());
+    }
+
+    #[test]
+    pub fn test_crosses_limit() {
+        let today = |shots| Today { shots, coffees: 1 };
+        // 3 + 2 goes over 4, while 5 + 1 was already over.
+        assert!(crosses_limit(4, &today(5), 2));
+        assert!(!crosses_limit(4, &today(6), 1));
+        assert!(crosses_limit(4, &today(5), 1));
+        // Reaching the limit isn't going over it.
+        assert!(!crosses_limit(4, &today(4), 1));
+        assert!(!crosses_limit(4, &today(3), 0));
+    }
+
+    #[tokio::test]
+    pub async fn test_run() {
+        let dir = std::env::temp_dir().join(format!("coffee_hooks_test_{}", std::process::id()));
+        let _ = std::fs::create_dir(&dir);
+        let out = dir.join("event.json");
+        let event = Event::LimitExceeded {
+            limit: 4,
+            today: Today {
+                shots: 5,
+                coffees: 3,
+            },
+        };
+
+        let hooks = Hooks {
+            limit_exceeded: Some(format!("cat > {}", out.display())),
+            ..Hooks::default()
+        };
+        run(&hooks, "home", &event).await;
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&out).unwrap()).unwrap();
+        assert_eq!(json["event"], "limit_exceeded");
+        assert_eq!(json["today"]["shots"], 5);
+
+        // Failing and hanging hooks are only reported.
+        let hooks = Hooks {
+            limit_exceeded: Some("sleep 5".into()),
+            timeout: Some(1),
+            ..Hooks::default()
+        };
+        let started = std::time::Instant::now();
+        run(&hooks, "home", &event).await;
+        assert!(started.elapsed() < Duration::from_secs(3));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
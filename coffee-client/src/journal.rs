@@ -0,0 +1,229 @@
+// An on-disk journal of coffees that haven't made it to the server yet. Adds
+// go here first so nothing is lost when the server can't be reached, and are
+// replayed with their ids so a retry is only stored once. Each profile's
+// coffees are kept apart, as they belong to different servers and keys.
+//
+// An add, the dashboard and a sync can all have it open at once, so each
+// change is made to the file as it is now, under a lock, rather than to what
+// was read when it was opened.
+
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PendingCoffee {
+    pub id: String,
+    pub utc_time: i64,
+    pub shots: i32,
+    // Journals from before drinks won't have one.
+    #[serde(default)]
+    pub drink: Option<String>,
+    // The profile it was added under, which only journals from before
+    // profiles won't have.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+}
+
+impl PendingCoffee {
+    pub fn new(utc_time: i64, shots: i32, drink: Option<String>) -> Self {
+        PendingCoffee {
+            id: Uuid::new_v4().to_string(),
+            utc_time,
+            shots,
+            drink,
+            profile: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    profile: String,
+    entries: Vec<PendingCoffee>,
+    // Other profiles' coffees, kept as they are.
+    others: Vec<PendingCoffee>,
+}
+
+impl Journal {
+    /// Opens `profile`'s part of the journal at `path`, which is fine not to
+    /// exist yet.
+    pub fn open(path: &Path, profile: &str) -> std::io::Result<Self> {
+        let mut journal = Journal {
+            path: path.into(),
+            profile: profile.into(),
+            entries: Vec::new(),
+            others: Vec::new(),
+        };
+        let lock = journal.lock()?;
+        lock.lock_shared()?;
+        journal.read()?;
+        Ok(journal)
+    }
+
+    pub fn entries(&self) -> &[PendingCoffee] {
+        &self.entries
+    }
+
+    pub fn push(&mut self, mut entry: PendingCoffee) -> std::io::Result<()> {
+        entry.profile = Some(self.profile.clone());
+        self.change(|entries| entries.push(entry))
+    }
+
+    pub fn remove(&mut self, id: &str) -> std::io::Result<()> {
+        self.change(|entries| entries.retain(|e| e.id != id))
+    }
+
+    // Re-reads the journal, makes the change and saves it, all while holding
+    // the lock so nobody else's change is lost in between.
+    fn change(&mut self, f: impl FnOnce(&mut Vec<PendingCoffee>)) -> std::io::Result<()> {
+        let lock = self.lock()?;
+        lock.lock()?;
+        self.read()?;
+        f(&mut self.entries);
+        self.save()
+    }
+
+    // The journal itself is replaced on every save, so the lock is taken on a
+    // file next to it which stays put. It's let go when dropped.
+    fn lock(&self) -> std::io::Result<File> {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".lock");
+        OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+    }
+
+    // Coffees from before profiles are taken as this profile's.
+    fn read(&mut self) -> std::io::Result<()> {
+        let all: Vec<PendingCoffee> = if self.path.exists() {
+            serde_json::from_reader(BufReader::new(File::open(&self.path)?))?
+        } else {
+            Vec::new()
+        };
+        let profile = &self.profile;
+        let (mut entries, others): (Vec<_>, _) = all
+            .into_iter()
+            .partition(|e| e.profile.as_deref().is_none_or(|p| p == profile));
+        for e in &mut entries {
+            e.profile = Some(profile.clone());
+        }
+        self.entries = entries;
+        self.others = others;
+        Ok(())
+    }
+
+    // Writes to a temporary file first so a crash can't leave a half written
+    // journal behind.
+    fn save(&self) -> std::io::Result<()> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(format!(".{}.tmp", std::process::id()));
+        let tmp = PathBuf::from(tmp);
+
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        let all: Vec<_> = self.others.iter().chain(&self.entries).collect();
+        serde_json::to_writer(&mut writer, &all)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        drop(writer);
+
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The journal and its lock file.
+    fn remove(path: &Path) {
+        fs::remove_file(path).unwrap();
+        let mut lock = path.to_path_buf().into_os_string();
+        lock.push(".lock");
+        fs::remove_file(lock).unwrap();
+    }
+
+    #[test]
+    pub fn test_profiles_kept_apart() {
+        let path = std::env::temp_dir().join(format!("coffee_journal_test_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut work = Journal::open(&path, "work").unwrap();
+        work.push(PendingCoffee::new(100, 1, None)).unwrap();
+        let mut home = Journal::open(&path, "home").unwrap();
+        assert!(home.entries().is_empty());
+        home.push(PendingCoffee::new(200, 2, None)).unwrap();
+
+        // Each only sees, and so only sends, its own.
+        let work = Journal::open(&path, "work").unwrap();
+        assert_eq!(work.entries().len(), 1);
+        assert_eq!(work.entries()[0].shots, 1);
+        let mut home = Journal::open(&path, "home").unwrap();
+        assert_eq!(home.entries().len(), 1);
+        assert_eq!(home.entries()[0].shots, 2);
+
+        // Removing one of home's leaves work's alone.
+        let id = home.entries()[0].id.clone();
+        home.remove(&id).unwrap();
+        assert!(Journal::open(&path, "home").unwrap().entries().is_empty());
+        assert_eq!(Journal::open(&path, "work").unwrap().entries().len(), 1);
+
+        remove(&path);
+    }
+
+    #[test]
+    pub fn test_concurrent_changes() {
+        let path = std::env::temp_dir().join(format!("coffee_journal_race_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        // Opened before either has added anything, yet neither add is lost.
+        let mut first = Journal::open(&path, "home").unwrap();
+        let mut second = Journal::open(&path, "home").unwrap();
+        first.push(PendingCoffee::new(100, 1, None)).unwrap();
+        second.push(PendingCoffee::new(200, 2, None)).unwrap();
+        assert_eq!(second.entries().len(), 2);
+        let id = first.entries()[0].id.clone();
+        second.remove(&id).unwrap();
+        first.push(PendingCoffee::new(300, 3, None)).unwrap();
+        let shots: Vec<_> = first.entries().iter().map(|e| e.shots).collect();
+        assert_eq!(shots, vec![2, 3]);
+
+        let adders: Vec<_> = (0..8)
+            .map(|_| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    let mut journal = Journal::open(&path, "home").unwrap();
+                    for _ in 0..10 {
+                        journal.push(PendingCoffee::new(400, 4, None)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for adder in adders {
+            adder.join().unwrap();
+        }
+        assert_eq!(Journal::open(&path, "home").unwrap().entries().len(), 82);
+
+        remove(&path);
+    }
+
+    #[test]
+    pub fn test_old_entries() {
+        let path = std::env::temp_dir().join(format!("coffee_journal_old_{}", std::process::id()));
+        fs::write(&path, r#"[{"id": "a", "utc_time": 100, "shots": 1}]"#).unwrap();
+
+        // Whoever opens it first takes them on.
+        let mut journal = Journal::open(&path, "home").unwrap();
+        assert_eq!(journal.entries().len(), 1);
+        journal.push(PendingCoffee::new(200, 2, None)).unwrap();
+        assert_eq!(Journal::open(&path, "home").unwrap().entries().len(), 2);
+        assert!(Journal::open(&path, "work").unwrap().entries().is_empty());
+
+        remove(&path);
+    }
+}
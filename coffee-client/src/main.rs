@@ -1,72 +1,688 @@
+mod chart;
+mod completions;
+mod config;
+mod dashboard;
+mod doctor;
+mod drinks;
 mod error;
+mod hooks;
+mod journal;
+mod man;
+mod prompt;
+mod stats;
+mod when;
 
-use coffee_common::coffee::coffee_client::CoffeeClient;
-use coffee_common::coffee::{AddCoffeeRequest, CoffeeItem, ListCoffeeRequest, RegisterRequest};
+use chart::Style;
+use coffee_sdk::{
+    Change, Client, Code, Coffee, Drink, RetryPolicy, Server, TlsOptions, WatchEvent,
+    FUTURE_TOLERANCE_SECS,
+};
+use config::{CoffeeConfig, Profile};
+use drinks::DrinkCache;
 use error::ClientError;
+use hooks::{Event, EventCoffee, Today};
+use journal::{Journal, PendingCoffee};
+use prompt::Cache;
+use stats::Period;
+use when::When;
 
 use chrono::prelude::*;
-use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufReader, BufWriter};
-use std::path::Path;
-use tonic::Request;
+use clap::{App, AppSettings, Arg, ArgMatches, Shell, SubCommand};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 static DEFAULT_SERVER: &str = "[::1]:50051";
 static DEFAULT_CONFIG: &str = ".coffee";
+static DEFAULT_JOURNAL: &str = ".coffee_journal";
+static DEFAULT_PROMPT_CACHE: &str = ".coffee_prompt";
+static DEFAULT_DRINK_CACHE: &str = ".coffee_drinks";
+// Where `login` looks for a key before reading stdin.
+static DEFAULT_KEY_ENV: &str = "COFFEE_API_KEY";
+// How far back `stats` looks when not given a range.
+const DEFAULT_STATS_DAYS: i64 = 30;
+// How long a quick connection waits on the server, see `Connection::quick`.
+const QUICK_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
 
-#[derive(Debug, Default, Deserialize, Serialize)]
-struct CoffeeConfig {
-    api_key: String,
+// Gets the API Key from either the args or the profile. Args take precedence
+// over the profile.
+fn get_api_key<'a>(
+    profile: Option<&'a Profile>,
+    args: &'a ArgMatches,
+) -> Result<&'a str, ClientError> {
+    match args.value_of("key") {
+        Some(k) => Ok(k),
+        None => profile
+            .and_then(|p| p.api_key.as_deref())
+            .ok_or(ClientError::NoApiKey),
+    }
+}
+
+// Reads an API key from the environment variable `env`, or failing that
+// stdin, prompting without echoing it if stdin is a terminal.
+fn read_api_key(env: &str) -> Result<String, ClientError> {
+    let key = match std::env::var(env) {
+        Ok(k) => k,
+        Err(_) if atty::is(atty::Stream::Stdin) => {
+            rpassword::read_password_from_tty(Some("API key: "))?
+        }
+        Err(_) => {
+            let mut line = String::new();
+            std::io::stdin().read_line(&mut line)?;
+            line
+        }
+    };
+    let key = key.trim();
+    if key.is_empty() {
+        return Err(ClientError::NoApiKey);
+    }
+    Ok(key.into())
+}
+
+fn journal_path() -> PathBuf {
+    let mut path = dirs::home_dir().expect("Could not locate a home directory...");
+    path.push(DEFAULT_JOURNAL);
+    path
+}
+
+fn prompt_cache_path() -> PathBuf {
+    let mut path = dirs::home_dir().expect("Could not locate a home directory...");
+    path.push(DEFAULT_PROMPT_CACHE);
+    path
+}
+
+fn drink_cache_path() -> PathBuf {
+    let mut path = dirs::home_dir().expect("Could not locate a home directory...");
+    path.push(DEFAULT_DRINK_CACHE);
+    path
+}
+
+// The global flags to give a `coffee` run in the background, so it talks to
+// the same server the same way.
+fn background_args(matches: &ArgMatches, config_path: &Path) -> Vec<OsString> {
+    let mut args = vec!["--config".into(), config_path.into()];
+    for flag in &["profile", "server", "timeout", "retries"] {
+        if let Some(v) = matches.value_of(flag) {
+            args.push(format!("--{}", flag).into());
+            args.push(v.into());
+        }
+    }
+    args
+}
+
+// Connects to the server, authenticating with `api_key`.
+async fn connect(server: &Server, api_key: &str) -> Result<Client, ClientError> {
+    Ok(Client::connect(server).await?.with_api_key(api_key))
+}
+
+// A connection made the first time it's needed and then shared, so commands
+// that might not need the server at all don't wait on it, and those making
+// several calls only connect once.
+struct Connection<'a> {
+    server: &'a Server,
+    api_key: &'a str,
+    client: Option<Client>,
+    quick: bool,
+}
+
+impl<'a> Connection<'a> {
+    fn new(server: &'a Server, api_key: &'a str) -> Self {
+        Connection {
+            server,
+            api_key,
+            client: None,
+            quick: false,
+        }
+    }
+
+    // Gives up on the server after one short try, for commands that carry on
+    // without it, like add, which keeps the coffee locally: there's no sense
+    // waiting long to find out. Connecting doesn't wait on the server to
+    // answer, so it's asked about its health as well. Calls once connected
+    // still get the usual timeout and retries.
+    fn quick(server: &'a Server, api_key: &'a str) -> Self {
+        Connection {
+            quick: true,
+            ..Connection::new(server, api_key)
+        }
+    }
+
+    async fn get(&mut self) -> Result<&Client, ClientError> {
+        if self.client.is_none() {
+            let client = if self.quick {
+                let once = Server {
+                    timeout: Some(
+                        self.server
+                            .timeout
+                            .map_or(QUICK_CONNECT_TIMEOUT, |t| t.min(QUICK_CONNECT_TIMEOUT)),
+                    ),
+                    retry: RetryPolicy::never(),
+                    ..self.server.clone()
+                };
+                let client = connect(&once, self.api_key).await?;
+                match client.health().await {
+                    Err(e) if e.is_offline() => return Err(e.into()),
+                    // Anything else is for the calls to come to report.
+                    _ => {}
+                }
+                client
+                    .with_timeout(self.server.timeout)
+                    .with_retry(self.server.retry.clone())
+            } else {
+                connect(self.server, self.api_key).await?
+            };
+            self.client = Some(client);
+        }
+        Ok(self.client.as_ref().unwrap())
+    }
+}
+
+// Today's shots and coffees for the hooks, from the server if it can be asked
+// and otherwise from what the prompt cache last heard, with anything still in
+// the journal on top.
+async fn today_so_far(client: Option<&Client>, cache_key: &str) -> Today {
+    let now = Local::now();
+    let mut summary = Cache::open(&prompt_cache_path()).get(cache_key);
+    if let Some(client) = client {
+        let start_of_day = now.date().and_hms(0, 0, 0).timestamp();
+        if let Ok(coffees) = client.list_coffees(start_of_day, 0).await {
+            prompt::summarise(&mut summary, &coffees, now);
+        }
+    }
+    let pending = Journal::open(&journal_path(), cache_key)
+        .map(|j| j.entries().to_vec())
+        .unwrap_or_default();
+    let (shots, coffees) = prompt::today(&summary, &pending, now);
+    Today { shots, coffees }
+}
+
+// Runs the add hook, and the limit_exceeded one if this coffee is what went
+// over. Today's totals are only asked for when there's a hook to use them.
+async fn run_add_hooks(
+    profile: Option<&Profile>,
+    cache_key: &str,
+    client: Option<&Client>,
+    coffee: &Coffee,
+    pending: bool,
+) {
+    let (hooks, limit) = match profile {
+        Some(p) => (&p.hooks, p.daily_limit),
+        None => return,
+    };
+    let limit = limit.filter(|_| hooks.limit_exceeded.is_some());
+    if hooks.add.is_none() && limit.is_none() {
+        return;
+    }
+
+    let today = today_so_far(client, cache_key).await;
+    let event = Event::Add {
+        coffee: coffee.into(),
+        pending,
+        today,
+    };
+    hooks::run(hooks, cache_key, &event).await;
+    if let Some(limit) = limit.filter(|l| hooks::crosses_limit(*l, &today, coffee.shots)) {
+        let event = Event::LimitExceeded { limit, today };
+        hooks::run(hooks, cache_key, &event).await;
+    }
 }
 
-/// The config won't always exist - a helper function to check it for us.
-fn read_config_if_exists(cfg: &Path) -> Option<std::io::Result<CoffeeConfig>> {
-    if cfg.exists() {
-        Some(read_config(cfg))
+// Reads a number given with `--<arg>`, complaining if it isn't one.
+fn parse_number<T: std::str::FromStr>(
+    args: &ArgMatches,
+    arg: &str,
+) -> Result<Option<T>, ClientError> {
+    match args.value_of(arg) {
+        None => Ok(None),
+        Some(v) => v
+            .parse()
+            .map(Some)
+            .map_err(|_| ClientError::BadArgument(format!("--{} needs to be a whole number", arg))),
+    }
+}
+
+// Whether an error just means the server couldn't be reached right now.
+fn is_offline(e: &ClientError) -> bool {
+    match e {
+        ClientError::Sdk(e) => e.is_offline(),
+        _ => false,
+    }
+}
+
+// Sends everything in the journal to the server, oldest first, removing each
+// entry once the server has it. Returns how many were sent.
+async fn flush_journal(client: &Client, journal: &mut Journal) -> Result<usize, ClientError> {
+    let mut flushed = 0;
+    for entry in journal.entries().to_vec() {
+        match client
+            .add_coffee(&pending_coffee(&entry), Some(&entry.id))
+            .await
+        {
+            Ok(()) => {
+                journal.remove(&entry.id)?;
+                flushed += 1;
+            }
+            // Trying again won't help, so don't let it block the rest.
+            Err(coffee_sdk::Error::Status(status)) if status.code() == Code::InvalidArgument => {
+                eprintln!(
+                    "Dropping a coffee the server rejected: {}",
+                    status.message()
+                );
+                journal.remove(&entry.id)?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(flushed)
+}
+
+// Parses a time argument in the user's timezone, complaining if it can't.
+fn parse_when(arg: &str, value: &str) -> Result<When<Local>, ClientError> {
+    when::parse(value, &Local::now()).map_err(|e| {
+        ClientError::BadArgument(format!("Cannot understand {} '{}': {}", arg, value, e))
+    })
+}
+
+// The range of times to list as seconds from unix epoch, start inclusive and
+// end exclusive, where 0 leaves that end open.
+fn list_range(cmd: &ArgMatches) -> Result<(i64, i64), ClientError> {
+    if let Some(on) = cmd.value_of("on").or_else(|| cmd.value_of("DATE")) {
+        let on = parse_when("--on", on)?;
+        return Ok((on.start(), on.end()));
+    }
+    let start = match cmd.value_of("from") {
+        Some(from) => parse_when("--from", from)?.start(),
+        None => 0,
+    };
+    let end = match cmd.value_of("to") {
+        Some(to) => parse_when("--to", to)?.end(),
+        None => 0,
+    };
+    if end != 0 && end <= start {
+        return Err(ClientError::BadArgument(
+            "--to needs to be after --from".into(),
+        ));
+    }
+    Ok((start, end))
+}
+
+// Everything logged between `start` and `end`, the server's coffees along with
+// any still waiting in the journal, in time order and marked with whether
+// they're still pending. Pending coffees are sent first so the server's list
+// is as complete as it can be.
+async fn fetch_coffees(
+    server: &Server,
+    api_key: &str,
+    profile_key: &str,
+    start_utc_time: i64,
+    end_utc_time: i64,
+) -> Result<Vec<(Coffee, bool)>, ClientError> {
+    let mut journal = Journal::open(&journal_path(), profile_key)?;
+
+    // If we can't get through, what's in the journal is all we have.
+    let res = match connect(server, api_key).await {
+        Ok(client) => match flush_journal(&client, &mut journal).await {
+            Ok(_) => client
+                .list_coffees(start_utc_time, end_utc_time)
+                .await
+                .map_err(ClientError::from),
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+    let synced = match res {
+        Ok(coffees) => coffees,
+        Err(e) if is_offline(&e) => {
+            eprintln!("Could not reach the server, only showing coffees saved locally.");
+            Vec::new()
+        }
+        Err(e) => return Err(e),
+    };
+
+    let mut all: Vec<_> = synced.into_iter().map(|c| (c, false)).collect();
+    all.extend(
+        journal
+            .entries()
+            .iter()
+            .filter(|p| {
+                p.utc_time >= start_utc_time && (end_utc_time == 0 || p.utc_time < end_utc_time)
+            })
+            .map(|p| (pending_coffee(p), true)),
+    );
+    all.sort_by_key(|(c, _)| c.utc_time);
+    Ok(all)
+}
+
+// e.g. "2 shot(s) of flatwhite at 2020-06-09 08:00".
+fn describe_coffee(c: &Coffee) -> String {
+    let t = Utc.timestamp(c.utc_time, 0).with_timezone(&Local);
+    let drink = match &c.drink {
+        Some(d) => format!(" of {}", d),
+        None => String::new(),
+    };
+    format!(
+        "{} shot(s){} at {}",
+        c.shots,
+        drink,
+        t.format("%Y-%m-%d %H:%M")
+    )
+}
+
+// What the server did to take `change` back.
+fn describe_undo(change: &Change) -> String {
+    match change {
+        Change::AddCoffee(c) => format!("Removed {}.", describe_coffee(c)),
+        Change::UpdateCoffee(c) => format!("Put {} back how it was.", describe_coffee(c)),
+        Change::DeleteCoffee(c) => format!("Brought {} back.", describe_coffee(c)),
+        Change::SaveDrink(name) => format!("Put {} back how it was.", name),
+        Change::DeleteDrink(name) => format!("Brought {} back.", name),
+    }
+}
+
+fn pending_coffee(p: &PendingCoffee) -> Coffee {
+    Coffee {
+        utc_time: p.utc_time,
+        shots: p.shots,
+        drink: p.drink.clone(),
+    }
+}
+
+// Works out what's being added: a number of shots, or a drink looked up on
+// the server, or in the profile's drinks as last listed when it's down.
+async fn resolve_drink(
+    conn: &mut Connection<'_>,
+    profile_key: &str,
+    amount: &str,
+) -> Result<(i32, Option<String>), ClientError> {
+    if let Ok(shots) = amount.parse() {
+        return Ok((shots, None));
+    }
+
+    let mut cache = DrinkCache::open(&drink_cache_path());
+    let listed = match conn.get().await {
+        Ok(client) => client.list_drinks().await.map_err(ClientError::from),
+        Err(e) => Err(e),
+    };
+    let found = match listed {
+        Ok(drinks) => {
+            // Only a convenience, so not being able to keep them isn't an error.
+            let _ = cache.set(profile_key, &drinks);
+            let name = amount.to_lowercase();
+            drinks
+                .into_iter()
+                .find(|d| d.name == name)
+                .map(|d| (d.shots, d.name))
+        }
+        Err(e) if is_offline(&e) => match cache.find(profile_key, amount) {
+            Some(d) => Some((d.shots, d.name)),
+            None => return Err(e),
+        },
+        Err(e) => return Err(e),
+    };
+    match found {
+        Some((shots, name)) => Ok((shots, Some(name))),
+        None => Err(ClientError::BadArgument(format!(
+            "There's no drink called {}, see `coffee drinks list`",
+            amount
+        ))),
+    }
+}
+
+// Parses an amount of money like "3.50" into cents.
+fn parse_price(s: &str) -> Option<i32> {
+    let price: f64 = s.trim_start_matches('$').parse().ok()?;
+    if price.is_finite() && price >= 0.0 {
+        Some((price * 100.0).round() as i32)
     } else {
         None
     }
 }
 
-fn read_config(cfg: &Path) -> std::io::Result<CoffeeConfig> {
-    let reader = BufReader::new(File::open(cfg)?);
-    let config = serde_json::from_reader(reader)?;
-    Ok(config)
+async fn drinks_command(
+    server: &Server,
+    api_key: &str,
+    profile_key: &str,
+    cmd: &ArgMatches<'_>,
+) -> Result<(), ClientError> {
+    let client = connect(server, api_key).await?;
+    match cmd.subcommand() {
+        ("list", _) => {
+            let drinks = client.list_drinks().await?;
+            let _ = DrinkCache::open(&drink_cache_path()).set(profile_key, &drinks);
+            println!(
+                "{:16} {:>5} {:>9} {:>7} {:>7}",
+                "Drink", "Shots", "Caffeine", "Size", "Price"
+            );
+            let or_dash = |v: Option<i32>, f: &dyn Fn(i32) -> String| match v {
+                Some(v) => f(v),
+                None => "-".to_string(),
+            };
+            for d in drinks {
+                println!(
+                    "{:16} {:>5} {:>9} {:>7} {:>7}{}",
+                    d.name,
+                    d.shots,
+                    or_dash(d.caffeine_mg, &|v| format!("{}mg", v)),
+                    or_dash(d.size_ml, &|v| format!("{}ml", v)),
+                    or_dash(d.price_cents, &|v| format!("{}.{:02}", v / 100, v % 100)),
+                    if d.global { "" } else { "  (yours)" },
+                );
+            }
+        }
+        ("add", Some(args)) => {
+            let number = |arg: &str| parse_number::<i32>(args, arg);
+            let price_cents = match args.value_of("price") {
+                None => None,
+                Some(p) => Some(parse_price(p).ok_or_else(|| {
+                    ClientError::BadArgument("--price needs to be an amount like 3.50".into())
+                })?),
+            };
+            let drink = Drink {
+                name: args.value_of("NAME").unwrap().to_lowercase(),
+                shots: number("shots")?.unwrap_or_default(),
+                caffeine_mg: number("caffeine")?,
+                size_ml: number("size")?,
+                price_cents,
+                global: false,
+            };
+            client.save_drink(&drink).await?;
+            println!("Saved {}.", drink.name);
+        }
+        ("rm", Some(args)) => {
+            let name = args.value_of("NAME").unwrap().to_lowercase();
+            if client.delete_drink(&name).await? {
+                println!("Removed {}.", name);
+            } else {
+                println!(
+                    "You have no drink called {}, the defaults can't be removed.",
+                    name
+                );
+            }
+        }
+        _ => {}
+    }
+    Ok(())
 }
 
-fn write_config(cfg: &CoffeeConfig) -> std::io::Result<()> {
-    let mut cfg_file = dirs::home_dir().expect("Could not locate a home directory...");
-    cfg_file.push(DEFAULT_CONFIG);
-    let writer = BufWriter::new(File::create(cfg_file)?);
-    serde_json::to_writer(writer, cfg)?;
+fn profile_command(config: &mut CoffeeConfig, cmd: &ArgMatches) -> Result<(), ClientError> {
+    match cmd.subcommand() {
+        ("add", Some(args)) => {
+            let name = args.value_of("NAME").unwrap();
+            let profile = config.profile_mut(Some(name));
+            if let Some(url) = args.value_of("url") {
+                profile.server = Some(url.into());
+            }
+            if let Some(key) = args.value_of("key") {
+                profile.api_key = Some(key.into());
+            }
+            if let Some(ca) = args.value_of("ca-cert") {
+                profile.tls.ca_cert = Some(ca.into());
+            }
+            if let Some(domain) = args.value_of("domain") {
+                profile.tls.domain = Some(domain.into());
+            }
+            if let Some(drink) = args.value_of("default-drink") {
+                profile.default_drink = Some(drink.into());
+            }
+            // --timeout and --retries are global, here they're saved.
+            if let Some(timeout) = parse_number(args, "timeout")? {
+                profile.timeout = Some(timeout);
+            }
+            if let Some(retries) = parse_number(args, "retries")? {
+                profile.retries = Some(retries);
+            }
+            if let Some(format) = args.value_of("prompt-format") {
+                profile.prompt_format = Some(format.into());
+            }
+            if let Some(secs) = parse_number(args, "prompt-refresh")? {
+                profile.prompt_refresh = Some(secs);
+            }
+            if let Some(limit) = parse_number(args, "daily-limit")? {
+                profile.daily_limit = Some(limit);
+            }
+            if let Some(cmd) = args.value_of("on-add") {
+                profile.hooks.add = Some(cmd.into());
+            }
+            if let Some(cmd) = args.value_of("on-undo") {
+                profile.hooks.undo = Some(cmd.into());
+            }
+            if let Some(cmd) = args.value_of("on-limit") {
+                profile.hooks.limit_exceeded = Some(cmd.into());
+            }
+            if let Some(secs) = parse_number(args, "hook-timeout")? {
+                profile.hooks.timeout = Some(secs);
+            }
+            println!("Saved profile {}.", name);
+        }
+        ("list", _) => {
+            if config.profiles.is_empty() {
+                println!("No profiles yet, add one with `coffee profile add`.");
+            }
+            for (name, p) in &config.profiles {
+                let current = if config.current.as_deref() == Some(name) {
+                    "*"
+                } else {
+                    " "
+                };
+                println!(
+                    "{} {:15} {:30} {}{}",
+                    current,
+                    name,
+                    p.server.as_deref().unwrap_or(DEFAULT_SERVER),
+                    if p.api_key.is_some() {
+                        "key set"
+                    } else {
+                        "no key"
+                    },
+                    if p.tls.enabled() { ", tls" } else { "" },
+                );
+            }
+        }
+        ("use", Some(args)) => {
+            let name = args.value_of("NAME").unwrap();
+            if !config.profiles.contains_key(name) {
+                return Err(ClientError::BadArgument(format!(
+                    "No profile named {}",
+                    name
+                )));
+            }
+            config.current = Some(name.into());
+            println!("Now using profile {}.", name);
+        }
+        ("remove", Some(args)) => {
+            let name = args.value_of("NAME").unwrap();
+            if config.profiles.remove(name).is_none() {
+                return Err(ClientError::BadArgument(format!(
+                    "No profile named {}",
+                    name
+                )));
+            }
+            if config.current.as_deref() == Some(name) {
+                config.current = config.profiles.keys().next().cloned();
+            }
+            println!("Removed profile {}.", name);
+        }
+        _ => {}
+    }
     Ok(())
 }
 
-// Gets the API Key from either the args or the config. Args take precedence
-// over the config.
-fn get_api_key<'a>(
-    cfg: &'a Option<CoffeeConfig>,
-    args: &'a ArgMatches,
-) -> Result<&'a str, ClientError> {
-    match args.value_of("key") {
-        Some(k) => Ok(k),
-        None => match cfg {
-            Some(c) => Ok(&c.api_key),
-            None => Err(ClientError::NoApiKey),
-        },
+// The server to talk to and how, from the flags and the profile. The flags win
+// over the profile.
+fn server_for(matches: &ArgMatches, profile: Option<&Profile>) -> Result<Server, ClientError> {
+    let mut server = Server::new(
+        matches
+            .value_of("server")
+            .map(String::from)
+            .or_else(|| profile.and_then(|p| p.server.clone()))
+            .unwrap_or_else(|| DEFAULT_SERVER.into()),
+    );
+    if let Some(p) = profile {
+        server.tls = TlsOptions {
+            ca_cert: p.tls.ca_cert.clone(),
+            domain: p.tls.domain.clone(),
+        };
     }
+    match parse_number(matches, "timeout")?.or_else(|| profile.and_then(|p| p.timeout)) {
+        Some(0) => server.timeout = None,
+        Some(secs) => server.timeout = Some(Duration::from_secs(secs)),
+        None => {}
+    }
+    if let Some(retries) =
+        parse_number::<u32>(matches, "retries")?.or_else(|| profile.and_then(|p| p.retries))
+    {
+        server.retry.max_attempts = retries.saturating_add(1);
+    }
+    Ok(server)
 }
 
-#[tokio::main]
-async fn main() -> Result<(), ClientError> {
+// Tells the user what went wrong, and what they might do about it.
+fn report(e: &ClientError) {
+    let msg = e.to_string();
+    let mut chars = msg.chars();
+    let msg = match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => msg,
+    };
+    eprintln!("Error: {}", msg);
+    if let Some(hint) = e.hint() {
+        eprintln!("Hint: {}", hint);
+    }
+}
+
+// Help text clap can hold on to, for the few that are worked out. Only one App
+// is made per run.
+fn leak(s: String) -> &'static str {
+    Box::leak(s.into_boxed_str())
+}
+
+// The whole command line. `completions` and `man` are made from it too, so they
+// can't drift from what's accepted.
+fn cli() -> App<'static, 'static> {
     let key_arg = Arg::with_name("key")
         .required(false)
         .short("k")
         .long("key")
+        .takes_value(true)
         .help("Override the API key used");
-    let matches = App::new(env!("CARGO_PKG_NAME"))
+    let on_arg = Arg::with_name("on")
+        .long("on")
+        .takes_value(true)
+        .required(false)
+        .conflicts_with_all(&["from", "to"])
+        .help("Only coffees on this day, e.g. \"yesterday\" or \"last monday\"");
+    let from_arg = Arg::with_name("from")
+        .long("from")
+        .takes_value(true)
+        .required(false)
+        .help("Only coffees from this time on");
+    let to_arg = Arg::with_name("to")
+        .long("to")
+        .takes_value(true)
+        .required(false)
+        .help("Only coffees up to this time, or to the end of this day");
+    App::new(env!("CARGO_PKG_NAME"))
         .setting(AppSettings::ArgRequiredElseHelp)
         .setting(AppSettings::ColoredHelp)
         .version(env!("CARGO_PKG_VERSION"))
@@ -76,13 +692,10 @@ async fn main() -> Result<(), ClientError> {
             Arg::with_name("server")
                 .short("s")
                 .long("server")
-                .help(
-                    format!(
-                        "Override the default server to connect to, default is: {}",
-                        DEFAULT_SERVER
-                    )
-                    .as_str(),
-                )
+                .help(leak(format!(
+                    "Override the default server to connect to, default is: {}",
+                    DEFAULT_SERVER
+                )))
                 .takes_value(true)
                 .required(false)
                 .global(true),
@@ -96,6 +709,32 @@ async fn main() -> Result<(), ClientError> {
                 .required(false)
                 .global(true),
         )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .help("Seconds to wait on each call to the server, 0 waits forever, default is 10")
+                .takes_value(true)
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("retries")
+                .long("retries")
+                .help("How many times to retry a call that failed for a passing reason, default is 3")
+                .takes_value(true)
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .short("p")
+                .long("profile")
+                .env("COFFEE_PROFILE")
+                .help("Use this profile rather than the current one")
+                .takes_value(true)
+                .required(false)
+                .global(true),
+        )
         .subcommand(
             SubCommand::with_name("register")
                 .about("Registers an email against an API Key")
@@ -105,14 +744,43 @@ async fn main() -> Result<(), ClientError> {
                         .help("An email address (to be verified against)"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("login")
+                .about("Saves an existing API key to the profile, once the server has checked it")
+                .long_about(
+                    "Saves an existing API key to the profile, once the server has checked it. \
+                     The key is read from the environment variable named by --key-env if it's \
+                     set, and otherwise from stdin, so it never ends up in your shell history.",
+                )
+                .arg(
+                    Arg::with_name("key-env")
+                        .long("key-env")
+                        .takes_value(true)
+                        .required(false)
+                        .help(leak(format!(
+                            "The environment variable to read the key from, default is: {}",
+                            DEFAULT_KEY_ENV
+                        ))),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("logout").about("Removes the API key from the profile"),
+        )
         .subcommand(
             SubCommand::with_name("add")
                 .about("Adds a coffee")
                 .arg(&key_arg)
                 .arg(
                     Arg::with_name("AMOUNT")
-                        .required(true)
-                        .help("The amount of coffee, in shots"),
+                        .required(false)
+                        .help("The amount of coffee in shots, or a drink from `coffee drinks list`. Defaults to the profile's default drink"),
+                )
+                .arg(
+                    Arg::with_name("at")
+                        .long("at")
+                        .takes_value(true)
+                        .required(false)
+                        .help("When it was had, e.g. \"08:30\", \"yesterday 3pm\" or \"2h ago\". Defaults to now"),
                 ),
         )
         .subcommand(
@@ -122,111 +790,676 @@ async fn main() -> Result<(), ClientError> {
                 .arg(
                     Arg::with_name("DATE")
                         .required(false)
-                        .help("A date to list the coffees for."),
+                        .conflicts_with_all(&["on", "from", "to"])
+                        .help("A date to list the coffees for, the same as --on"),
+                )
+                .arg(&on_arg)
+                .arg(&from_arg)
+                .arg(&to_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Charts the coffees for this registered user, over the last 30 days by default")
+                .arg(&key_arg)
+                .arg(&on_arg)
+                .arg(&from_arg)
+                .arg(&to_arg)
+                .arg(
+                    Arg::with_name("by")
+                        .long("by")
+                        .takes_value(true)
+                        .required(false)
+                        .possible_values(Period::ALL)
+                        .default_value("day")
+                        .help("Chart the shots per day or per week"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("tui")
+                .about("A full-screen dashboard that updates as coffees are logged")
+                .arg(&key_arg)
+                .arg(
+                    Arg::with_name("preset")
+                        .long("preset")
+                        .takes_value(true)
+                        .required(false)
+                        .help("The shots or drink added with the add key, defaults to the profile's default drink or 1"),
                 ),
         )
-        .get_matches();
+        .subcommand(
+            SubCommand::with_name("sync")
+                .about("Sends coffees saved while the server was unreachable")
+                .arg(&key_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("undo")
+                .about("Takes back your last change, if it was recent")
+                .arg(&key_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("pending")
+                .about("Lists coffees saved locally that haven't been synced yet"),
+        )
+        .subcommand(
+            SubCommand::with_name("drinks")
+                .about("Manages the drinks that can be added by name")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .arg(&key_arg)
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("Lists your drinks along with the server's defaults"),
+                )
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Adds a drink, or replaces one of the same name")
+                        .arg(
+                            Arg::with_name("NAME")
+                                .required(true)
+                                .help("A single word, e.g. flatwhite"),
+                        )
+                        .arg(
+                            Arg::with_name("shots")
+                                .long("shots")
+                                .takes_value(true)
+                                .required(true)
+                                .help("How many shots it has"),
+                        )
+                        .arg(
+                            Arg::with_name("caffeine")
+                                .long("caffeine")
+                                .takes_value(true)
+                                .help("The caffeine in it, in mg"),
+                        )
+                        .arg(
+                            Arg::with_name("size")
+                                .long("size")
+                                .takes_value(true)
+                                .help("Its size, in ml"),
+                        )
+                        .arg(
+                            Arg::with_name("price")
+                                .long("price")
+                                .takes_value(true)
+                                .help("What it costs, e.g. 4.50"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("rm")
+                        .about("Removes one of your drinks")
+                        .arg(Arg::with_name("NAME").required(true)),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("profile")
+                .about("Manages the profiles for the servers you use")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Adds a profile, or updates an existing one")
+                        .arg(Arg::with_name("NAME").required(true))
+                        .arg(
+                            Arg::with_name("url")
+                                .long("url")
+                                .takes_value(true)
+                                .help("The server, as host:port or a full http(s):// URL"),
+                        )
+                        .arg(
+                            Arg::with_name("key")
+                                .short("k")
+                                .long("key")
+                                .takes_value(true)
+                                .help("The API key to use with this server"),
+                        )
+                        .arg(
+                            Arg::with_name("ca-cert")
+                                .long("ca-cert")
+                                .takes_value(true)
+                                .help("A PEM file to trust the server's certificate with, turns on TLS"),
+                        )
+                        .arg(
+                            Arg::with_name("domain")
+                                .long("domain")
+                                .takes_value(true)
+                                .help("The name on the server's certificate, turns on TLS"),
+                        )
+                        .arg(
+                            Arg::with_name("default-drink")
+                                .long("default-drink")
+                                .takes_value(true)
+                                .help("What `add` adds when not given an amount"),
+                        )
+                        .arg(
+                            Arg::with_name("prompt-format")
+                                .long("prompt-format")
+                                .takes_value(true)
+                                .help("The format `prompt` uses, see `coffee prompt --help`"),
+                        )
+                        .arg(
+                            Arg::with_name("prompt-refresh")
+                                .long("prompt-refresh")
+                                .takes_value(true)
+                                .help("How often `prompt` asks the server again, in seconds"),
+                        )
+                        .arg(
+                            Arg::with_name("daily-limit")
+                                .long("daily-limit")
+                                .takes_value(true)
+                                .help("Shots a day, going over which runs the --on-limit hook"),
+                        )
+                        .arg(
+                            Arg::with_name("on-add")
+                                .long("on-add")
+                                .takes_value(true)
+                                .help("A command to run after adding a coffee"),
+                        )
+                        .arg(
+                            Arg::with_name("on-undo")
+                                .long("on-undo")
+                                .takes_value(true)
+                                .help("A command to run after an undo"),
+                        )
+                        .arg(
+                            Arg::with_name("on-limit")
+                                .long("on-limit")
+                                .takes_value(true)
+                                .help("A command to run when an add goes over --daily-limit"),
+                        )
+                        .arg(
+                            Arg::with_name("hook-timeout")
+                                .long("hook-timeout")
+                                .takes_value(true)
+                                .help("How long a hook can run before it's stopped, in seconds"),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("list").about("Lists the profiles"))
+                .subcommand(
+                    SubCommand::with_name("use")
+                        .about("Makes a profile the current one")
+                        .arg(Arg::with_name("NAME").required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("remove")
+                        .about("Removes a profile")
+                        .arg(Arg::with_name("NAME").required(true)),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Prints coffees as they are logged")
+                .arg(&key_arg)
+                .arg(
+                    Arg::with_name("since")
+                        .long("since")
+                        .takes_value(true)
+                        .required(false)
+                        .help("Also print coffees logged since this RFC 3339 time"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("prompt")
+                .about("Prints a summary of today's coffee for a shell prompt")
+                .long_about(
+                    "Prints a summary of today's coffee for a shell prompt. It's read from a \
+                     local cache so it's fast enough to run for every prompt, and the cache is \
+                     refreshed in the background when it gets old and after adds.\n\n\
+                     The format can use {shots} and {coffees} for today's shots and coffees, \
+                     {last} for the time since the last coffee, e.g. 45m, {pending} for coffees \
+                     not yet sent to the server and {stale}, a ? when the cache is out of date.",
+                )
+                .arg(&key_arg)
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .help(leak(format!(
+                            "The format to print, default is: {}",
+                            prompt::DEFAULT_FORMAT
+                        ))),
+                )
+                .arg(
+                    Arg::with_name("refresh")
+                        .long("refresh")
+                        .help("Brings the cache up to date from the server now, printing nothing"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("doctor")
+                .about("Checks the config, the connection to the server and the API key")
+                .arg(&key_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("Prints a completion script for a shell")
+                .long_about(
+                    "Prints a completion script for a shell. As well as the commands and flags, \
+                     it completes profile names and drinks, asking `coffee` for them as you type.",
+                )
+                .arg(
+                    Arg::with_name("SHELL")
+                        .required(true)
+                        .possible_values(&Shell::variants())
+                        .help("The shell to complete for"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("man").about("Prints the man page, as roff"))
+        .subcommand(
+            // What the completion scripts call to fill in names.
+            SubCommand::with_name("complete")
+                .setting(AppSettings::Hidden)
+                .arg(&key_arg)
+                .arg(Arg::with_name("BEFORE").required(true))
+                .arg(Arg::with_name("PREV").required(true)),
+        )
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run().await {
+        report(&e);
+        std::process::exit(e.exit_code());
+    }
+}
 
-    let config = match matches.value_of("config") {
-        Some(s) => read_config_if_exists(Path::new(s)),
+async fn run() -> Result<(), ClientError> {
+    let matches = cli().get_matches_safe();
+    let matches = match matches {
+        Ok(m) => m,
+        // Help and the version are printed as asked, with a zero exit code.
+        Err(e) if !e.use_stderr() => e.exit(),
+        Err(e) => {
+            eprintln!("{}", e.message);
+            std::process::exit(error::EXIT_USAGE);
+        }
+    };
+
+    let config_path = match matches.value_of("config") {
+        Some(s) => PathBuf::from(s),
         None => {
             let mut home = dirs::home_dir().expect("Could not locate a home directory...");
             home.push(DEFAULT_CONFIG);
-            read_config_if_exists(home.as_path())
+            home
         }
+    };
+    if let Some(cmd) = matches.subcommand_matches("completions") {
+        let shell = cmd.value_of("SHELL").unwrap().parse().unwrap();
+        completions::generate(cli(), shell, &mut std::io::stdout())?;
+        return Ok(());
+    } else if matches.subcommand_matches("man").is_some() {
+        man::render(&cli(), &mut std::io::stdout())?;
+        return Ok(());
+    } else if let Some(cmd) = matches.subcommand_matches("complete") {
+        completions::complete(&config_path, &matches, cmd).await;
+        return Ok(());
     }
-    .and_then(|f| match f {
-        Ok(cfg) => Some(cfg),
-        Err(e) => {
-            eprintln!("Could not read config file: {}", e);
-            None
+    // The doctor reports on a broken config rather than stopping at it.
+    if let Some(cmd) = matches.subcommand_matches("doctor") {
+        return doctor::run(&config_path, &matches, cmd).await;
+    }
+    let mut config = CoffeeConfig::load(&config_path).map_err(ClientError::Config)?;
+
+    let profile_name = matches.value_of("profile");
+    if let Some(name) = profile_name {
+        // Registering or logging in makes the profile if it's new.
+        let creates_profile = matches!(matches.subcommand_name(), Some("register" | "login"));
+        if config.profile(Some(name)).is_none() && !creates_profile {
+            return Err(ClientError::BadArgument(format!(
+                "No profile named {}, add it with `coffee profile add {}`",
+                name, name
+            )));
         }
-    });
+    }
+    let profile = config.profile(profile_name).cloned();
+    let profile = profile.as_ref();
+    // What the profile in use is known as in the prompt cache and to hooks.
+    let profile_key = profile_name
+        .or(config.current.as_deref())
+        .unwrap_or_default()
+        .to_string();
 
-    let addr = matches.value_of("server").unwrap_or(DEFAULT_SERVER);
-    let mut client = CoffeeClient::connect(format!("http://{}", addr)).await?;
+    let server = server_for(&matches, profile)?;
 
     if let Some(cmd) = matches.subcommand_matches("register") {
-        // TODO: The api key should be mailed, this isn't great - we need a subcommand to take the apikey and write it to the config.
-        // For now we'll just write it back to the config.
-
-        let reg_req = Request::new(RegisterRequest {
-            email: cmd.value_of("EMAIL").unwrap_or("").into(),
-        });
-
-        let resp = client.register(reg_req).await?;
-        let resp = resp.get_ref();
-
-        if resp.success {
-            // Make sure we only update the api key here if config already
-            // exists - so use the existing one or get a default instance of it.
-            let mut config = config.or(Some(CoffeeConfig::default())).unwrap();
-            config.api_key = resp.api_key.clone();
-            write_config(&config)?;
-            println!("Config updates.");
+        let client = Client::connect(&server).await?;
+
+        // TODO: The api key should be mailed, this isn't great. For now we'll
+        // just write it back to the config, a mailed key can be saved with
+        // `coffee login`.
+        let api_key = client.register(cmd.value_of("EMAIL").unwrap_or("")).await?;
+
+        // The key goes in the profile in use, which is made if need be.
+        config.profile_mut(profile_name).api_key = Some(api_key);
+        config.save(&config_path)?;
+        println!("Config updates.");
+    } else if let Some(cmd) = matches.subcommand_matches("login") {
+        let api_key = read_api_key(cmd.value_of("key-env").unwrap_or(DEFAULT_KEY_ENV))?;
+
+        // Only keys the server knows about are saved.
+        let client = connect(&server, &api_key).await?;
+        match client.verify_key().await {
+            Ok(_) => {}
+            Err(e) if e.code() == Some(Code::Unauthenticated) => {
+                return Err(ClientError::UnknownApiKey);
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        config.log_in(profile_name, api_key, matches.value_of("server"));
+        config.save(&config_path)?;
+        println!("Logged in, the key is saved in {}.", config_path.display());
+    } else if matches.subcommand_matches("logout").is_some() {
+        if config.log_out(profile_name) {
+            config.save(&config_path)?;
+            println!("Logged out.");
         } else {
-            eprintln!("Server error when registering.");
-            return Err(ClientError::RegistrationError);
+            println!("Not logged in.");
         }
     } else if let Some(cmd) = matches.subcommand_matches("add") {
-        let api_key = get_api_key(&config, cmd)?;
+        let api_key = get_api_key(profile, cmd)?;
 
-        let shots = match cmd.value_of("AMOUNT").unwrap_or("").parse() {
-            Ok(i) => i,
-            Err(e) => {
-                eprintln!("Cannot convert argument to number: {}", e);
-                return Err(ClientError::BadArgument);
+        let amount = match cmd
+            .value_of("AMOUNT")
+            .or_else(|| profile.and_then(|p| p.default_drink.as_deref()))
+        {
+            Some(a) => a,
+            None => {
+                return Err(ClientError::BadArgument(
+                    "No amount given, and the profile has no default drink".into(),
+                ));
+            }
+        };
+        let mut conn = Connection::quick(&server, api_key);
+        let (shots, drink) = match resolve_drink(&mut conn, &profile_key, amount).await {
+            Ok(d) => d,
+            Err(e) if is_offline(&e) => {
+                eprintln!(
+                    "Could not reach the server to look up {}, and it hasn't been seen before. Add it as a number of shots instead.",
+                    amount
+                );
+                return Err(e);
             }
+            Err(e) => return Err(e),
         };
 
         // Seconds from unix epoch.
-        let utc_time = Utc::now().timestamp();
-
-        let add_req = Request::new(AddCoffeeRequest {
-            api_key: api_key.into(),
-            coffee: Some(CoffeeItem { utc_time, shots }),
-        });
-        let resp = client.add_coffee(add_req).await?;
-
-        if resp.get_ref().success {
-            println!("Done!");
-        } else {
-            return Err(ClientError::AddFailed);
+        let utc_time = match cmd.value_of("at") {
+            Some(at) => match parse_when("--at", at)? {
+                When::At(t) => t.timestamp(),
+                When::Day(_) => {
+                    return Err(ClientError::BadArgument(format!(
+                        "--at needs a time of day as well, e.g. \"{} 8am\"",
+                        at
+                    )));
+                }
+            },
+            None => Utc::now().timestamp(),
+        };
+        if utc_time > Utc::now().timestamp() + FUTURE_TOLERANCE_SECS {
+            return Err(ClientError::BadArgument(
+                "Cannot add a coffee in the future".into(),
+            ));
         }
-    } else if let Some(cmd) = matches.subcommand_matches("list") {
-        let api_key = get_api_key(&config, cmd)?;
 
-        let list_req = Request::new(ListCoffeeRequest {
-            api_key: api_key.into(),
-            start_utc_time: 0,
-            end_utc_time: 0,
-        });
+        // Into the journal first, so it's kept even if the server is down.
+        let mut journal = Journal::open(&journal_path(), &profile_key)?;
+        let added = PendingCoffee::new(utc_time, shots, drink);
+        journal.push(added.clone())?;
 
-        let resp = client.list_coffee(list_req).await?;
+        let res = match conn.get().await {
+            Ok(client) => flush_journal(client, &mut journal).await,
+            Err(e) => Err(e),
+        };
+        let pending = match res {
+            Ok(_) => {
+                println!("Done!");
+                prompt::refresh_in_background(background_args(&matches, &config_path), api_key);
+                false
+            }
+            Err(ClientError::Sdk(e)) if e.is_offline() => {
+                println!(
+                    "Could not reach the server ({}), saved locally ({} pending). Run `coffee sync` once it's back.",
+                    e,
+                    journal.entries().len()
+                );
+                true
+            }
+            Err(e) => return Err(e),
+        };
+        let client = if pending { None } else { conn.client.as_ref() };
+        let coffee = pending_coffee(&added);
+        run_add_hooks(profile, &profile_key, client, &coffee, pending).await;
+    } else if let Some(cmd) = matches.subcommand_matches("list") {
+        let api_key = get_api_key(profile, cmd)?;
+        let (start_utc_time, end_utc_time) = list_range(cmd)?;
+        let all =
+            fetch_coffees(&server, api_key, &profile_key, start_utc_time, end_utc_time).await?;
 
-        if !&resp.get_ref().coffees.is_empty() {
-            let mut daily_coffees = HashMap::new();
-            for coffee in &resp.get_ref().coffees {
+        if !all.is_empty() {
+            let mut daily_coffees = BTreeMap::new();
+            for coffee in &all {
                 // Group the coffees by date.
-                let t = Utc.timestamp(coffee.utc_time, 0).with_timezone(&Local);
-                let coffees = daily_coffees.entry(t.date()).or_insert_with(|| Vec::new());
+                let t = Utc.timestamp(coffee.0.utc_time, 0).with_timezone(&Local);
+                let coffees = daily_coffees.entry(t.date()).or_insert_with(Vec::new);
                 coffees.push(coffee);
             }
 
             for (date, coffees) in daily_coffees {
                 let mut acc = 0;
                 println!("{:20}", date);
-                for c in coffees {
+                for (c, pending) in coffees {
                     let t = Utc.timestamp(c.utc_time, 0).with_timezone(&Local);
                     acc += c.shots;
-                    println!("{:16}{:10}: {}", "", t.time(), c.shots);
+                    let drink = match &c.drink {
+                        Some(d) => format!(" {}", d),
+                        None => String::new(),
+                    };
+                    let marker = if *pending { " (pending)" } else { "" };
+                    println!("{:16}{:10}: {}{}{}", "", t.time(), c.shots, drink, marker);
                 }
                 println!("{:26}{}", "Daily Total:", acc);
             }
         } else {
             println!("Nothing found :(");
         }
+    } else if let Some(cmd) = matches.subcommand_matches("stats") {
+        let api_key = get_api_key(profile, cmd)?;
+        let (mut start_utc_time, end_utc_time) = list_range(cmd)?;
+        if start_utc_time == 0 && end_utc_time == 0 {
+            let from = Local::today() - chrono::Duration::days(DEFAULT_STATS_DAYS - 1);
+            start_utc_time = from.and_hms(0, 0, 0).timestamp();
+        }
+        let period = cmd
+            .value_of("by")
+            .unwrap_or("day")
+            .parse()
+            .unwrap_or(Period::Day);
+
+        let coffees: Vec<_> =
+            fetch_coffees(&server, api_key, &profile_key, start_utc_time, end_utc_time)
+                .await?
+                .into_iter()
+                .map(|(c, _)| {
+                    let t = Utc.timestamp(c.utc_time, 0).with_timezone(&Local);
+                    (t.naive_local(), c.shots)
+                })
+                .collect();
+        print!("{}", stats::render(&coffees, period, &Style::detect()));
+    } else if let Some(cmd) = matches.subcommand_matches("tui") {
+        let api_key = get_api_key(profile, cmd)?;
+        let preset = cmd
+            .value_of("preset")
+            .or_else(|| profile.and_then(|p| p.default_drink.as_deref()))
+            .unwrap_or("1");
+        let mut conn = Connection::new(&server, api_key);
+        let preset = resolve_drink(&mut conn, &profile_key, preset).await?;
+        if preset.0 <= 0 {
+            return Err(ClientError::BadArgument(
+                "--preset needs to be a drink or a number of shots".into(),
+            ));
+        }
+
+        let client = conn.get().await?.clone();
+        dashboard::run(client, &profile_key, preset).await?;
+    } else if let Some(cmd) = matches.subcommand_matches("sync") {
+        let api_key = get_api_key(profile, cmd)?;
+        let mut journal = Journal::open(&journal_path(), &profile_key)?;
+        if journal.entries().is_empty() {
+            println!("Nothing to sync.");
+            return Ok(());
+        }
+
+        let client = connect(&server, api_key).await?;
+        let synced = flush_journal(&client, &mut journal).await?;
+        prompt::refresh_in_background(background_args(&matches, &config_path), api_key);
+        println!(
+            "Synced {} coffee(s), {} still pending.",
+            synced,
+            journal.entries().len()
+        );
+    } else if let Some(cmd) = matches.subcommand_matches("undo") {
+        let api_key = get_api_key(profile, cmd)?;
+
+        // Anything still in the journal is newer than what the server has
+        // from us, and taking it back doesn't need the server at all.
+        let mut journal = Journal::open(&journal_path(), &profile_key)?;
+        if let Some(p) = journal.entries().last().cloned() {
+            journal.remove(&p.id)?;
+            let coffee = pending_coffee(&p);
+            println!(
+                "Removed {} from the unsent coffees.",
+                describe_coffee(&coffee)
+            );
+            if let Some(profile) = profile {
+                let event = Event::Undo {
+                    change: "add_coffee",
+                    coffee: Some(EventCoffee::from(&coffee)),
+                    drink: None,
+                    pending: true,
+                };
+                hooks::run(&profile.hooks, &profile_key, &event).await;
+            }
+            return Ok(());
+        }
+
+        let client = connect(&server, api_key).await?;
+        // Nothing to undo, or it's changed since, comes back as the server's
+        // explanation.
+        let undone = client.undo_last().await?;
+        prompt::refresh_in_background(background_args(&matches, &config_path), api_key);
+        println!("{}", describe_undo(&undone.change));
+        let event = match undone.change {
+            Change::AddCoffee(c) => ("add_coffee", Some(EventCoffee::from(&c)), None),
+            Change::UpdateCoffee(c) => ("update_coffee", Some(EventCoffee::from(&c)), None),
+            Change::DeleteCoffee(c) => ("delete_coffee", Some(EventCoffee::from(&c)), None),
+            Change::SaveDrink(name) => ("save_drink", None, Some(name)),
+            Change::DeleteDrink(name) => ("delete_drink", None, Some(name)),
+        };
+        if let Some(profile) = profile {
+            let (change, coffee, drink) = event;
+            let event = Event::Undo {
+                change,
+                coffee,
+                drink,
+                pending: false,
+            };
+            hooks::run(&profile.hooks, &profile_key, &event).await;
+        }
+    } else if let Some(cmd) = matches.subcommand_matches("prompt") {
+        let cache_key = profile_key.as_str();
+        let refresh_secs = profile
+            .and_then(|p| p.prompt_refresh)
+            .unwrap_or(prompt::DEFAULT_REFRESH_SECS);
+        let mut cache = Cache::open(&prompt_cache_path());
+
+        if cmd.is_present("refresh") {
+            let api_key = match std::env::var(prompt::REFRESH_KEY_ENV) {
+                Ok(k) => k,
+                Err(_) => get_api_key(profile, cmd)?.into(),
+            };
+            let client = connect(&server, &api_key).await?;
+            prompt::refresh(&client, &mut cache, cache_key).await?;
+            return Ok(());
+        }
+
+        // Without a key there's nothing to refresh with, so the prompt just
+        // shows what's local.
+        let mut summary = cache.get(cache_key);
+        let now = Utc::now().timestamp();
+        if let Ok(api_key) = get_api_key(profile, cmd) {
+            if prompt::needs_refresh(&summary, refresh_secs, now) {
+                summary.refreshing = now;
+                if cache.set(cache_key, summary.clone()).is_ok() {
+                    prompt::refresh_in_background(background_args(&matches, &config_path), api_key);
+                }
+            }
+        }
+        let pending = Journal::open(&journal_path(), &profile_key)
+            .map(|j| j.entries().to_vec())
+            .unwrap_or_default();
+        let format = cmd
+            .value_of("format")
+            .or_else(|| profile.and_then(|p| p.prompt_format.as_deref()))
+            .unwrap_or(prompt::DEFAULT_FORMAT);
+        println!(
+            "{}",
+            prompt::render(format, &summary, &pending, refresh_secs, Local::now())
+        );
+    } else if matches.subcommand_matches("pending").is_some() {
+        let journal = Journal::open(&journal_path(), &profile_key)?;
+        if journal.entries().is_empty() {
+            println!("Nothing pending.");
+        }
+        for p in journal.entries() {
+            let t = Utc.timestamp(p.utc_time, 0).with_timezone(&Local);
+            println!("{}: {}", t.format("%Y-%m-%d %H:%M:%S"), p.shots);
+        }
+    } else if let Some(cmd) = matches.subcommand_matches("drinks") {
+        let api_key = get_api_key(profile, cmd)?;
+        drinks_command(&server, api_key, &profile_key, cmd).await?;
+    } else if let Some(cmd) = matches.subcommand_matches("profile") {
+        profile_command(&mut config, cmd)?;
+        config.save(&config_path)?;
+    } else if let Some(cmd) = matches.subcommand_matches("watch") {
+        let api_key = get_api_key(profile, cmd)?;
+        let client = connect(&server, api_key).await?;
+
+        let since_utc_time = match cmd.value_of("since") {
+            Some(s) => match DateTime::parse_from_rfc3339(s) {
+                Ok(t) => t.timestamp(),
+                Err(e) => {
+                    return Err(ClientError::BadArgument(format!(
+                        "Cannot parse --since as an RFC 3339 time: {}",
+                        e
+                    )));
+                }
+            },
+            None => 0,
+        };
+
+        let mut watch = client.watch(since_utc_time).await?;
+        while let Some(event) = watch.next().await? {
+            // Heartbeats just keep the stream alive.
+            if let WatchEvent::Coffee(c) = event {
+                let t = Utc.timestamp(c.utc_time, 0).with_timezone(&Local);
+                println!("{}: {}", t.format("%Y-%m-%d %H:%M:%S"), c.shots);
+            }
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_read_api_key() {
+        let env = format!("COFFEE_TEST_KEY_{}", std::process::id());
+        std::env::set_var(&env, "  abc\n");
+        assert_eq!(read_api_key(&env).unwrap(), "abc");
+        // A blank key isn't one.
+        std::env::set_var(&env, " ");
+        assert!(matches!(read_api_key(&env), Err(ClientError::NoApiKey)));
+        std::env::remove_var(&env);
+    }
+}
@@ -0,0 +1,216 @@
+// `coffee man`, the man page written out as roff from the same App that parses
+// the command line, so it says what the CLI actually accepts. clap 2 can't do
+// this itself, and only lets the App's args be read through its hidden `p`
+// field, which is why Cargo.toml pins clap exactly.
+
+use clap::{App, AppSettings, ArgSettings};
+use std::io::Write;
+
+// One option or positional, ready to go in a .TP paragraph.
+struct Entry {
+    term: String,
+    help: String,
+}
+
+/// Writes the man page for `app` to `out`.
+pub fn render(app: &App, out: &mut impl Write) -> std::io::Result<()> {
+    let meta = &app.p.meta;
+    let name = meta.bin_name.as_deref().unwrap_or("coffee");
+    writeln!(
+        out,
+        ".TH {} 1 \"\" \"{} {}\" \"User Commands\"",
+        name.to_uppercase(),
+        name,
+        meta.version.unwrap_or_default()
+    )?;
+    writeln!(out, ".SH NAME")?;
+    writeln!(
+        out,
+        "{} \\- {}",
+        escape(name),
+        escape(meta.about.unwrap_or_default())
+    )?;
+    writeln!(out, ".SH SYNOPSIS")?;
+    writeln!(out, "{}", synopsis(name, app))?;
+    if let Some(about) = meta.long_about {
+        writeln!(out, ".SH DESCRIPTION")?;
+        writeln!(out, "{}", escape(about))?;
+    }
+    writeln!(out, ".SH OPTIONS")?;
+    write_entries(out, &entries(app))?;
+    writeln!(out, ".SH COMMANDS")?;
+    write_commands(out, name, app)?;
+    Ok(())
+}
+
+// Each visible subcommand as its own .SS section, its own subcommands after it.
+fn write_commands(out: &mut impl Write, path: &str, app: &App) -> std::io::Result<()> {
+    for sub in visible_subcommands(app) {
+        let path = format!("{} {}", path, sub.p.meta.name);
+        writeln!(
+            out,
+            ".SS \"{}\"",
+            synopsis(&path, sub).replace('"', "\\(dq")
+        )?;
+        let about = sub.p.meta.long_about.or(sub.p.meta.about);
+        if let Some(about) = about {
+            writeln!(out, "{}", escape(about))?;
+        }
+        write_entries(out, &entries(sub))?;
+        write_commands(out, &path, sub)?;
+    }
+    Ok(())
+}
+
+fn write_entries(out: &mut impl Write, entries: &[Entry]) -> std::io::Result<()> {
+    for e in entries {
+        writeln!(out, ".TP")?;
+        writeln!(out, "{}", e.term)?;
+        writeln!(out, "{}", e.help)?;
+    }
+    Ok(())
+}
+
+fn visible_subcommands<'a, 'b>(app: &'a App<'b, 'b>) -> impl Iterator<Item = &'a App<'b, 'b>> {
+    app.p
+        .subcommands
+        .iter()
+        .filter(|s| !s.p.is_set(AppSettings::Hidden))
+}
+
+// e.g. coffee add [OPTIONS] [AMOUNT]
+fn synopsis(path: &str, app: &App) -> String {
+    let mut s = format!("\\fB{}\\fR", escape(path));
+    if !app.p.flags.is_empty() || !app.p.opts.is_empty() {
+        s.push_str(" [OPTIONS]");
+    }
+    for pos in app.p.positionals.values() {
+        if pos.b.is_set(ArgSettings::Hidden) {
+            continue;
+        }
+        if pos.b.is_set(ArgSettings::Required) {
+            s.push_str(&format!(" <{}>", escape(pos.b.name)));
+        } else {
+            s.push_str(&format!(" [{}]", escape(pos.b.name)));
+        }
+    }
+    if visible_subcommands(app).next().is_some() {
+        s.push_str(" <COMMAND>");
+    }
+    s
+}
+
+fn entries(app: &App) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    for flag in app.p.flags.iter() {
+        if flag.b.is_set(ArgSettings::Hidden) {
+            continue;
+        }
+        entries.push(Entry {
+            term: switches(flag.s.short, flag.s.long),
+            help: escape(flag.b.long_help.or(flag.b.help).unwrap_or_default()),
+        });
+    }
+    for opt in app.p.opts.iter() {
+        if opt.b.is_set(ArgSettings::Hidden) {
+            continue;
+        }
+        let value = opt
+            .v
+            .val_names
+            .as_ref()
+            .and_then(|names| names.values().next().copied())
+            .map(String::from)
+            .unwrap_or_else(|| opt.b.name.to_uppercase());
+        let mut help = escape(opt.b.long_help.or(opt.b.help).unwrap_or_default());
+        if let Some(values) = &opt.v.possible_vals {
+            help.push_str(&format!(
+                " [possible values: {}]",
+                escape(&values.join(", "))
+            ));
+        }
+        if let Some((env, _)) = &opt.v.env {
+            help.push_str(&format!(" [env: {}]", escape(&env.to_string_lossy())));
+        }
+        entries.push(Entry {
+            term: format!(
+                "{} \\fI{}\\fR",
+                switches(opt.s.short, opt.s.long),
+                escape(&value)
+            ),
+            help,
+        });
+    }
+    for pos in app.p.positionals.values() {
+        if pos.b.is_set(ArgSettings::Hidden) {
+            continue;
+        }
+        let mut help = escape(pos.b.long_help.or(pos.b.help).unwrap_or_default());
+        if let Some(values) = &pos.v.possible_vals {
+            help.push_str(&format!(
+                " [possible values: {}]",
+                escape(&values.join(", "))
+            ));
+        }
+        entries.push(Entry {
+            term: format!("\\fI{}\\fR", escape(pos.b.name)),
+            help,
+        });
+    }
+    entries
+}
+
+// e.g. \fB\-s\fR, \fB\-\-server\fR
+fn switches(short: Option<char>, long: Option<&str>) -> String {
+    let short = short.map(|c| format!("\\fB\\-{}\\fR", escape(&c.to_string())));
+    let long = long.map(|l| format!("\\fB\\-\\-{}\\fR", escape(l)));
+    short.into_iter().chain(long).collect::<Vec<_>>().join(", ")
+}
+
+// Text as roff shows it rather than reads it: backslashes and hyphens are
+// escaped, and a line starting with a dot or quote isn't taken as a request.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\e")
+        .replace('-', "\\-")
+        .lines()
+        .map(|line| {
+            if line.starts_with('.') || line.starts_with('\'') {
+                format!("\\&{}", line)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_render() {
+        let mut page = Vec::new();
+        render(&crate::cli(), &mut page).unwrap();
+        let page = String::from_utf8(page).unwrap();
+
+        assert!(page.starts_with(".TH "));
+        assert!(page.contains("\\fB\\-s\\fR, \\fB\\-\\-server\\fR \\fISERVER\\fR"));
+        assert!(page.contains(".SS \"\\fBcoffee add\\fR [OPTIONS] [AMOUNT]\""));
+        assert!(page.contains(".SS \"\\fBcoffee profile use\\fR <NAME>\""));
+        assert!(page.contains("[env: COFFEE_PROFILE]"));
+        // Every command a person can run is there, and no others.
+        for sub in visible_subcommands(&crate::cli()) {
+            let name = escape(&sub.p.meta.name);
+            assert!(page.contains(&format!(" {}\\fR", name)), "{}", name);
+        }
+        assert!(!page.contains(" complete\\fR"));
+    }
+
+    #[test]
+    pub fn test_escape() {
+        assert_eq!(escape("--at 8am"), "\\-\\-at 8am");
+        assert_eq!(escape("a\\b"), "a\\eb");
+        assert_eq!(escape("one\n.two"), "one\n\\&.two");
+    }
+}
@@ -0,0 +1,253 @@
+// `coffee prompt`, today's coffee for a shell prompt. Prompts are drawn after
+// every command, far too often to ask the server each time, so this only reads
+// a summary kept in a local cache. When the summary gets old a `coffee prompt
+// --refresh` is started in the background to bring it up to date for next
+// time, and adds start one straight away.
+
+use crate::error::ClientError;
+use crate::journal::PendingCoffee;
+
+use chrono::prelude::*;
+use coffee_sdk::{Client, Coffee};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+pub const DEFAULT_FORMAT: &str = "☕{shots}";
+// How old the summary can get before a prompt starts a refresh, in seconds.
+pub const DEFAULT_REFRESH_SECS: u64 = 5 * 60;
+// A refresh that hasn't finished after this long has most likely died, so
+// another can be started.
+const REFRESH_GRACE_SECS: i64 = 30;
+// Where a background refresh finds the key it was started with, which might
+// not be the profile's. It's kept out of the arguments so other users can't
+// see it in `ps`.
+pub const REFRESH_KEY_ENV: &str = "COFFEE_REFRESH_API_KEY";
+
+/// What the server had for a day when it was last asked.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Summary {
+    // When the server was last asked, seconds from unix epoch. 0 if never.
+    pub updated: i64,
+    // When the last refresh started, so every prompt doesn't start another.
+    pub refreshing: i64,
+    // The local day the counts are for, as YYYY-MM-DD.
+    pub day: String,
+    pub shots: i32,
+    pub coffees: usize,
+    // The newest coffee the server knew of, whatever day it was on.
+    pub last_coffee: Option<i64>,
+}
+
+/// The summaries for each profile, as a file that's fine not to exist yet.
+#[derive(Debug)]
+pub struct Cache {
+    path: PathBuf,
+    summaries: BTreeMap<String, Summary>,
+}
+
+impl Cache {
+    // A prompt shouldn't fail over a cache it can't read, which can just be
+    // filled in again.
+    pub fn open(path: &Path) -> Self {
+        let summaries = File::open(path)
+            .ok()
+            .and_then(|f| serde_json::from_reader(BufReader::new(f)).ok())
+            .unwrap_or_default();
+        Cache {
+            path: path.into(),
+            summaries,
+        }
+    }
+
+    pub fn get(&self, profile: &str) -> Summary {
+        self.summaries.get(profile).cloned().unwrap_or_default()
+    }
+
+    pub fn set(&mut self, profile: &str, summary: Summary) -> std::io::Result<()> {
+        self.summaries.insert(profile.into(), summary);
+        self.save()
+    }
+
+    // Written to a temporary file first, so a prompt never reads half a cache.
+    fn save(&self) -> std::io::Result<()> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(format!(".{}.tmp", std::process::id()));
+        let tmp = PathBuf::from(tmp);
+
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut writer, &self.summaries)?;
+        writer.flush()?;
+        drop(writer);
+
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+/// Whether it's time to ask the server again, and nobody else is already.
+pub fn needs_refresh(summary: &Summary, refresh_secs: u64, now: i64) -> bool {
+    now - summary.updated >= refresh_secs as i64 && now - summary.refreshing >= REFRESH_GRACE_SECS
+}
+
+/// Starts `coffee prompt --refresh` in the background, passing on `args` so
+/// it uses the same config, profile and server, and `api_key`. It's left to
+/// finish on its own, and not hearing from it is fine.
+pub fn refresh_in_background(args: Vec<OsString>, api_key: &str) {
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(_) => return,
+    };
+    let _ = Command::new(exe)
+        .args(args)
+        .args(["prompt", "--refresh"])
+        .env(REFRESH_KEY_ENV, api_key)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+}
+
+/// Asks the server for today's coffees and saves the summary.
+pub async fn refresh(client: &Client, cache: &mut Cache, profile: &str) -> Result<(), ClientError> {
+    let mut summary = cache.get(profile);
+    summary.refreshing = Utc::now().timestamp();
+    cache.set(profile, summary.clone())?;
+
+    let coffees = client
+        .list_coffees(Local::today().and_hms(0, 0, 0).timestamp(), 0)
+        .await?;
+    summary.updated = Utc::now().timestamp();
+    summary.refreshing = 0;
+    summarise(&mut summary, &coffees, Local::now());
+    cache.set(profile, summary)?;
+    Ok(())
+}
+
+/// Fills in `summary` from `coffees`, the server's coffees for the day `now`
+/// falls on.
+pub fn summarise(summary: &mut Summary, coffees: &[Coffee], now: DateTime<Local>) {
+    summary.day = now.format("%Y-%m-%d").to_string();
+    summary.shots = coffees.iter().map(|c| c.shots).sum();
+    summary.coffees = coffees.len();
+    // With nothing today, the last one we heard of is still the newest.
+    if let Some(last) = coffees.iter().map(|c| c.utc_time).max() {
+        summary.last_coffee = Some(last);
+    }
+}
+
+/// The shots and coffees for the day `now` falls on, from the server's
+/// summary and the coffees still waiting to be sent.
+pub fn today(summary: &Summary, pending: &[PendingCoffee], now: DateTime<Local>) -> (i32, usize) {
+    let (mut shots, mut coffees) = if summary.day == now.format("%Y-%m-%d").to_string() {
+        (summary.shots, summary.coffees)
+    } else {
+        (0, 0)
+    };
+    let start_of_day = now.date().and_hms(0, 0, 0).timestamp();
+    for p in pending.iter().filter(|p| p.utc_time >= start_of_day) {
+        shots += p.shots;
+        coffees += 1;
+    }
+    (shots, coffees)
+}
+
+/// Fills in `format` for the day `now` falls on, from the server's summary and
+/// coffees still waiting to be sent. The placeholders are:
+///
+///   {shots}    shots today
+///   {coffees}  coffees today
+///   {last}     how long since the last coffee, e.g. 45m
+///   {pending}  coffees not yet sent to the server
+///   {stale}    ? if the summary is out of date, otherwise nothing
+pub fn render(
+    format: &str,
+    summary: &Summary,
+    pending: &[PendingCoffee],
+    refresh_secs: u64,
+    now: DateTime<Local>,
+) -> String {
+    let (shots, coffees) = today(summary, pending, now);
+    let last = pending
+        .iter()
+        .map(|p| p.utc_time)
+        .chain(summary.last_coffee)
+        .max()
+        .map(|t| since(now.timestamp() - t))
+        .unwrap_or_default();
+    // Twice the interval gives a refresh time to come back.
+    let stale = if now.timestamp() - summary.updated > 2 * refresh_secs as i64 {
+        "?"
+    } else {
+        ""
+    };
+
+    format
+        .replace("{shots}", &shots.to_string())
+        .replace("{coffees}", &coffees.to_string())
+        .replace("{last}", &last)
+        .replace("{pending}", &pending.len().to_string())
+        .replace("{stale}", stale)
+}
+
+// A short, rough duration, e.g. 5m, 3h or 2d.
+fn since(secs: i64) -> String {
+    let secs = secs.max(0);
+    match secs {
+        s if s < 60 * 60 => format!("{}m", s / 60),
+        s if s < 24 * 60 * 60 => format!("{}h", s / (60 * 60)),
+        s => format!("{}d", s / (24 * 60 * 60)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_render() {
+        let now = Local.ymd(2020, 6, 9).and_hms(12, 0, 0);
+        let summary = Summary {
+            updated: now.timestamp() - 60,
+            refreshing: 0,
+            day: "2020-06-09".into(),
+            shots: 3,
+            coffees: 2,
+            last_coffee: Some(now.timestamp() - 90 * 60),
+        };
+        let format = "{shots}/{coffees} {last}{stale} +{pending}";
+        assert_eq!(render(format, &summary, &[], 300, now), "3/2 1h +0");
+
+        // Coffees waiting to be sent count too.
+        let pending = [PendingCoffee::new(now.timestamp() - 5 * 60, 2, None)];
+        assert_eq!(render(format, &summary, &pending, 300, now), "5/3 5m +1");
+
+        // Yesterday's counts don't carry over, and an old summary is marked.
+        let tomorrow = now + chrono::Duration::days(1);
+        assert_eq!(render(format, &summary, &[], 300, tomorrow), "0/0 1d? +0");
+        assert_eq!(
+            render(DEFAULT_FORMAT, &Summary::default(), &[], 300, now),
+            "☕0"
+        );
+    }
+
+    #[test]
+    pub fn test_needs_refresh() {
+        let summary = Summary {
+            updated: 1000,
+            ..Summary::default()
+        };
+        assert!(!needs_refresh(&summary, 300, 1200));
+        assert!(needs_refresh(&summary, 300, 1300));
+        let refreshing = Summary {
+            refreshing: 1290,
+            ..summary
+        };
+        assert!(!needs_refresh(&refreshing, 300, 1300));
+        assert!(needs_refresh(&refreshing, 300, 1320));
+    }
+}
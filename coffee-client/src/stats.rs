@@ -0,0 +1,207 @@
+// Works out the numbers behind `coffee stats` and draws them. Times are the
+// user's local ones, so days and hours line up with what they'd expect.
+
+use crate::chart::{self, Style};
+
+use chrono::prelude::*;
+use chrono::Duration;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Period {
+    Day,
+    Week,
+}
+
+impl Period {
+    pub const ALL: &'static [&'static str] = &["day", "week"];
+
+    // The first day of the period `date` falls in, weeks starting on Monday.
+    fn start(self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Period::Day => date,
+            Period::Week => date - Duration::days(date.weekday().num_days_from_monday().into()),
+        }
+    }
+
+    fn length(self) -> Duration {
+        match self {
+            Period::Day => Duration::days(1),
+            Period::Week => Duration::weeks(1),
+        }
+    }
+
+    fn label(self, start: NaiveDate) -> String {
+        match self {
+            Period::Day => start.format("%a %Y-%m-%d").to_string(),
+            Period::Week => start.format("w/c %Y-%m-%d").to_string(),
+        }
+    }
+}
+
+impl FromStr for Period {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "day" => Ok(Period::Day),
+            "week" => Ok(Period::Week),
+            _ => Err(format!("Unknown period: {}", s)),
+        }
+    }
+}
+
+// Shots in each period from the first coffee to the last, including the
+// empty ones in between.
+fn per_period(coffees: &[(NaiveDateTime, i32)], period: Period) -> Vec<(NaiveDate, i64)> {
+    let mut totals = BTreeMap::new();
+    for (t, shots) in coffees {
+        *totals.entry(period.start(t.date())).or_insert(0) += i64::from(*shots);
+    }
+
+    let (first, last) = match (totals.keys().next(), totals.keys().next_back()) {
+        (Some(f), Some(l)) => (*f, *l),
+        _ => return Vec::new(),
+    };
+    let mut all = Vec::new();
+    let mut d = first;
+    while d <= last {
+        all.push((d, totals.get(&d).copied().unwrap_or(0)));
+        d += period.length();
+    }
+    all
+}
+
+fn by_hour(coffees: &[(NaiveDateTime, i32)]) -> [i64; 24] {
+    let mut hours = [0; 24];
+    for (t, shots) in coffees {
+        hours[t.hour() as usize] += i64::from(*shots);
+    }
+    hours
+}
+
+/// Renders the whole report for `coffees`, given as local times and shots.
+pub fn render(coffees: &[(NaiveDateTime, i32)], period: Period, style: &Style) -> String {
+    if coffees.is_empty() {
+        return "Nothing found :(\n".into();
+    }
+
+    let days = per_period(coffees, Period::Day);
+    let total_shots: i64 = days.iter().map(|(_, s)| s).sum();
+    let (busiest, busiest_shots) = days.iter().max_by_key(|(_, s)| *s).unwrap();
+    let hours = by_hour(coffees);
+    let favourite_hour = (0..24).max_by_key(|h| hours[*h]).unwrap();
+
+    let mut out = String::new();
+    out.push_str(&chart::heading("Summary", style));
+    out.push('\n');
+    let figures = vec![
+        ("Coffees", coffees.len().to_string()),
+        ("Shots", total_shots.to_string()),
+        (
+            "Days",
+            format!(
+                "{} ({} to {})",
+                days.len(),
+                days[0].0,
+                days[days.len() - 1].0
+            ),
+        ),
+        (
+            "Shots per day",
+            format!("{:.1}", total_shots as f64 / days.len() as f64),
+        ),
+        (
+            "Busiest day",
+            format!("{} ({} shots)", busiest, busiest_shots),
+        ),
+        ("Favourite hour", format!("{:02}:00", favourite_hour)),
+    ];
+    for (name, value) in figures {
+        out.push_str(&format!("{:>15}: {}\n", name, value));
+    }
+    out.push_str(&format!(
+        "{:>15}: {}\n\n",
+        "Trend",
+        chart::sparkline(&days.iter().map(|(_, s)| *s).collect::<Vec<_>>(), style)
+    ));
+
+    let title = match period {
+        Period::Day => "Shots per day",
+        Period::Week => "Shots per week",
+    };
+    out.push_str(&chart::heading(title, style));
+    out.push('\n');
+    let rows: Vec<_> = per_period(coffees, period)
+        .into_iter()
+        .map(|(d, s)| (period.label(d), s))
+        .collect();
+    out.push_str(&chart::bar_chart(&rows, style));
+    out.push('\n');
+
+    out.push_str(&chart::heading("Shots by hour of day", style));
+    out.push('\n');
+    let rows: Vec<_> = hours
+        .iter()
+        .enumerate()
+        .map(|(h, s)| (format!("{:02}:00", h), *s))
+        .collect();
+    out.push_str(&chart::bar_chart(&rows, style));
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn coffees() -> Vec<(NaiveDateTime, i32)> {
+        // Tuesday, Wednesday, then the following Monday.
+        vec![
+            (NaiveDate::from_ymd(2020, 6, 9).and_hms(8, 0, 0), 2),
+            (NaiveDate::from_ymd(2020, 6, 10).and_hms(8, 30, 0), 1),
+            (NaiveDate::from_ymd(2020, 6, 10).and_hms(15, 0, 0), 1),
+            (NaiveDate::from_ymd(2020, 6, 15).and_hms(9, 0, 0), 3),
+        ]
+    }
+
+    #[test]
+    pub fn test_per_period() {
+        let days = per_period(&coffees(), Period::Day);
+        // Sorted, with the quiet days filled in.
+        assert_eq!(days.len(), 7);
+        assert_eq!(days[0], (NaiveDate::from_ymd(2020, 6, 9), 2));
+        assert_eq!(days[1], (NaiveDate::from_ymd(2020, 6, 10), 2));
+        assert_eq!(days[2], (NaiveDate::from_ymd(2020, 6, 11), 0));
+
+        let weeks = per_period(&coffees(), Period::Week);
+        assert_eq!(
+            weeks,
+            vec![
+                (NaiveDate::from_ymd(2020, 6, 8), 4),
+                (NaiveDate::from_ymd(2020, 6, 15), 3)
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_by_hour() {
+        let hours = by_hour(&coffees());
+        assert_eq!(hours[8], 3);
+        assert_eq!(hours[9], 3);
+        assert_eq!(hours[15], 1);
+        assert_eq!(hours.iter().sum::<i64>(), 7);
+    }
+
+    #[test]
+    pub fn test_render_plain() {
+        let out = render(&coffees(), Period::Week, &Style::plain());
+        assert!(out.is_ascii());
+        assert!(out.contains("Busiest day: 2020-06-15 (3 shots)"));
+        assert!(out.contains("w/c 2020-06-08"));
+        assert_eq!(
+            render(&[], Period::Day, &Style::plain()),
+            "Nothing found :(\n"
+        );
+    }
+}
@@ -0,0 +1,284 @@
+// Parses the times people actually type, e.g. "08:30", "yesterday 3pm",
+// "2h ago" or "last monday", as well as full timestamps. Anything without an
+// explicit offset is taken to be in the user's timezone.
+
+use chrono::prelude::*;
+use chrono::Duration;
+
+static DATE_TIME_FORMATS: &[&str] = &[
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%d %H:%M",
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y-%m-%dT%H:%M",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum When<Tz: TimeZone> {
+    // A whole day, e.g. "yesterday".
+    Day(Date<Tz>),
+    // A point in time, e.g. "yesterday 3pm".
+    At(DateTime<Tz>),
+}
+
+impl<Tz: TimeZone> When<Tz> {
+    // The first second covered, in seconds from unix epoch.
+    pub fn start(&self) -> i64 {
+        match self {
+            When::Day(d) => start_of_day(d).timestamp(),
+            When::At(t) => t.timestamp(),
+        }
+    }
+
+    // The first second after the end, so a day runs up to the next midnight.
+    pub fn end(&self) -> i64 {
+        match self {
+            // parse won't give a day without one after it.
+            When::Day(d) => d
+                .succ_opt()
+                .map_or(i64::MAX, |next| start_of_day(&next).timestamp()),
+            When::At(t) => t.timestamp(),
+        }
+    }
+}
+
+/// Parses `input` relative to `now`, in `now`'s timezone.
+pub fn parse<Tz: TimeZone>(input: &str, now: &DateTime<Tz>) -> Result<When<Tz>, String> {
+    let tz = now.timezone();
+    let s = input.trim().to_lowercase();
+
+    if s == "now" {
+        return Ok(When::At(now.clone()));
+    }
+    if let Ok(t) = DateTime::parse_from_rfc3339(input.trim()) {
+        return Ok(When::At(t.with_timezone(&tz)));
+    }
+    for f in DATE_TIME_FORMATS {
+        if let Ok(naive) = NaiveDateTime::parse_from_str(&s, f) {
+            return local(&tz, naive).map(When::At);
+        }
+    }
+    if let Some(ago) = s.strip_suffix(" ago") {
+        return now
+            .clone()
+            .checked_sub_signed(parse_duration(ago)?)
+            .map(When::At)
+            .ok_or_else(|| format!("Too long ago: {}", input));
+    }
+
+    let words: Vec<&str> = s.split_whitespace().collect();
+    let (date, rest) = match parse_day(&words, &now.date()) {
+        Some((date, used)) => (date, &words[used..]),
+        // Just a time means today.
+        None => (now.date(), &words[..]),
+    };
+    let rest = match rest.split_first() {
+        Some((&"at", rest)) => rest,
+        _ => rest,
+    };
+    if rest.is_empty() {
+        return if words.is_empty() {
+            Err("No time given".into())
+        } else if date.succ_opt().is_none() {
+            Err(format!("Too far in the future: {}", input))
+        } else {
+            Ok(When::Day(date))
+        };
+    }
+
+    let time = parse_clock(&rest.concat()).ok_or_else(|| format!("Unknown time: {}", input))?;
+    local(&tz, date.naive_local().and_time(time)).map(When::At)
+}
+
+// Recognises a day at the start of `words`, returning it and how many words it
+// took up.
+fn parse_day<Tz: TimeZone>(words: &[&str], today: &Date<Tz>) -> Option<(Date<Tz>, usize)> {
+    let first = *words.first()?;
+    match first {
+        "today" => return Some((today.clone(), 1)),
+        "yesterday" => return Some((today.pred(), 1)),
+        _ => {}
+    }
+    if let Ok(d) = NaiveDate::parse_from_str(first, "%Y-%m-%d") {
+        return today
+            .timezone()
+            .from_local_date(&d)
+            .earliest()
+            .map(|d| (d, 1));
+    }
+
+    // "monday" is the most recent one, which could be today, while "last
+    // monday" is always before today.
+    let (name, used, last) = match words {
+        ["last", name, ..] => (*name, 2, true),
+        [name, ..] => (*name, 1, false),
+        _ => return None,
+    };
+    let weekday: Weekday = name.parse().ok()?;
+    let mut back =
+        (7 + today.weekday().num_days_from_monday() - weekday.num_days_from_monday()) % 7;
+    if last && back == 0 {
+        back = 7;
+    }
+    Some((today.clone() - Duration::days(back.into()), used))
+}
+
+// A clock time, either 24 hour ("08:30", "15:00") or 12 hour ("3pm", "3:15am").
+fn parse_clock(s: &str) -> Option<NaiveTime> {
+    match s {
+        "noon" => return Some(NaiveTime::from_hms(12, 0, 0)),
+        "midnight" => return Some(NaiveTime::from_hms(0, 0, 0)),
+        _ => {}
+    }
+
+    let (clock, pm) = if let Some(c) = s.strip_suffix("am") {
+        (c, Some(false))
+    } else if let Some(c) = s.strip_suffix("pm") {
+        (c, Some(true))
+    } else {
+        (s, None)
+    };
+
+    let mut parts = clock.splitn(2, ':');
+    let hour: u32 = parts.next()?.parse().ok()?;
+    let minute: u32 = match parts.next() {
+        Some(m) if m.len() == 2 => m.parse().ok()?,
+        Some(_) => return None,
+        // A bare number is only a time with am/pm, otherwise "8" is too vague.
+        None if pm.is_some() => 0,
+        None => return None,
+    };
+
+    let hour = match pm {
+        Some(_) if hour == 0 || hour > 12 => return None,
+        Some(true) => hour % 12 + 12,
+        Some(false) => hour % 12,
+        None => hour,
+    };
+    NaiveTime::from_hms_opt(hour, minute, 0)
+}
+
+// "90m", "2h", "3 days" and the like.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let amount: i64 = s[..split]
+        .parse()
+        .map_err(|_| format!("Unknown amount of time: {}", s))?;
+    let unit = match s[split..].trim() {
+        "s" | "sec" | "secs" | "second" | "seconds" => Duration::seconds(1),
+        "m" | "min" | "mins" | "minute" | "minutes" => Duration::minutes(1),
+        "h" | "hr" | "hrs" | "hour" | "hours" => Duration::hours(1),
+        "d" | "day" | "days" => Duration::days(1),
+        "w" | "week" | "weeks" => Duration::weeks(1),
+        u => return Err(format!("Unknown unit of time: {}", u)),
+    };
+    unit.num_milliseconds()
+        .checked_mul(amount)
+        .map(Duration::milliseconds)
+        .ok_or_else(|| format!("Too much time: {}", s))
+}
+
+fn local<Tz: TimeZone>(tz: &Tz, naive: NaiveDateTime) -> Result<DateTime<Tz>, String> {
+    // Ambiguous times (when the clocks go back) take the first.
+    tz.from_local_datetime(&naive)
+        .earliest()
+        .ok_or_else(|| format!("{} doesn't exist in this timezone", naive))
+}
+
+fn start_of_day<Tz: TimeZone>(d: &Date<Tz>) -> DateTime<Tz> {
+    // Where the clocks go forward at midnight the day starts an hour later.
+    d.and_hms_opt(0, 0, 0)
+        .or_else(|| d.and_hms_opt(1, 0, 0))
+        .expect("A day with no start")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Wednesday 2020-06-10 10:00 at UTC+10.
+    fn now() -> DateTime<FixedOffset> {
+        FixedOffset::east(10 * 3600)
+            .ymd(2020, 6, 10)
+            .and_hms(10, 0, 0)
+    }
+
+    fn at(s: &str) -> DateTime<FixedOffset> {
+        match parse(s, &now()).unwrap() {
+            When::At(t) => t,
+            w => panic!("{} parsed as {:?}", s, w),
+        }
+    }
+
+    fn day(s: &str) -> Date<FixedOffset> {
+        match parse(s, &now()).unwrap() {
+            When::Day(d) => d,
+            w => panic!("{} parsed as {:?}", s, w),
+        }
+    }
+
+    #[test]
+    pub fn test_last_day() {
+        // There's no day after it for its end.
+        let last = chrono::naive::MAX_DATE.format("%Y-%m-%d").to_string();
+        assert!(parse(&last, &now()).is_err());
+        let before = chrono::naive::MAX_DATE
+            .pred()
+            .format("%Y-%m-%d")
+            .to_string();
+        assert!(parse(&before, &now()).is_ok());
+    }
+
+    #[test]
+    pub fn test_absolute() {
+        let tz = now().timezone();
+        assert_eq!(at("now"), now());
+        assert_eq!(
+            at("2020-06-01T08:00:00Z"),
+            tz.ymd(2020, 6, 1).and_hms(18, 0, 0)
+        );
+        assert_eq!(at("2020-06-01 08:30"), tz.ymd(2020, 6, 1).and_hms(8, 30, 0));
+        assert_eq!(day("2020-06-01"), tz.ymd(2020, 6, 1));
+    }
+
+    #[test]
+    pub fn test_relative() {
+        let tz = now().timezone();
+        assert_eq!(at("08:30"), tz.ymd(2020, 6, 10).and_hms(8, 30, 0));
+        assert_eq!(at("yesterday 3pm"), tz.ymd(2020, 6, 9).and_hms(15, 0, 0));
+        assert_eq!(at("today at 12am"), tz.ymd(2020, 6, 10).and_hms(0, 0, 0));
+        assert_eq!(at("2h ago"), now() - Duration::hours(2));
+        assert_eq!(at("90 minutes ago"), now() - Duration::minutes(90));
+        assert_eq!(day("yesterday"), tz.ymd(2020, 6, 9));
+        assert_eq!(day("last monday"), tz.ymd(2020, 6, 8));
+        assert_eq!(day("wednesday"), tz.ymd(2020, 6, 10));
+        assert_eq!(day("last wednesday"), tz.ymd(2020, 6, 3));
+        assert_eq!(
+            at("last friday 7:45am"),
+            tz.ymd(2020, 6, 5).and_hms(7, 45, 0)
+        );
+    }
+
+    #[test]
+    pub fn test_day_bounds() {
+        let w = parse("yesterday", &now()).unwrap();
+        assert_eq!(w.end() - w.start(), 24 * 3600);
+        assert_eq!(w.end(), parse("today", &now()).unwrap().start());
+    }
+
+    #[test]
+    pub fn test_rejects_nonsense() {
+        for s in &["", "soon", "13pm", "8", "25:00", "2 fortnights ago", "last"] {
+            assert!(parse(s, &now()).is_err(), "{} should not parse", s);
+        }
+    }
+
+    #[test]
+    pub fn test_rejects_overflow() {
+        // Too big for an i32, which used to wrap around to 1 day.
+        assert!(parse("4294967297 days ago", &now()).is_err());
+        // Fits a duration, but not a date that far back.
+        assert!(parse("2000000000 weeks ago", &now()).is_err());
+        assert!(parse("99999999999999999999 seconds ago", &now()).is_err());
+    }
+}
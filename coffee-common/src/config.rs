@@ -0,0 +1,299 @@
+// Layered configuration shared by the servers. Each layer overrides the one
+// before it: built-in defaults, then a TOML file, then COFFEE_* environment
+// variables, and finally whatever the CLI flags set.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use toml::value::{Table, Value};
+
+static ENV_PREFIX: &str = "COFFEE_";
+// Separates nested keys in environment variables, e.g. COFFEE_TLS__CERT.
+static ENV_SEPARATOR: &str = "__";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    Print(toml::ser::Error),
+    Env(String),
+}
+
+impl std::error::Error for ConfigError {}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(e: std::io::Error) -> Self {
+        ConfigError::Io(e)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(e: toml::de::Error) -> Self {
+        ConfigError::Parse(e)
+    }
+}
+
+impl From<toml::ser::Error> for ConfigError {
+    fn from(e: toml::ser::Error) -> Self {
+        ConfigError::Print(e)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: Option<String>,
+    pub key: Option<String>,
+}
+
+impl TlsConfig {
+    // TLS is only turned on once both halves of the identity are configured.
+    pub fn enabled(&self) -> bool {
+        self.cert.is_some() && self.key.is_some()
+    }
+}
+
+// Sessions for the web server's pages.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    // A file of at least 32 random bytes that session cookies are encrypted
+    // and signed with. Without one a key is made up at startup, and everyone
+    // is signed out when the server restarts.
+    pub key_file: Option<String>,
+    // Seconds a session lasts.
+    pub max_age: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            key_file: None,
+            max_age: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Features {
+    // Whether new users can register through the server.
+    pub registration: bool,
+    pub rate_limiting: bool,
+    // Whether to serve Prometheus metrics.
+    pub metrics: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Features {
+            registration: true,
+            rate_limiting: true,
+            metrics: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub addr: String,
+    pub db: String,
+    // Seconds to let in-flight requests finish once asked to shut down.
+    pub shutdown_timeout: u64,
+    // Side port the RPC server serves /metrics on, kept apart from the public
+    // address.
+    pub metrics_addr: String,
+    // Bearer token the web server wants before serving /metrics next to its
+    // pages. Without one it doesn't serve them.
+    pub metrics_token: Option<String>,
+    // Seconds between heartbeats sent to coffee watchers.
+    pub watch_heartbeat: u64,
+    // Seconds after a change that `coffee undo` can still take it back.
+    pub undo_window: u64,
+    // "pretty" or "json".
+    pub log_format: String,
+    // An env-filter directive, e.g. "info" or "coffee_common=debug,info".
+    pub log_level: String,
+    pub tls: TlsConfig,
+    pub session: SessionConfig,
+    // Per-method rate limits as BURST/PER_SECOND, keyed by RPC method name,
+    // or UpdateCoffee and DeleteCoffee for the REST routes without one.
+    pub limits: BTreeMap<String, String>,
+    pub features: Features,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            addr: "[::1]:50051".into(),
+            db: "coffee_db".into(),
+            shutdown_timeout: 30,
+            metrics_addr: "[::1]:9090".into(),
+            metrics_token: None,
+            watch_heartbeat: 15,
+            undo_window: 600,
+            log_format: "pretty".into(),
+            log_level: "info".into(),
+            tls: TlsConfig::default(),
+            session: SessionConfig::default(),
+            limits: BTreeMap::new(),
+            features: Features::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Layers the optional TOML file and then the environment over
+    /// `defaults`. CLI flags are left for the caller to apply on top.
+    pub fn load(defaults: ServerConfig, file: Option<&Path>) -> Result<Self, ConfigError> {
+        let mut value = Value::try_from(defaults)?;
+        if let Some(f) = file {
+            let file_value = std::fs::read_to_string(f)?.parse::<Value>()?;
+            merge(&mut value, file_value);
+        }
+        apply_env(&mut value, std::env::vars())?;
+        Ok(value.try_into()?)
+    }
+
+    /// The effective config, as TOML that `load` would read back.
+    pub fn to_toml(&self) -> Result<String, ConfigError> {
+        // Going through a Value makes sure tables are written after plain keys.
+        Ok(toml::to_string_pretty(&Value::try_from(self)?)?)
+    }
+}
+
+// Recursively merges `over` into `base`, with `over` winning.
+fn merge(base: &mut Value, over: Value) {
+    match (base, over) {
+        (Value::Table(base), Value::Table(over)) => {
+            for (k, v) in over {
+                match base.get_mut(&k) {
+                    Some(b) => merge(b, v),
+                    None => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (base, over) => *base = over,
+    }
+}
+
+fn table_at<'a>(table: &'a mut Table, path: &[String]) -> Option<&'a mut Table> {
+    match path.split_first() {
+        None => Some(table),
+        Some((first, rest)) => table_at(table.get_mut(first)?.as_table_mut()?, rest),
+    }
+}
+
+// Applies COFFEE_* variables, e.g. COFFEE_DB or COFFEE_FEATURES__REGISTRATION.
+// Values are parsed to match the type already at that key, and are taken as
+// strings for keys that don't exist yet.
+fn apply_env(
+    value: &mut Value,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<(), ConfigError> {
+    for (name, raw) in vars {
+        if !name.starts_with(ENV_PREFIX) {
+            continue;
+        }
+        let path: Vec<String> = name[ENV_PREFIX.len()..]
+            .split(ENV_SEPARATOR)
+            .map(|p| p.to_lowercase())
+            .collect();
+        let (last, tables) = path.split_last().unwrap();
+
+        // Ignore anything that doesn't name a section we know about, other
+        // tools may well use the same prefix.
+        let table = match value.as_table_mut().and_then(|t| table_at(t, tables)) {
+            Some(t) if !tables.is_empty() || t.contains_key(last) => t,
+            _ => continue,
+        };
+
+        let parsed = match table.get(last) {
+            Some(Value::Boolean(_)) => raw.parse().map(Value::Boolean).ok(),
+            Some(Value::Integer(_)) => raw.parse().map(Value::Integer).ok(),
+            Some(Value::Float(_)) => raw.parse().map(Value::Float).ok(),
+            _ => Some(Value::String(raw.clone())),
+        };
+        match parsed {
+            Some(v) => {
+                table.insert(last.clone(), v);
+            }
+            None => {
+                return Err(ConfigError::Env(format!(
+                    "Invalid value for {}: {}",
+                    name, raw
+                )))
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_layering() {
+        let mut value = Value::try_from(ServerConfig::default()).unwrap();
+        let file = r#"
+            db = "/var/lib/coffee"
+
+            [features]
+            registration = false
+
+            [limits]
+            AddCoffee = "5/1"
+        "#;
+        merge(&mut value, file.parse().unwrap());
+
+        let env = vec![
+            ("COFFEE_DB".to_string(), "/tmp/coffee".to_string()),
+            ("COFFEE_TLS__CERT".to_string(), "cert.pem".to_string()),
+            (
+                "COFFEE_SESSION__KEY_FILE".to_string(),
+                "session.key".to_string(),
+            ),
+            (
+                "COFFEE_FEATURES__RATE_LIMITING".to_string(),
+                "false".to_string(),
+            ),
+            ("COFFEE_UNRELATED".to_string(), "whatever".to_string()),
+            ("PATH".to_string(), "/bin".to_string()),
+        ];
+        apply_env(&mut value, env.into_iter()).unwrap();
+
+        let cfg: ServerConfig = value.try_into().unwrap();
+        assert_eq!(cfg.addr, ServerConfig::default().addr);
+        assert_eq!(cfg.db, "/tmp/coffee");
+        assert_eq!(cfg.tls.cert.as_deref(), Some("cert.pem"));
+        assert!(!cfg.tls.enabled());
+        assert_eq!(cfg.session.key_file.as_deref(), Some("session.key"));
+        assert_eq!(cfg.limits["AddCoffee"], "5/1");
+        assert!(!cfg.features.registration);
+        assert!(!cfg.features.rate_limiting);
+
+        // What gets printed should load back to the same thing.
+        let printed: ServerConfig = toml::from_str(&cfg.to_toml().unwrap()).unwrap();
+        assert_eq!(printed, cfg);
+    }
+
+    #[test]
+    pub fn test_bad_env_value() {
+        let mut value = Value::try_from(ServerConfig::default()).unwrap();
+        let env = vec![(
+            "COFFEE_FEATURES__REGISTRATION".to_string(),
+            "nope".to_string(),
+        )];
+        assert!(apply_env(&mut value, env.into_iter()).is_err());
+    }
+}
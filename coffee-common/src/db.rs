@@ -1,13 +1,98 @@
 // datastructures to be used with sqlx and helper functions.
 
+use crate::logging::Redacted;
+use crate::metrics;
+
 use crypto::digest::Digest;
 use crypto::sha1::Sha1;
+use sqlx::pool::PoolConnection;
 use sqlx::prelude::*;
-use sqlx::sqlite::{SqlitePool, SqliteQueryAs};
+use sqlx::sqlite::{SqliteConnection, SqlitePool, SqliteQueryAs};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, instrument};
+
+// Schema changes made since the tables were first created, applied in order.
+// How many have been applied is kept in SQLite's user_version.
+static MIGRATIONS: &[&str] = &[
+    // Lets a client retry an add without it being stored twice.
+    "ALTER TABLE COFFEE ADD COLUMN client_id TEXT;",
+    "CREATE UNIQUE INDEX IF NOT EXISTS COFFEE_CLIENT_ID ON COFFEE(user, client_id);",
+    // The drink catalog. Drinks without a user are the defaults everyone gets,
+    // which a user's own drink of the same name replaces.
+    "ALTER TABLE COFFEE ADD COLUMN drink TEXT;",
+    "CREATE TABLE IF NOT EXISTS DRINKS(id INTEGER PRIMARY KEY ASC,
+                                       user INTEGER,
+                                       name TEXT NOT NULL,
+                                       shots INTEGER NOT NULL,
+                                       caffeine_mg INTEGER,
+                                       size_ml INTEGER,
+                                       price_cents INTEGER,
+                                       FOREIGN KEY(user) REFERENCES USERS(id));",
+    "CREATE UNIQUE INDEX IF NOT EXISTS DRINKS_NAME ON DRINKS(IFNULL(user, 0), name);",
+    "INSERT INTO DRINKS(user, name, shots, caffeine_mg, size_ml) VALUES
+        (NULL, 'espresso', 1, 63, 30),
+        (NULL, 'doppio', 2, 126, 60),
+        (NULL, 'macchiato', 1, 63, 40),
+        (NULL, 'cortado', 2, 126, 120),
+        (NULL, 'flatwhite', 2, 126, 160),
+        (NULL, 'cappuccino', 2, 126, 180),
+        (NULL, 'latte', 2, 126, 240),
+        (NULL, 'longblack', 2, 126, 120),
+        (NULL, 'americano', 2, 126, 240);",
+    // What each user has changed, so the latest change can be undone. A drink
+    // change keeps the drink as it was before and after.
+    "CREATE TABLE IF NOT EXISTS ACTIONS(id INTEGER PRIMARY KEY ASC,
+                                        user INTEGER NOT NULL,
+                                        utctime INTEGER NOT NULL,
+                                        kind TEXT NOT NULL,
+                                        coffee INTEGER,
+                                        drink TEXT,
+                                        old_shots INTEGER,
+                                        old_caffeine_mg INTEGER,
+                                        old_size_ml INTEGER,
+                                        old_price_cents INTEGER,
+                                        new_shots INTEGER,
+                                        new_caffeine_mg INTEGER,
+                                        new_size_ml INTEGER,
+                                        new_price_cents INTEGER,
+                                        undone BOOL NOT NULL DEFAULT false,
+                                        FOREIGN KEY(user) REFERENCES USERS(id));",
+    "CREATE INDEX IF NOT EXISTS ACTIONS_USER ON ACTIONS(user, undone, id);",
+    // Coffee edits and deletes, which keep the coffee as it was before and
+    // after. Its shots go in old_shots and new_shots.
+    "ALTER TABLE ACTIONS ADD COLUMN old_utctime INTEGER;",
+    "ALTER TABLE ACTIONS ADD COLUMN new_utctime INTEGER;",
+    "ALTER TABLE ACTIONS ADD COLUMN old_coffee_drink TEXT;",
+    "ALTER TABLE ACTIONS ADD COLUMN new_coffee_drink TEXT;",
+    "ALTER TABLE ACTIONS ADD COLUMN client_id TEXT;",
+];
+
+// The kinds of change kept in ACTIONS.
+static ADD_COFFEE: &str = "add_coffee";
+static UPDATE_COFFEE: &str = "update_coffee";
+static DELETE_COFFEE: &str = "delete_coffee";
+static SAVE_DRINK: &str = "save_drink";
+static DELETE_DRINK: &str = "delete_drink";
+
+type Transaction = sqlx::Transaction<PoolConnection<SqliteConnection>>;
+
+// Longest a drink's name can be.
+const MAX_DRINK_NAME: usize = 32;
+
+// How far ahead of the server's clock a coffee can be, for clients whose clocks
+// run fast.
+pub const FUTURE_TOLERANCE_SECS: i64 = 5 * 60;
 
 #[derive(Debug)]
 pub enum DbError {
     UnknownApiKey,
+    NothingToUndo,
+    // The last change is older than the undo window.
+    UndoExpired,
+    // What the last change touched has changed again since.
+    UndoConflict(String),
     InternalError(sqlx::error::Error),
 }
 
@@ -27,20 +112,201 @@ impl From<sqlx::error::Error> for DbError {
 
 impl From<DbError> for tonic::Status {
     fn from(e: DbError) -> Self {
-        tonic::Status::internal(format!("Internal database error: #{:?}", e))
+        match e {
+            DbError::UnknownApiKey => tonic::Status::unauthenticated("Unknown API key"),
+            DbError::NothingToUndo => tonic::Status::not_found("Nothing to undo"),
+            DbError::UndoExpired => {
+                tonic::Status::not_found("Nothing to undo, the last change is too old to take back")
+            }
+            DbError::UndoConflict(msg) => tonic::Status::failed_precondition(msg),
+            _ => tonic::Status::internal(format!("Internal database error: #{:?}", e)),
+        }
     }
 }
 
 #[derive(Debug)]
 struct ApiKey {
-    api_key: String,
     user_id: i32,
 }
 
-#[derive(sqlx::FromRow, Debug)]
+#[derive(sqlx::FromRow, Debug, Clone, PartialEq)]
 pub struct Coffee {
     pub shots: i32,
     pub utctime: i64,
+    // The name of the drink it was, if it was added as one.
+    pub drink: Option<String>,
+}
+
+impl Coffee {
+    // The same checks for every way a coffee can be added or changed, `now`
+    // being seconds from unix epoch.
+    pub fn validate(&self, now: i64) -> Result<(), String> {
+        if self.shots < 0 {
+            return Err("Shots can't be negative".into());
+        }
+        if self.utctime > now + FUTURE_TOLERANCE_SECS {
+            return Err("Cannot add a coffee in the future".into());
+        }
+        // Nothing reading coffees back expects them from before the epoch, or
+        // copes with times too far out to be dates.
+        if self.utctime < 0 {
+            return Err("Cannot add a coffee from before 1970".into());
+        }
+        Ok(())
+    }
+}
+
+// Checks a range of times to list coffees between, where an `end` of 0 means
+// there's no upper bound.
+pub fn validate_range(start: i64, end: i64) -> Result<(), String> {
+    if end != 0 && end <= start {
+        return Err("The end time must be after the start".into());
+    }
+    Ok(())
+}
+
+// A coffee along with the id it's stored under, for changing it later.
+#[derive(sqlx::FromRow, Debug, Clone, PartialEq)]
+pub struct StoredCoffee {
+    pub id: i64,
+    pub shots: i32,
+    pub utctime: i64,
+    pub drink: Option<String>,
+}
+
+impl StoredCoffee {
+    pub fn coffee(&self) -> Coffee {
+        Coffee {
+            shots: self.shots,
+            utctime: self.utctime,
+            drink: self.drink.clone(),
+        }
+    }
+}
+
+#[derive(sqlx::FromRow, Debug, Clone, PartialEq)]
+pub struct Drink {
+    pub name: String,
+    pub shots: i32,
+    pub caffeine_mg: Option<i32>,
+    pub size_ml: Option<i32>,
+    pub price_cents: Option<i32>,
+    // Whether it's one of the defaults rather than the user's own.
+    pub global: bool,
+}
+
+impl Drink {
+    // Names are what's typed after `coffee add`, so they're kept to simple
+    // words that can't be mistaken for a number of shots.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() || self.name.len() > MAX_DRINK_NAME {
+            return Err(format!(
+                "Drink names must be 1 to {} characters",
+                MAX_DRINK_NAME
+            ));
+        }
+        if !self
+            .name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        {
+            return Err("Drink names can only use a-z, 0-9 and -".into());
+        }
+        if self.name.parse::<i32>().is_ok() {
+            return Err("Drink names can't be a number".into());
+        }
+        let negative = |v: Option<i32>| v.is_some_and(|v| v < 0);
+        if self.shots < 0
+            || negative(self.caffeine_mg)
+            || negative(self.size_ml)
+            || negative(self.price_cents)
+        {
+            return Err("Drink amounts can't be negative".into());
+        }
+        Ok(())
+    }
+}
+
+// A change that was undone.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    AddCoffee(Coffee),
+    // The coffee as it's been put back to.
+    UpdateCoffee(Coffee),
+    DeleteCoffee(Coffee),
+    SaveDrink(String),
+    DeleteDrink(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Undone {
+    pub action: Action,
+    // When the change was made.
+    pub utctime: i64,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct ActionRow {
+    id: i64,
+    utctime: i64,
+    kind: String,
+    coffee: Option<i64>,
+    drink: Option<String>,
+    old_shots: Option<i32>,
+    old_caffeine_mg: Option<i32>,
+    old_size_ml: Option<i32>,
+    old_price_cents: Option<i32>,
+    new_shots: Option<i32>,
+    new_caffeine_mg: Option<i32>,
+    new_size_ml: Option<i32>,
+    new_price_cents: Option<i32>,
+    old_utctime: Option<i64>,
+    new_utctime: Option<i64>,
+    old_coffee_drink: Option<String>,
+    new_coffee_drink: Option<String>,
+    client_id: Option<String>,
+}
+
+impl ActionRow {
+    // The coffee before the change, for coffee edits and deletes.
+    fn old_coffee(&self) -> Option<Coffee> {
+        Some(Coffee {
+            shots: self.old_shots?,
+            utctime: self.old_utctime?,
+            drink: self.old_coffee_drink.clone(),
+        })
+    }
+
+    fn new_coffee(&self) -> Option<Coffee> {
+        Some(Coffee {
+            shots: self.new_shots?,
+            utctime: self.new_utctime?,
+            drink: self.new_coffee_drink.clone(),
+        })
+    }
+
+    // The user's drink before the change, if they had one.
+    fn old_drink(&self) -> Option<Drink> {
+        Some(Drink {
+            name: self.drink.clone()?,
+            shots: self.old_shots?,
+            caffeine_mg: self.old_caffeine_mg,
+            size_ml: self.old_size_ml,
+            price_cents: self.old_price_cents,
+            global: false,
+        })
+    }
+
+    fn new_drink(&self) -> Option<Drink> {
+        Some(Drink {
+            name: self.drink.clone()?,
+            shots: self.new_shots?,
+            caffeine_mg: self.new_caffeine_mg,
+            size_ml: self.new_size_ml,
+            price_cents: self.new_price_cents,
+            global: false,
+        })
+    }
 }
 
 #[derive(sqlx::FromRow, Debug)]
@@ -49,16 +315,45 @@ pub struct User {
     pub apikey: String,
 }
 
+// Counts used to report on the database and its users.
 #[derive(Debug)]
+pub struct DbStats {
+    pub pool_size: u32,
+    pub pool_idle: usize,
+    pub registered_users: i64,
+    pub active_users: i64,
+}
+
+// How long a validated API key is trusted before going back to the database.
+// Kept short, as it's also how long a disabled or deleted user's key keeps
+// working: the other server shares the database, so there's no telling it.
+const KEY_CACHE_TTL: Duration = Duration::from_secs(5);
+
+// Cheap to clone, all clones share the same pool and key cache.
+#[derive(Debug, Clone)]
 pub struct Db {
     pool: SqlitePool,
+    // API key -> (user id, when it was validated). Only keys that were valid
+    // go in, so made up ones can't fill it.
+    key_cache: Arc<Mutex<HashMap<String, (i32, Instant)>>>,
+    key_cache_ttl: Duration,
 }
 
 impl Db {
     pub async fn new(db_file: &str) -> Result<Self, DbError> {
         // TODO: yeahhhhh, we're gonna need a better way to do this...
+        let url = format!("sqlite:{}", db_file);
+        // Every connection to an in-memory database gets a database of its
+        // own, so there can only be the one.
+        let pool = if db_file.contains(":memory:") {
+            SqlitePool::builder().max_size(1).build(&url).await?
+        } else {
+            SqlitePool::new(&url).await?
+        };
         let db = Db {
-            pool: SqlitePool::new(&format!("sqlite:{}", db_file)).await?,
+            pool,
+            key_cache: Arc::new(Mutex::new(HashMap::new())),
+            key_cache_ttl: KEY_CACHE_TTL,
         };
 
         sqlx::query(
@@ -80,12 +375,52 @@ impl Db {
         .execute(&db.pool)
         .await?;
 
+        db.migrate(MIGRATIONS).await?;
         Ok(db)
     }
 
+    // Each migration goes in with its version bump or not at all, so one that
+    // fails, or a crash part way, never leaves it half done or run twice.
+    async fn migrate(&self, migrations: &[&str]) -> Result<(), DbError> {
+        loop {
+            let mut tx = self.pool.begin().await?;
+            let (version,): (i32,) = sqlx::query_as("PRAGMA user_version;")
+                .fetch_one(&mut tx)
+                .await?;
+            let m = match migrations.get(version.max(0) as usize) {
+                Some(m) => m,
+                None => {
+                    tx.rollback().await?;
+                    return Ok(());
+                }
+            };
+            // Rolled back rather than dropped, which would close the connection.
+            if let Err(e) = sqlx::query(m).execute(&mut tx).await {
+                tx.rollback().await?;
+                return Err(e.into());
+            }
+            sqlx::query(&format!("PRAGMA user_version = {};", version.max(0) + 1))
+                .execute(&mut tx)
+                .await?;
+            tx.commit().await?;
+        }
+    }
+
+    // Checks the database can still be queried, for health checks.
+    pub async fn ping(&self) -> Result<(), DbError> {
+        sqlx::query("SELECT 1;").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    // Closes the pool, waiting for connections in use to be returned first.
+    pub async fn close(&self) {
+        self.pool.close().await;
+    }
+
     // Registers a user and returns the API key for that email address. If the
     // email already has an API key then that key will be returned.
     // i.e. There is a 1:1 mapping of email to api-key.
+    #[instrument(name = "db.register_user", skip(self, email))]
     pub async fn register_user(&self, email: &str) -> Result<User, DbError> {
         let user = sqlx::query_as::<_, User>(
             "SELECT email, apikey FROM USERS WHERE email = ? AND enabled = TRUE;",
@@ -101,7 +436,7 @@ impl Db {
             None => {
                 // The user needs to be registered.
                 let mut hasher = Sha1::new();
-                hasher.input_str(&email);
+                hasher.input_str(email);
 
                 let user = User {
                     email: email.into(),
@@ -113,57 +448,596 @@ impl Db {
                     .bind(&user.apikey)
                     .execute(&self.pool)
                     .await?;
+                info!("Registered a new user");
                 Ok(user)
             }
         }
     }
 
     // Validates the api key is for a registered user and that it is active, and returns the users internal id.
+    #[instrument(name = "db.validate_api_key", skip(self, api_key), fields(api_key = %Redacted(api_key)))]
     async fn validate_api_key(&self, api_key: &str) -> Result<ApiKey, DbError> {
-        // TODO: Do we need to cache this result for some time?
+        if let Some((user_id, validated)) = self.key_cache.lock().unwrap().get(api_key) {
+            if validated.elapsed() < self.key_cache_ttl {
+                metrics::KEY_CACHE_HITS.inc();
+                debug!("API key found in cache");
+                return Ok(ApiKey { user_id: *user_id });
+            }
+        }
+        metrics::KEY_CACHE_MISSES.inc();
+
         let mut c = sqlx::query("SELECT id FROM USERS WHERE apikey = ? AND enabled = TRUE;")
             .bind(api_key)
             .fetch(&self.pool);
 
-        if let Some(row) = c.next().await? {
-            let user_id = row.get::<i32, _>("id");
-            return Ok(ApiKey {
-                api_key: api_key.into(),
-                user_id,
-            });
+        let row = c.next().await?;
+        let mut cache = self.key_cache.lock().unwrap();
+        let ttl = self.key_cache_ttl;
+        cache.retain(|_, (_, validated)| validated.elapsed() < ttl);
+        match row {
+            Some(row) => {
+                let user_id = row.get::<i32, _>("id");
+                cache.insert(api_key.into(), (user_id, Instant::now()));
+                Ok(ApiKey { user_id })
+            }
+            None => {
+                cache.remove(api_key);
+                Err(DbError::UnknownApiKey)
+            }
         }
-        Err(DbError::UnknownApiKey)
     }
 
-    pub async fn add_coffee(&self, api_key: &str, c: &Coffee) -> Result<(), DbError> {
+    // The internal id of the user the api key belongs to.
+    pub async fn user_id(&self, api_key: &str) -> Result<i32, DbError> {
+        Ok(self.validate_api_key(api_key).await?.user_id)
+    }
+
+    // Stores a coffee, returning its id if it was new. A coffee with the same
+    // `client_id` as one already stored for the user is a retry, and is
+    // ignored.
+    #[instrument(name = "db.add_coffee", skip(self, api_key, c, client_id), fields(api_key = %Redacted(api_key), shots = c.shots))]
+    pub async fn add_coffee(
+        &self,
+        api_key: &str,
+        c: &Coffee,
+        client_id: Option<&str>,
+    ) -> Result<Option<i64>, DbError> {
         let key = self.validate_api_key(api_key).await?;
-        sqlx::query("INSERT INTO COFFEE(user, utctime, shots) VALUES (?, ?, ?);")
-            .bind(key.user_id)
-            .bind(c.utctime)
-            .bind(c.shots)
-            .execute(&self.pool)
-            .await?;
-        Ok(())
+        let mut tx = self.pool.begin().await?;
+        let added = sqlx::query(
+            "INSERT OR IGNORE INTO COFFEE(user, utctime, shots, drink, client_id) VALUES (?, ?, ?, ?, ?);",
+        )
+        .bind(key.user_id)
+        .bind(c.utctime)
+        .bind(c.shots)
+        .bind(&c.drink)
+        .bind(client_id)
+        .execute(&mut tx)
+        .await?
+            > 0;
+        let id = if added {
+            let (id,): (i64,) = sqlx::query_as("SELECT last_insert_rowid();")
+                .fetch_one(&mut tx)
+                .await?;
+            let change = Change {
+                coffee: Some(id),
+                ..Change::default()
+            };
+            log_action(&mut tx, key.user_id, ADD_COFFEE, &change).await?;
+            Some(id)
+        } else {
+            None
+        };
+        tx.commit().await?;
+        if added {
+            metrics::COFFEES_LOGGED.inc();
+            metrics::SHOTS_LOGGED.inc_by(c.shots.max(0) as i64);
+        } else {
+            debug!("Ignoring a coffee we already have");
+        }
+        Ok(id)
+    }
+
+    // Users who have logged a coffee at or after `active_since` count as active.
+    #[instrument(name = "db.stats", skip(self))]
+    pub async fn stats(&self, active_since: i64) -> Result<DbStats, DbError> {
+        let (registered_users,): (i64,) =
+            sqlx::query_as("SELECT COUNT(*) FROM USERS WHERE enabled = TRUE;")
+                .fetch_one(&self.pool)
+                .await?;
+        let (active_users,): (i64,) =
+            sqlx::query_as("SELECT COUNT(DISTINCT user) FROM COFFEE WHERE utctime >= ?;")
+                .bind(active_since)
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(DbStats {
+            pool_size: self.pool.size(),
+            pool_idle: self.pool.idle(),
+            registered_users,
+            active_users,
+        })
     }
 
     pub async fn get_coffees(&self, api_key: &str) -> Result<Vec<Coffee>, DbError> {
-        let key = self.validate_api_key(api_key).await?;
         // TODO: Need to take the time as a constraint here for the query, dont want to return everything. But... here we are.
+        self.get_coffees_between(api_key, 0, 0).await
+    }
+
+    // Coffees logged from `start` up to but not including `end`, where an `end`
+    // of 0 means there's no upper bound.
+    #[instrument(name = "db.get_coffees_between", skip(self, api_key), fields(api_key = %Redacted(api_key)))]
+    pub async fn get_coffees_between(
+        &self,
+        api_key: &str,
+        start: i64,
+        end: i64,
+    ) -> Result<Vec<Coffee>, DbError> {
+        let key = self.validate_api_key(api_key).await?;
+        let end = if end == 0 { i64::MAX } else { end };
         let res: Vec<Coffee> = sqlx::query_as::<_, Coffee>(
             "SELECT utctime,
-                  shots
+                  shots,
+                  drink
                   FROM COFFEE
-                  INNER JOIN USERS
-                  ON COFFEE.user = USERS.id
-                  WHERE USERS.apikey = ?
+                  WHERE user = ?
+                  AND utctime >= ?
+                  AND utctime < ?
                   ORDER BY utctime ASC",
         )
-        .bind(&key.api_key)
+        .bind(key.user_id)
+        .bind(start)
+        .bind(end)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(res)
+    }
+
+    // Up to `limit` coffees from `start` to `end` as get_coffees_between, after
+    // skipping the first `offset`, along with their ids.
+    #[instrument(name = "db.get_coffee_page", skip(self, api_key), fields(api_key = %Redacted(api_key)))]
+    pub async fn get_coffee_page(
+        &self,
+        api_key: &str,
+        start: i64,
+        end: i64,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<StoredCoffee>, DbError> {
+        let key = self.validate_api_key(api_key).await?;
+        let end = if end == 0 { i64::MAX } else { end };
+        let res = sqlx::query_as::<_, StoredCoffee>(
+            "SELECT id,
+                  utctime,
+                  shots,
+                  drink
+                  FROM COFFEE
+                  WHERE user = ?
+                  AND utctime >= ?
+                  AND utctime < ?
+                  ORDER BY utctime ASC, id ASC
+                  LIMIT ? OFFSET ?",
+        )
+        .bind(key.user_id)
+        .bind(start)
+        .bind(end)
+        .bind(limit)
+        .bind(offset)
         .fetch_all(&self.pool)
         .await?;
+        Ok(res)
+    }
+
+    // The coffee added with `client_id`, if there is one.
+    #[instrument(name = "db.find_coffee", skip(self, api_key, client_id), fields(api_key = %Redacted(api_key)))]
+    pub async fn find_coffee(
+        &self,
+        api_key: &str,
+        client_id: &str,
+    ) -> Result<Option<StoredCoffee>, DbError> {
+        let key = self.validate_api_key(api_key).await?;
+        let res = sqlx::query_as::<_, StoredCoffee>(
+            "SELECT id, utctime, shots, drink FROM COFFEE WHERE user = ? AND client_id = ?",
+        )
+        .bind(key.user_id)
+        .bind(client_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(res)
+    }
 
+    // The user's coffee `id`, if they have one.
+    #[instrument(name = "db.get_coffee", skip(self, api_key), fields(api_key = %Redacted(api_key)))]
+    pub async fn get_coffee(
+        &self,
+        api_key: &str,
+        id: i64,
+    ) -> Result<Option<StoredCoffee>, DbError> {
+        let key = self.validate_api_key(api_key).await?;
+        let res = sqlx::query_as::<_, StoredCoffee>(
+            "SELECT id, utctime, shots, drink FROM COFFEE WHERE id = ? AND user = ?",
+        )
+        .bind(id)
+        .bind(key.user_id)
+        .fetch_optional(&self.pool)
+        .await?;
         Ok(res)
     }
+
+    // Replaces the user's coffee `id` with `c`, returning whether they had one.
+    #[instrument(name = "db.update_coffee", skip(self, api_key, c), fields(api_key = %Redacted(api_key)))]
+    pub async fn update_coffee(&self, api_key: &str, id: i64, c: &Coffee) -> Result<bool, DbError> {
+        let key = self.validate_api_key(api_key).await?;
+        let mut tx = self.pool.begin().await?;
+        let (old, _) = match coffee_by_id(&mut tx, key.user_id, id).await? {
+            Some(old) => old,
+            None => {
+                tx.rollback().await?;
+                return Ok(false);
+            }
+        };
+        sqlx::query("UPDATE COFFEE SET utctime = ?, shots = ?, drink = ? WHERE id = ?;")
+            .bind(c.utctime)
+            .bind(c.shots)
+            .bind(&c.drink)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        let change = Change {
+            coffee: Some(id),
+            old_coffee: Some(&old),
+            new_coffee: Some(c),
+            ..Change::default()
+        };
+        log_action(&mut tx, key.user_id, UPDATE_COFFEE, &change).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    // Deletes the user's coffee `id`, returning whether they had one.
+    #[instrument(name = "db.delete_coffee", skip(self, api_key), fields(api_key = %Redacted(api_key)))]
+    pub async fn delete_coffee(&self, api_key: &str, id: i64) -> Result<bool, DbError> {
+        let key = self.validate_api_key(api_key).await?;
+        let mut tx = self.pool.begin().await?;
+        let (old, client_id) = match coffee_by_id(&mut tx, key.user_id, id).await? {
+            Some(old) => old,
+            None => {
+                tx.rollback().await?;
+                return Ok(false);
+            }
+        };
+        sqlx::query("DELETE FROM COFFEE WHERE id = ?;")
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        let change = Change {
+            coffee: Some(id),
+            old_coffee: Some(&old),
+            client_id: client_id.as_deref(),
+            ..Change::default()
+        };
+        log_action(&mut tx, key.user_id, DELETE_COFFEE, &change).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    // The user's own drinks along with the defaults they haven't replaced, by
+    // name.
+    #[instrument(name = "db.get_drinks", skip(self, api_key), fields(api_key = %Redacted(api_key)))]
+    pub async fn get_drinks(&self, api_key: &str) -> Result<Vec<Drink>, DbError> {
+        let key = self.validate_api_key(api_key).await?;
+        let drinks = sqlx::query_as::<_, Drink>(
+            "SELECT name, shots, caffeine_mg, size_ml, price_cents, user IS NULL AS global
+                  FROM DRINKS
+                  WHERE user = ?
+                  OR (user IS NULL AND name NOT IN (SELECT name FROM DRINKS WHERE user = ?))
+                  ORDER BY name ASC",
+        )
+        .bind(key.user_id)
+        .bind(key.user_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(drinks)
+    }
+
+    // Adds or replaces one of the user's drinks. `global` is ignored, users
+    // can only change their own.
+    #[instrument(name = "db.save_drink", skip(self, api_key, d), fields(api_key = %Redacted(api_key), drink = %d.name))]
+    pub async fn save_drink(&self, api_key: &str, d: &Drink) -> Result<(), DbError> {
+        let key = self.validate_api_key(api_key).await?;
+        let mut tx = self.pool.begin().await?;
+        let old = own_drink(&mut tx, key.user_id, &d.name).await?;
+        put_drink(&mut tx, key.user_id, d).await?;
+        let change = Change {
+            drink: Some(&d.name),
+            old_drink: old.as_ref(),
+            new_drink: Some(d),
+            ..Change::default()
+        };
+        log_action(&mut tx, key.user_id, SAVE_DRINK, &change).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // Removes one of the user's drinks, returning whether there was one. A
+    // default of the same name comes back in its place.
+    #[instrument(name = "db.delete_drink", skip(self, api_key), fields(api_key = %Redacted(api_key)))]
+    pub async fn delete_drink(&self, api_key: &str, name: &str) -> Result<bool, DbError> {
+        let key = self.validate_api_key(api_key).await?;
+        let mut tx = self.pool.begin().await?;
+        let old = match own_drink(&mut tx, key.user_id, name).await? {
+            Some(d) => d,
+            None => {
+                tx.rollback().await?;
+                return Ok(false);
+            }
+        };
+        sqlx::query("DELETE FROM DRINKS WHERE user = ? AND name = ?;")
+            .bind(key.user_id)
+            .bind(name)
+            .execute(&mut tx)
+            .await?;
+        let change = Change {
+            drink: Some(name),
+            old_drink: Some(&old),
+            ..Change::default()
+        };
+        log_action(&mut tx, key.user_id, DELETE_DRINK, &change).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    // Reverses the user's latest change that hasn't been undone already, as
+    // long as it was made at or after `not_before`. Undoing again reverses
+    // the change before that, and so on. It's refused if what the change
+    // touched has changed since, rather than overwriting the newer change.
+    #[instrument(name = "db.undo_last", skip(self, api_key), fields(api_key = %Redacted(api_key)))]
+    pub async fn undo_last(&self, api_key: &str, not_before: i64) -> Result<Undone, DbError> {
+        let key = self.validate_api_key(api_key).await?;
+        let mut tx = self.pool.begin().await?;
+        // Dropping a transaction closes its connection, so one that's
+        // refused is rolled back instead.
+        let res = undo_latest(&mut tx, key.user_id, not_before).await;
+        match res {
+            Ok(_) => tx.commit().await?,
+            Err(_) => tx.rollback().await?,
+        };
+        res
+    }
+}
+
+async fn undo_latest(
+    tx: &mut Transaction,
+    user_id: i32,
+    not_before: i64,
+) -> Result<Undone, DbError> {
+    let row = sqlx::query_as::<_, ActionRow>(
+        "SELECT id, utctime, kind, coffee, drink,
+              old_shots, old_caffeine_mg, old_size_ml, old_price_cents,
+              new_shots, new_caffeine_mg, new_size_ml, new_price_cents,
+              old_utctime, new_utctime, old_coffee_drink, new_coffee_drink, client_id
+              FROM ACTIONS
+              WHERE user = ? AND undone = FALSE
+              ORDER BY id DESC
+              LIMIT 1",
+    )
+    .bind(user_id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(DbError::NothingToUndo)?;
+    if row.utctime < not_before {
+        return Err(DbError::UndoExpired);
+    }
+
+    let action = if row.kind == ADD_COFFEE {
+        let id = row.coffee.unwrap_or_default();
+        let coffee = sqlx::query_as::<_, Coffee>(
+            "SELECT utctime, shots, drink FROM COFFEE WHERE id = ? AND user = ?",
+        )
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| DbError::UndoConflict("That coffee has already been removed".into()))?;
+        sqlx::query("DELETE FROM COFFEE WHERE id = ?;")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        Action::AddCoffee(coffee)
+    } else if row.kind == UPDATE_COFFEE {
+        let id = row.coffee.unwrap_or_default();
+        let now = coffee_by_id(tx, user_id, id).await?.map(|(c, _)| c);
+        if now != row.new_coffee() {
+            return Err(DbError::UndoConflict(
+                "That coffee has changed since, not undoing".into(),
+            ));
+        }
+        let old = row
+            .old_coffee()
+            .ok_or_else(|| DbError::UndoConflict("That change can't be undone".into()))?;
+        sqlx::query("UPDATE COFFEE SET utctime = ?, shots = ?, drink = ? WHERE id = ?;")
+            .bind(old.utctime)
+            .bind(old.shots)
+            .bind(&old.drink)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        Action::UpdateCoffee(old)
+    } else if row.kind == DELETE_COFFEE {
+        // It goes back under its old id, and client id so retries of it are
+        // still recognised, unless something has taken either since.
+        let old = row
+            .old_coffee()
+            .ok_or_else(|| DbError::UndoConflict("That change can't be undone".into()))?;
+        let restored = sqlx::query(
+            "INSERT OR IGNORE INTO COFFEE(id, user, utctime, shots, drink, client_id) VALUES (?, ?, ?, ?, ?, ?);",
+        )
+        .bind(row.coffee)
+        .bind(user_id)
+        .bind(old.utctime)
+        .bind(old.shots)
+        .bind(&old.drink)
+        .bind(&row.client_id)
+        .execute(&mut *tx)
+        .await?;
+        if restored == 0 {
+            return Err(DbError::UndoConflict(
+                "Another coffee has taken that one's place, not undoing".into(),
+            ));
+        }
+        Action::DeleteCoffee(old)
+    } else {
+        let name = row.drink.clone().unwrap_or_default();
+        if own_drink(tx, user_id, &name).await? != row.new_drink() {
+            return Err(DbError::UndoConflict(format!(
+                "{} has changed since, not undoing",
+                name
+            )));
+        }
+        match row.old_drink() {
+            Some(old) => put_drink(tx, user_id, &old).await?,
+            None => {
+                sqlx::query("DELETE FROM DRINKS WHERE user = ? AND name = ?;")
+                    .bind(user_id)
+                    .bind(&name)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+        if row.kind == SAVE_DRINK {
+            Action::SaveDrink(name)
+        } else {
+            Action::DeleteDrink(name)
+        }
+    };
+
+    sqlx::query("UPDATE ACTIONS SET undone = TRUE WHERE id = ?;")
+        .bind(row.id)
+        .execute(&mut *tx)
+        .await?;
+    info!(kind = %row.kind, "Undid a change");
+    Ok(Undone {
+        action,
+        utctime: row.utctime,
+    })
+}
+
+// The user's coffee `id` and the client id it was added with.
+async fn coffee_by_id(
+    tx: &mut Transaction,
+    user_id: i32,
+    id: i64,
+) -> Result<Option<(Coffee, Option<String>)>, DbError> {
+    let row: Option<(i64, i32, Option<String>, Option<String>)> = sqlx::query_as(
+        "SELECT utctime, shots, drink, client_id FROM COFFEE WHERE id = ? AND user = ?",
+    )
+    .bind(id)
+    .bind(user_id)
+    .fetch_optional(tx)
+    .await?;
+    Ok(row.map(|(utctime, shots, drink, client_id)| {
+        (
+            Coffee {
+                shots,
+                utctime,
+                drink,
+            },
+            client_id,
+        )
+    }))
+}
+
+// The user's own drink called `name`, not counting the defaults.
+async fn own_drink(
+    tx: &mut Transaction,
+    user_id: i32,
+    name: &str,
+) -> Result<Option<Drink>, DbError> {
+    let drink = sqlx::query_as::<_, Drink>(
+        "SELECT name, shots, caffeine_mg, size_ml, price_cents, FALSE AS global
+              FROM DRINKS
+              WHERE user = ? AND name = ?",
+    )
+    .bind(user_id)
+    .bind(name)
+    .fetch_optional(tx)
+    .await?;
+    Ok(drink)
+}
+
+async fn put_drink(tx: &mut Transaction, user_id: i32, d: &Drink) -> Result<(), DbError> {
+    sqlx::query(
+        "INSERT OR REPLACE INTO DRINKS(user, name, shots, caffeine_mg, size_ml, price_cents)
+              VALUES (?, ?, ?, ?, ?, ?);",
+    )
+    .bind(user_id)
+    .bind(&d.name)
+    .bind(d.shots)
+    .bind(d.caffeine_mg)
+    .bind(d.size_ml)
+    .bind(d.price_cents)
+    .execute(tx)
+    .await?;
+    Ok(())
+}
+
+// What a change touched, and what's needed to reverse it.
+#[derive(Debug, Default)]
+struct Change<'a> {
+    coffee: Option<i64>,
+    drink: Option<&'a str>,
+    old_drink: Option<&'a Drink>,
+    new_drink: Option<&'a Drink>,
+    old_coffee: Option<&'a Coffee>,
+    new_coffee: Option<&'a Coffee>,
+    client_id: Option<&'a str>,
+}
+
+// Records a change in the user's action log.
+async fn log_action(
+    tx: &mut Transaction,
+    user_id: i32,
+    kind: &str,
+    change: &Change<'_>,
+) -> Result<(), DbError> {
+    let (old, new) = (change.old_drink, change.new_drink);
+    let (old_coffee, new_coffee) = (change.old_coffee, change.new_coffee);
+    sqlx::query(
+        "INSERT INTO ACTIONS(user, utctime, kind, coffee, drink,
+              old_shots, old_caffeine_mg, old_size_ml, old_price_cents,
+              new_shots, new_caffeine_mg, new_size_ml, new_price_cents,
+              old_utctime, new_utctime, old_coffee_drink, new_coffee_drink, client_id)
+              VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);",
+    )
+    .bind(user_id)
+    .bind(utc_now())
+    .bind(kind)
+    .bind(change.coffee)
+    .bind(change.drink)
+    .bind(old.map(|d| d.shots).or(old_coffee.map(|c| c.shots)))
+    .bind(old.and_then(|d| d.caffeine_mg))
+    .bind(old.and_then(|d| d.size_ml))
+    .bind(old.and_then(|d| d.price_cents))
+    .bind(new.map(|d| d.shots).or(new_coffee.map(|c| c.shots)))
+    .bind(new.and_then(|d| d.caffeine_mg))
+    .bind(new.and_then(|d| d.size_ml))
+    .bind(new.and_then(|d| d.price_cents))
+    .bind(old_coffee.map(|c| c.utctime))
+    .bind(new_coffee.map(|c| c.utctime))
+    .bind(old_coffee.and_then(|c| c.drink.as_deref()))
+    .bind(new_coffee.and_then(|c| c.drink.as_deref()))
+    .bind(change.client_id)
+    .execute(tx)
+    .await?;
+    Ok(())
+}
+
+// Seconds from unix epoch.
+fn utc_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
 }
 
 #[cfg(test)]
@@ -183,4 +1057,389 @@ mod test {
         // The key should be reported as valid...
         db.validate_api_key(&user1.apikey).await.unwrap();
     }
+
+    #[tokio::test]
+    pub async fn test_key_revoked() {
+        let mut db = Db::new(DB).await.unwrap();
+        db.key_cache_ttl = Duration::from_millis(50);
+        let user = db.register_user("revoked@bar.com").await.unwrap();
+        db.validate_api_key(&user.apikey).await.unwrap();
+
+        // A key stops working once its user is disabled and the cache has
+        // let it go...
+        sqlx::query("UPDATE USERS SET enabled = FALSE WHERE apikey = ?;")
+            .bind(&user.apikey)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        tokio::time::delay_for(db.key_cache_ttl).await;
+        assert!(matches!(
+            db.validate_api_key(&user.apikey).await,
+            Err(DbError::UnknownApiKey)
+        ));
+
+        // ...or deleted, and it isn't kept in the cache while it's unknown.
+        sqlx::query("DELETE FROM USERS WHERE apikey = ?;")
+            .bind(&user.apikey)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        assert!(matches!(
+            db.validate_api_key(&user.apikey).await,
+            Err(DbError::UnknownApiKey)
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn test_key_cache() {
+        let db = Db::new(DB).await.unwrap();
+        let user = db.register_user("cache@bar.com").await.unwrap();
+        let (hits, misses) = (
+            metrics::KEY_CACHE_HITS.get(),
+            metrics::KEY_CACHE_MISSES.get(),
+        );
+
+        db.validate_api_key(&user.apikey).await.unwrap();
+        db.validate_api_key(&user.apikey).await.unwrap();
+        // Made up keys always go to the database.
+        assert!(db.validate_api_key("guess").await.is_err());
+        assert!(db.validate_api_key("guess").await.is_err());
+
+        // Other tests run alongside, so the counters only give a lower bound.
+        assert!(metrics::KEY_CACHE_HITS.get() > hits);
+        assert!(metrics::KEY_CACHE_MISSES.get() >= misses + 3);
+        let cache = db.key_cache.lock().unwrap();
+        assert_eq!(cache.len(), 1);
+        assert!(cache.contains_key(&user.apikey));
+    }
+
+    #[tokio::test]
+    pub async fn test_add_is_idempotent() {
+        let db = Db::new(DB).await.unwrap();
+        let user = db.register_user("retry@bar.com").await.unwrap();
+        let c = Coffee {
+            shots: 2,
+            utctime: 1000,
+            drink: None,
+        };
+
+        // Retrying with the same client id doesn't store it twice...
+        let id = db.add_coffee(&user.apikey, &c, Some("a")).await.unwrap();
+        assert!(id.is_some());
+        assert_eq!(
+            db.add_coffee(&user.apikey, &c, Some("a")).await.unwrap(),
+            None
+        );
+        // ...but coffees without one are always new.
+        assert!(db
+            .add_coffee(&user.apikey, &c, None)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(db
+            .add_coffee(&user.apikey, &c, None)
+            .await
+            .unwrap()
+            .is_some());
+
+        assert_eq!(db.get_coffees(&user.apikey).await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    pub async fn test_coffees_between() {
+        let db = Db::new(DB).await.unwrap();
+        let user = db.register_user("range@bar.com").await.unwrap();
+        for utctime in &[100, 200, 300] {
+            let c = Coffee {
+                shots: 1,
+                utctime: *utctime,
+                drink: None,
+            };
+            db.add_coffee(&user.apikey, &c, None).await.unwrap();
+        }
+
+        let times = |coffees: Vec<Coffee>| coffees.iter().map(|c| c.utctime).collect::<Vec<_>>();
+        // The start is included and the end isn't.
+        let between = db
+            .get_coffees_between(&user.apikey, 100, 300)
+            .await
+            .unwrap();
+        assert_eq!(times(between), vec![100, 200]);
+        let open_ended = db.get_coffees_between(&user.apikey, 200, 0).await.unwrap();
+        assert_eq!(times(open_ended), vec![200, 300]);
+    }
+
+    #[tokio::test]
+    pub async fn test_coffee_pages() {
+        let db = Db::new(DB).await.unwrap();
+        let user = db.register_user("pages@bar.com").await.unwrap();
+        let other = db.register_user("other@bar.com").await.unwrap();
+        for utctime in &[100, 200, 300] {
+            let c = Coffee {
+                shots: 1,
+                utctime: *utctime,
+                drink: None,
+            };
+            let id = format!("c{}", utctime);
+            db.add_coffee(&user.apikey, &c, Some(&id)).await.unwrap();
+        }
+
+        let page = db.get_coffee_page(&user.apikey, 0, 0, 2, 1).await.unwrap();
+        let times: Vec<_> = page.iter().map(|c| c.utctime).collect();
+        assert_eq!(times, vec![200, 300]);
+        let found = db.find_coffee(&user.apikey, "c200").await.unwrap();
+        assert_eq!(found.as_ref(), page.first());
+        let id = page[0].id;
+        assert_eq!(db.get_coffee(&user.apikey, id).await.unwrap(), found);
+        assert_eq!(db.get_coffee(&other.apikey, id).await.unwrap(), None);
+
+        // Only the user's own coffees can be changed.
+        let changed = Coffee {
+            shots: 3,
+            utctime: 250,
+            drink: Some("latte".into()),
+        };
+        assert!(!db.update_coffee(&other.apikey, id, &changed).await.unwrap());
+        assert!(db.update_coffee(&user.apikey, id, &changed).await.unwrap());
+        let found = db.find_coffee(&user.apikey, "c200").await.unwrap().unwrap();
+        assert_eq!(found.coffee(), changed);
+
+        assert!(!db.delete_coffee(&other.apikey, id).await.unwrap());
+        assert!(db.delete_coffee(&user.apikey, id).await.unwrap());
+        assert!(!db.delete_coffee(&user.apikey, id).await.unwrap());
+        assert_eq!(db.get_coffees(&user.apikey).await.unwrap().len(), 2);
+    }
+
+    #[test]
+    pub fn test_coffee_validation() {
+        let c = Coffee {
+            shots: 2,
+            utctime: 1000,
+            drink: None,
+        };
+        assert!(c.validate(1000).is_ok());
+        assert!(c.validate(1000 - FUTURE_TOLERANCE_SECS).is_ok());
+        assert!(c.validate(999 - FUTURE_TOLERANCE_SECS).is_err());
+        let at = |utctime| Coffee {
+            utctime,
+            ..c.clone()
+        };
+        assert!(at(0).validate(1000).is_ok());
+        assert!(at(-1).validate(1000).is_err());
+        assert!(at(i64::MIN).validate(1000).is_err());
+        assert!(Coffee { shots: -1, ..c }.validate(1000).is_err());
+        assert!(validate_range(100, 0).is_ok());
+        assert!(validate_range(100, 100).is_err());
+    }
+
+    #[tokio::test]
+    pub async fn test_drinks() {
+        let db = Db::new(DB).await.unwrap();
+        let user = db.register_user("drinks@bar.com").await.unwrap();
+        let other = db.register_user("other@bar.com").await.unwrap();
+
+        let defaults = db.get_drinks(&user.apikey).await.unwrap();
+        let latte = defaults.iter().find(|d| d.name == "latte").unwrap();
+        assert!(latte.global);
+
+        // A user's own drink replaces the default of the same name, for them.
+        let mine = Drink {
+            shots: 3,
+            price_cents: Some(450),
+            global: false,
+            ..latte.clone()
+        };
+        db.save_drink(&user.apikey, &mine).await.unwrap();
+        let drinks = db.get_drinks(&user.apikey).await.unwrap();
+        assert_eq!(drinks.len(), defaults.len());
+        assert_eq!(drinks.iter().find(|d| d.name == "latte"), Some(&mine));
+        let theirs = db.get_drinks(&other.apikey).await.unwrap();
+        assert_eq!(theirs.iter().find(|d| d.name == "latte"), Some(latte));
+
+        // Deleting it brings the default back, and the defaults can't go.
+        assert!(db.delete_drink(&user.apikey, "latte").await.unwrap());
+        assert!(!db.delete_drink(&user.apikey, "latte").await.unwrap());
+        assert_eq!(db.get_drinks(&user.apikey).await.unwrap(), defaults);
+    }
+
+    #[test]
+    pub fn test_drink_validation() {
+        let drink = |name: &str, shots| Drink {
+            name: name.into(),
+            shots,
+            caffeine_mg: None,
+            size_ml: None,
+            price_cents: None,
+            global: false,
+        };
+        assert!(drink("flat-white2", 2).validate().is_ok());
+        assert!(drink("", 1).validate().is_err());
+        assert!(drink("Flat White", 1).validate().is_err());
+        assert!(drink("2", 1).validate().is_err());
+        assert!(drink("espresso", -1).validate().is_err());
+    }
+
+    #[tokio::test]
+    pub async fn test_undo() {
+        let db = Db::new(DB).await.unwrap();
+        let user = db.register_user("undo@bar.com").await.unwrap();
+        let key = &user.apikey;
+        let mocha = Drink {
+            name: "mocha".into(),
+            shots: 2,
+            caffeine_mg: None,
+            size_ml: Some(300),
+            price_cents: None,
+            global: false,
+        };
+        let c = Coffee {
+            shots: 2,
+            utctime: 1000,
+            drink: Some("mocha".into()),
+        };
+        db.save_drink(key, &mocha).await.unwrap();
+        db.save_drink(
+            key,
+            &Drink {
+                shots: 3,
+                ..mocha.clone()
+            },
+        )
+        .await
+        .unwrap();
+        db.add_coffee(key, &c, None).await.unwrap();
+
+        // Changes come back off newest first.
+        let undone = db.undo_last(key, 0).await.unwrap();
+        assert_eq!(undone.action, Action::AddCoffee(c));
+        assert!(db.get_coffees(key).await.unwrap().is_empty());
+        let undone = db.undo_last(key, 0).await.unwrap();
+        assert_eq!(undone.action, Action::SaveDrink("mocha".into()));
+        let drinks = db.get_drinks(key).await.unwrap();
+        assert_eq!(drinks.iter().find(|d| d.name == "mocha"), Some(&mocha));
+
+        // Nothing older than the window is undone.
+        assert!(matches!(
+            db.undo_last(key, utc_now() + 60).await,
+            Err(DbError::UndoExpired)
+        ));
+
+        // A drink that's changed behind the log's back is left alone.
+        sqlx::query("UPDATE DRINKS SET shots = 5 WHERE name = 'mocha';")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        assert!(matches!(
+            db.undo_last(key, 0).await,
+            Err(DbError::UndoConflict(_))
+        ));
+        sqlx::query("UPDATE DRINKS SET shots = 2 WHERE name = 'mocha';")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        db.undo_last(key, 0).await.unwrap();
+        assert!(db
+            .get_drinks(key)
+            .await
+            .unwrap()
+            .iter()
+            .all(|d| d.name != "mocha"));
+        assert!(matches!(
+            db.undo_last(key, 0).await,
+            Err(DbError::NothingToUndo)
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn test_undo_edits() {
+        let db = Db::new(DB).await.unwrap();
+        let user = db.register_user("undo_edits@bar.com").await.unwrap();
+        let key = &user.apikey;
+        let c = Coffee {
+            shots: 2,
+            utctime: 1000,
+            drink: Some("latte".into()),
+        };
+        let edited = Coffee {
+            shots: 3,
+            utctime: 2000,
+            drink: None,
+        };
+        let id = db
+            .add_coffee(key, &c, Some("retried"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(db.update_coffee(key, id, &edited).await.unwrap());
+        assert!(db.delete_coffee(key, id).await.unwrap());
+
+        // The delete comes back as the edited coffee, under its old ids.
+        let undone = db.undo_last(key, 0).await.unwrap();
+        assert_eq!(undone.action, Action::DeleteCoffee(edited.clone()));
+        assert_eq!(db.get_coffees(key).await.unwrap(), vec![edited.clone()]);
+        assert_eq!(db.add_coffee(key, &c, Some("retried")).await.unwrap(), None);
+
+        // An edit made since isn't overwritten.
+        let other = Coffee {
+            shots: 1,
+            ..edited.clone()
+        };
+        sqlx::query("UPDATE COFFEE SET shots = 1 WHERE id = ?;")
+            .bind(id)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        assert!(matches!(
+            db.undo_last(key, 0).await,
+            Err(DbError::UndoConflict(_))
+        ));
+        assert_eq!(db.get_coffees(key).await.unwrap(), vec![other]);
+        sqlx::query("UPDATE COFFEE SET shots = 3 WHERE id = ?;")
+            .bind(id)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+
+        let undone = db.undo_last(key, 0).await.unwrap();
+        assert_eq!(undone.action, Action::UpdateCoffee(c.clone()));
+        assert_eq!(db.get_coffees(key).await.unwrap(), vec![c.clone()]);
+        let undone = db.undo_last(key, 0).await.unwrap();
+        assert_eq!(undone.action, Action::AddCoffee(c));
+        assert!(db.get_coffees(key).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    pub async fn test_failed_migration() {
+        let db = Db::new(DB).await.unwrap();
+        let version = || async {
+            let (v,): (i32,) = sqlx::query_as("PRAGMA user_version;")
+                .fetch_one(&db.pool)
+                .await
+                .unwrap();
+            v as usize
+        };
+        let tables = || async {
+            let (n,): (i32,) = sqlx::query_as(
+                "SELECT COUNT(*) FROM sqlite_master WHERE name IN ('MIG_A', 'MIG_B');",
+            )
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+            n
+        };
+        let mut migrations = MIGRATIONS.to_vec();
+        migrations.push("CREATE TABLE MIG_A(x INTEGER);");
+        migrations.push("CREATE TABLE MIG_B(x INTEGER); INSERT INTO NOPE VALUES (1);");
+
+        // The one before it stays, and none of the failed one does.
+        assert!(db.migrate(&migrations).await.is_err());
+        assert_eq!(version().await, MIGRATIONS.len() + 1);
+        assert_eq!(tables().await, 1);
+
+        // Once fixed it picks up where it stopped.
+        migrations[MIGRATIONS.len() + 1] = "CREATE TABLE MIG_B(x INTEGER);";
+        db.migrate(&migrations).await.unwrap();
+        assert_eq!(version().await, MIGRATIONS.len() + 2);
+        assert_eq!(tables().await, 2);
+    }
 }
@@ -1,5 +1,14 @@
+pub mod config;
 pub mod db;
+pub mod limit;
+pub mod logging;
+pub mod metrics;
+pub mod retry;
 
 pub mod coffee {
     tonic::include_proto!("coffee");
 }
+
+pub mod health {
+    tonic::include_proto!("grpc.health.v1");
+}
@@ -0,0 +1,376 @@
+// Token-bucket rate limiting for the RPC methods, and the REST routes that do
+// the same things, so a script stuck in a loop can't flood the servers.
+
+use crate::config::ServerConfig;
+use crate::retry;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tonic::Status;
+
+// A call turned away, and how long until it would be let through.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limited {
+    pub method: Method,
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for Limited {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "Rate limit exceeded for {}, slow down", self.method)
+    }
+}
+
+impl From<Limited> for Status {
+    fn from(l: Limited) -> Self {
+        retry::resource_exhausted(l.to_string(), l.retry_after)
+    }
+}
+
+// How often idle (full) buckets are dropped.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+// The slowest a bucket may refill, a token a day. Anything slower is as good
+// as shut, and far slower ones have waits too long for a Duration.
+const MIN_PER_SECOND: f64 = 1.0 / 86400.0;
+const MAX_WAIT: Duration = Duration::from_secs(86400);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Register,
+    AddCoffee,
+    ListCoffee,
+    // Only over REST.
+    UpdateCoffee,
+    DeleteCoffee,
+    WatchCoffees,
+    VerifyKey,
+    ListDrinks,
+    SaveDrink,
+    DeleteDrink,
+    UndoLast,
+}
+
+impl Method {
+    pub const ALL: [Method; 11] = [
+        Method::Register,
+        Method::AddCoffee,
+        Method::ListCoffee,
+        Method::UpdateCoffee,
+        Method::DeleteCoffee,
+        Method::WatchCoffees,
+        Method::VerifyKey,
+        Method::ListDrinks,
+        Method::SaveDrink,
+        Method::DeleteDrink,
+        Method::UndoLast,
+    ];
+
+    fn default_limit(self) -> Limit {
+        match self {
+            // Registration is keyed by peer address and should be rare.
+            Method::Register => Limit {
+                burst: 5,
+                per_second: 1.0 / 60.0,
+            },
+            Method::AddCoffee => Limit {
+                burst: 20,
+                per_second: 1.0,
+            },
+            Method::ListCoffee | Method::ListDrinks => Limit {
+                burst: 30,
+                per_second: 2.0,
+            },
+            Method::UpdateCoffee
+            | Method::DeleteCoffee
+            | Method::SaveDrink
+            | Method::DeleteDrink
+            | Method::UndoLast => Limit {
+                burst: 20,
+                per_second: 1.0,
+            },
+            // Watches are long lived, there's no need to open them often.
+            Method::WatchCoffees => Limit {
+                burst: 5,
+                per_second: 0.1,
+            },
+            // Keyed by peer address, so it can't be used to guess keys.
+            Method::VerifyKey => Limit {
+                burst: 10,
+                per_second: 0.2,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let name = match self {
+            Method::Register => "Register",
+            Method::AddCoffee => "AddCoffee",
+            Method::ListCoffee => "ListCoffee",
+            Method::UpdateCoffee => "UpdateCoffee",
+            Method::DeleteCoffee => "DeleteCoffee",
+            Method::WatchCoffees => "WatchCoffees",
+            Method::VerifyKey => "VerifyKey",
+            Method::ListDrinks => "ListDrinks",
+            Method::SaveDrink => "SaveDrink",
+            Method::DeleteDrink => "DeleteDrink",
+            Method::UndoLast => "UndoLast",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Method {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Method::ALL
+            .iter()
+            .find(|m| m.to_string().eq_ignore_ascii_case(s))
+            .copied()
+            .ok_or_else(|| format!("Unknown method: {}", s))
+    }
+}
+
+// Who a bucket belongs to. Calls with a valid key are limited per user, and
+// anything else per peer address, so made up keys can't each get a fresh
+// bucket.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Caller {
+    User(i32),
+    Peer(String),
+}
+
+// A bucket holds up to `burst` tokens and refills at `per_second`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+impl FromStr for Limit {
+    type Err = String;
+
+    // Parses BURST/PER_SECOND, e.g. "10/0.5".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '/');
+        let burst = parts
+            .next()
+            .and_then(|b| b.trim().parse::<u32>().ok())
+            .filter(|b| *b > 0)
+            .ok_or_else(|| format!("Invalid burst in limit: {}", s))?;
+        let per_second = parts
+            .next()
+            .and_then(|r| r.trim().parse::<f64>().ok())
+            .filter(|r| r.is_finite() && *r >= MIN_PER_SECOND)
+            .ok_or_else(|| format!("Invalid rate in limit: {}", s))?;
+        Ok(Limit { burst, per_second })
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &Limit, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        self.updated = now;
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    buckets: HashMap<(Method, Caller), Bucket>,
+    pruned: Instant,
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: HashMap<Method, Limit>,
+    buckets: Mutex<Buckets>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        let limits = Method::ALL
+            .iter()
+            .map(|m| (*m, m.default_limit()))
+            .collect();
+        RateLimiter {
+            limits,
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl RateLimiter {
+    // A limiter built from the `limits` in the config, falling back to the
+    // defaults for anything not mentioned. Does no limiting at all if the
+    // feature is turned off.
+    pub fn from_config(config: &ServerConfig) -> Result<Self, String> {
+        let mut limiter = RateLimiter::default();
+        if !config.features.rate_limiting {
+            limiter.limits.clear();
+            return Ok(limiter);
+        }
+        for (method, limit) in &config.limits {
+            limiter.set_limit(method.parse()?, limit.parse()?);
+        }
+        Ok(limiter)
+    }
+
+    pub fn set_limit(&mut self, method: Method, limit: Limit) {
+        self.limits.insert(method, limit);
+    }
+
+    // Takes a token for `caller` on `method`, or says how long until one is
+    // available.
+    pub fn check(&self, method: Method, caller: &Caller) -> Result<(), Limited> {
+        self.check_at(method, caller, Instant::now())
+    }
+
+    fn check_at(&self, method: Method, caller: &Caller, now: Instant) -> Result<(), Limited> {
+        let limit = match self.limits.get(&method) {
+            Some(l) => l,
+            None => return Ok(()),
+        };
+
+        let mut guard = self.buckets.lock().unwrap();
+        let Buckets { buckets, pruned } = &mut *guard;
+        // A full bucket is no different from a new one.
+        if now.saturating_duration_since(*pruned) >= PRUNE_INTERVAL {
+            let limits = &self.limits;
+            buckets.retain(|(m, _), b| {
+                b.refill(&limits[m], now);
+                b.tokens < limits[m].burst as f64
+            });
+            *pruned = now;
+        }
+
+        let bucket = buckets
+            .entry((method, caller.clone()))
+            .or_insert_with(|| Bucket {
+                tokens: limit.burst as f64,
+                updated: now,
+            });
+        bucket.refill(limit, now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            // Limits set in code needn't have been parsed, so they may be
+            // slower than a config's can.
+            let wait = (1.0 - bucket.tokens) / limit.per_second;
+            Err(Limited {
+                method,
+                retry_after: Duration::try_from_secs_f64(wait)
+                    .unwrap_or(MAX_WAIT)
+                    .min(MAX_WAIT),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_bucket_refills() {
+        let mut limiter = RateLimiter::default();
+        limiter.set_limit(
+            Method::AddCoffee,
+            Limit {
+                burst: 2,
+                per_second: 1.0,
+            },
+        );
+        let (a, b) = (Caller::User(1), Caller::Peer("::1".into()));
+        let start = Instant::now();
+
+        limiter.check_at(Method::AddCoffee, &a, start).unwrap();
+        limiter.check_at(Method::AddCoffee, &a, start).unwrap();
+        let err = limiter.check_at(Method::AddCoffee, &a, start).unwrap_err();
+        assert_eq!(err.retry_after, Duration::from_secs(1));
+        let status = Status::from(err);
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+
+        // Other keys get their own bucket...
+        limiter.check_at(Method::AddCoffee, &b, start).unwrap();
+        // ...and a second later there's another token for the first.
+        limiter
+            .check_at(Method::AddCoffee, &a, start + Duration::from_secs(1))
+            .unwrap();
+    }
+
+    #[test]
+    pub fn test_prunes_idle_buckets() {
+        let limiter = RateLimiter::default();
+        let start = Instant::now();
+        for id in 0..100 {
+            limiter
+                .check_at(Method::ListCoffee, &Caller::User(id), start)
+                .unwrap();
+        }
+        let busy = Caller::User(1000);
+        for _ in 0..30 {
+            limiter
+                .check_at(Method::ListCoffee, &busy, start + PRUNE_INTERVAL)
+                .unwrap();
+        }
+        // Those that had filled back up were dropped along the way.
+        let buckets = &limiter.buckets.lock().unwrap().buckets;
+        assert_eq!(buckets.len(), 1);
+        assert!(buckets.contains_key(&(Method::ListCoffee, busy)));
+    }
+
+    #[test]
+    pub fn test_parse_limit() {
+        let l: Limit = "10/0.5".parse().unwrap();
+        assert_eq!(
+            l,
+            Limit {
+                burst: 10,
+                per_second: 0.5
+            }
+        );
+        assert!("0/1".parse::<Limit>().is_err());
+        assert!("10/0".parse::<Limit>().is_err());
+        assert!("10/1e-300".parse::<Limit>().is_err());
+        assert!("10/inf".parse::<Limit>().is_err());
+        assert!(format!("10/{}", MIN_PER_SECOND).parse::<Limit>().is_ok());
+        assert!("10".parse::<Limit>().is_err());
+        assert_eq!("addcoffee".parse::<Method>(), Ok(Method::AddCoffee));
+    }
+
+    #[test]
+    pub fn test_slow_limit() {
+        let mut limiter = RateLimiter::default();
+        limiter.set_limit(
+            Method::AddCoffee,
+            Limit {
+                burst: 1,
+                per_second: 1e-300,
+            },
+        );
+        let caller = Caller::User(1);
+        let start = Instant::now();
+        limiter.check_at(Method::AddCoffee, &caller, start).unwrap();
+        let err = limiter
+            .check_at(Method::AddCoffee, &caller, start)
+            .unwrap_err();
+        assert_eq!(err.retry_after, MAX_WAIT);
+    }
+}
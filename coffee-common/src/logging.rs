@@ -0,0 +1,69 @@
+// Structured logging for the servers: subscriber setup, request ids to tie a
+// request's log lines together, and redaction so secrets never reach the logs.
+
+use crate::config::ServerConfig;
+
+use std::fmt;
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+/// Carries the request id in gRPC metadata and HTTP headers alike.
+pub static REQUEST_ID_HEADER: &str = "x-request-id";
+
+// The most of a caller supplied request id we'll log.
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+/// Sets up the global subscriber from the `log_format` ("pretty" or "json")
+/// and `log_level` (an env-filter directive such as "info") in the config.
+pub fn init(config: &ServerConfig) -> Result<(), String> {
+    let filter = EnvFilter::try_new(&config.log_level)
+        .map_err(|e| format!("Invalid log_level {}: {}", config.log_level, e))?;
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    let res = match config.log_format.as_str() {
+        "json" => builder.json().try_init(),
+        "pretty" => builder.pretty().try_init(),
+        f => return Err(format!("Unknown log_format {}, expected json or pretty", f)),
+    };
+    res.map_err(|e| format!("Could not set up logging: {}", e))
+}
+
+/// Uses the caller's request id if they sent a sensible one, so their logs
+/// and ours line up, or makes up a new one.
+pub fn request_id(supplied: Option<&str>) -> String {
+    match supplied {
+        Some(id)
+            if !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') =>
+        {
+            id.into()
+        }
+        _ => new_request_id(),
+    }
+}
+
+pub fn new_request_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// Displays just enough of a secret to tell two apart, e.g. `4f1a…`.
+pub struct Redacted<'a>(pub &'a str);
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let prefix: String = self.0.chars().take(4).collect();
+        if prefix.len() < self.0.len() {
+            write!(f, "{}…", prefix)
+        } else {
+            write!(f, "…")
+        }
+    }
+}
+
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        fmt::Display::fmt(self, f)
+    }
+}
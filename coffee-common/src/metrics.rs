@@ -0,0 +1,68 @@
+// Prometheus metrics shared by the servers. Everything goes in the default
+// registry so each server can add its own and have them rendered together.
+
+use crate::db::{Db, DbError};
+
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, register_int_gauge, Encoder, IntCounter, IntGauge};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Users who have logged a coffee within this many seconds are active.
+const ACTIVE_USER_WINDOW: i64 = 24 * 60 * 60;
+
+lazy_static! {
+    pub static ref COFFEES_LOGGED: IntCounter =
+        register_int_counter!("coffee_coffees_logged_total", "Coffees logged").unwrap();
+    pub static ref SHOTS_LOGGED: IntCounter =
+        register_int_counter!("coffee_shots_logged_total", "Shots logged").unwrap();
+    pub static ref KEY_CACHE_HITS: IntCounter = register_int_counter!(
+        "coffee_key_cache_hits_total",
+        "API key validations answered from the cache"
+    )
+    .unwrap();
+    pub static ref KEY_CACHE_MISSES: IntCounter = register_int_counter!(
+        "coffee_key_cache_misses_total",
+        "API key validations that went to the database"
+    )
+    .unwrap();
+    static ref DB_POOL_SIZE: IntGauge =
+        register_int_gauge!("coffee_db_pool_connections", "Open database connections").unwrap();
+    static ref DB_POOL_IDLE: IntGauge = register_int_gauge!(
+        "coffee_db_pool_idle_connections",
+        "Idle database connections"
+    )
+    .unwrap();
+    static ref REGISTERED_USERS: IntGauge =
+        register_int_gauge!("coffee_registered_users", "Registered, enabled users").unwrap();
+    static ref ACTIVE_USERS: IntGauge = register_int_gauge!(
+        "coffee_active_users",
+        "Users who have logged a coffee in the last day"
+    )
+    .unwrap();
+}
+
+/// Refreshes the gauges read from the database, then renders every
+/// registered metric in the Prometheus text format.
+pub async fn render(db: &Db) -> Result<String, DbError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let stats = db.stats(now - ACTIVE_USER_WINDOW).await?;
+    DB_POOL_SIZE.set(stats.pool_size as i64);
+    DB_POOL_IDLE.set(stats.pool_idle as i64);
+    REGISTERED_USERS.set(stats.registered_users);
+    ACTIVE_USERS.set(stats.active_users);
+
+    let mut buf = Vec::new();
+    let encoder = prometheus::TextEncoder::new();
+    encoder
+        .encode(&prometheus::gather(), &mut buf)
+        .expect("Could not encode metrics");
+    Ok(String::from_utf8(buf).expect("Metrics are not valid UTF-8"))
+}
+
+/// The content type to serve `render`ed metrics with.
+pub fn content_type() -> &'static str {
+    "text/plain; version=0.0.4"
+}
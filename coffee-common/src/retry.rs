@@ -0,0 +1,32 @@
+// Helpers for carrying a retry-after hint on a tonic::Status, so the client
+// knows how long to back off when the server turns a request away.
+
+use crate::coffee::RetryInfo;
+
+use prost::Message;
+use std::time::Duration;
+use tonic::{Code, Status};
+
+/// Builds a `ResourceExhausted` status that tells the client to wait for
+/// `retry_after` before trying again.
+pub fn resource_exhausted(message: impl Into<String>, retry_after: Duration) -> Status {
+    let info = RetryInfo {
+        retry_after_ms: retry_after.as_millis() as i64,
+    };
+    let mut details = Vec::new();
+    // Encoding into a Vec can't run out of space.
+    info.encode(&mut details)
+        .expect("Could not encode RetryInfo");
+    Status::with_details(Code::ResourceExhausted, message, details.into())
+}
+
+/// Pulls the retry-after hint back out of a status, if the server sent one.
+pub fn retry_after(status: &Status) -> Option<Duration> {
+    if status.code() != Code::ResourceExhausted || status.details().is_empty() {
+        return None;
+    }
+    RetryInfo::decode(status.details())
+        .ok()
+        .filter(|i| i.retry_after_ms >= 0)
+        .map(|i| Duration::from_millis(i.retry_after_ms as u64))
+}
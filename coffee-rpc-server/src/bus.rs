@@ -0,0 +1,61 @@
+// An in-process broadcast of newly added coffees, so watchers hear about
+// them the moment they're logged.
+
+use coffee_common::coffee::CoffeeItem;
+
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::{broadcast, watch};
+
+// How many events a slow watcher can fall behind before it starts missing them.
+const BUS_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct CoffeeEvent {
+    pub user_id: i32,
+    // The id it's stored under.
+    pub id: i64,
+    pub coffee: CoffeeItem,
+}
+
+#[derive(Debug, Clone)]
+pub struct CoffeeBus {
+    tx: broadcast::Sender<CoffeeEvent>,
+    // Set once the server is stopping, so watchers finish and let it.
+    closing: Arc<watch::Sender<bool>>,
+    closed: watch::Receiver<bool>,
+}
+
+impl Default for CoffeeBus {
+    fn default() -> Self {
+        let (tx, _) = broadcast::channel(BUS_CAPACITY);
+        let (closing, closed) = watch::channel(false);
+        CoffeeBus {
+            tx,
+            closing: Arc::new(closing),
+            closed,
+        }
+    }
+}
+
+impl CoffeeBus {
+    pub fn publish(&self, event: CoffeeEvent) {
+        // Nobody listening isn't an error.
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<CoffeeEvent> {
+        self.tx.subscribe()
+    }
+
+    /// Tells every watcher to finish up.
+    pub fn close(&self) {
+        let _ = self.closing.broadcast(true);
+    }
+
+    /// Resolves once `close` has been called, straight away if it already has.
+    pub fn closed(&self) -> impl Future<Output = ()> {
+        let mut closed = self.closed.clone();
+        async move { while let Some(false) = closed.recv().await {} }
+    }
+}
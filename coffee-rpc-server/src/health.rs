@@ -0,0 +1,80 @@
+// The gRPC health service. The server counts as serving while it can reach
+// its database, which is the one thing every RPC needs.
+
+use coffee_common::db::Db;
+use coffee_common::health::health_check_response::ServingStatus;
+use coffee_common::health::health_server::Health;
+use coffee_common::health::{HealthCheckRequest, HealthCheckResponse};
+
+use tonic::{Request, Response, Status};
+use tracing::warn;
+
+// The services that can be asked about by name. The empty name means the
+// server as a whole.
+const SERVICES: &[&str] = &["", "coffee.Coffee"];
+
+#[derive(Debug)]
+pub struct HealthService {
+    db: Db,
+}
+
+impl HealthService {
+    pub fn new(db: Db) -> Self {
+        HealthService { db }
+    }
+}
+
+// Probes call this every few seconds, so it isn't rate limited or logged unless
+// something's wrong.
+#[tonic::async_trait]
+impl Health for HealthService {
+    async fn check(
+        &self,
+        req: Request<HealthCheckRequest>,
+    ) -> Result<Response<HealthCheckResponse>, Status> {
+        let service = &req.get_ref().service;
+        if !SERVICES.contains(&service.as_str()) {
+            return Err(Status::not_found(format!("Unknown service {}", service)));
+        }
+
+        let status = match self.db.ping().await {
+            Ok(()) => ServingStatus::Serving,
+            Err(e) => {
+                warn!(error = ?e, "Health check could not reach the database");
+                ServingStatus::NotServing
+            }
+        };
+        Ok(Response::new(HealthCheckResponse {
+            status: status as i32,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    pub async fn test_check() {
+        let db = Db::new("file::memory:").await.unwrap();
+        let health = HealthService::new(db.clone());
+        let check = |service: &str| {
+            health.check(Request::new(HealthCheckRequest {
+                service: service.into(),
+            }))
+        };
+
+        let resp = check("").await.unwrap().into_inner();
+        assert_eq!(resp.status, ServingStatus::Serving as i32);
+        assert!(check("coffee.Coffee").await.is_ok());
+        assert_eq!(
+            check("nope").await.unwrap_err().code(),
+            tonic::Code::NotFound
+        );
+
+        // Once the database has gone, so has the server.
+        db.close().await;
+        let resp = check("").await.unwrap().into_inner();
+        assert_eq!(resp.status, ServingStatus::NotServing as i32);
+    }
+}
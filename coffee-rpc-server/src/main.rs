@@ -1,17 +1,23 @@
+mod bus;
+mod health;
+mod metrics;
 mod rpc;
+mod server;
 
-use coffee_common::coffee::coffee_server::CoffeeServer;
+use coffee_common::config::ServerConfig;
 use coffee_common::db::Db;
-use rpc::CoffeeService;
+use coffee_common::logging;
 
 use clap::{App, AppSettings, Arg};
-use tonic::transport::Server;
+use std::net::SocketAddr;
+use std::path::Path;
+use tokio::net::TcpListener;
 
 static DEFAULT_ADDR: &str = "[::1]:50051";
 static DEFAULT_DB: &str = "coffee_db";
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> Result<(), server::Error> {
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .setting(AppSettings::ColoredHelp)
         .version(env!("CARGO_PKG_VERSION"))
@@ -55,17 +61,61 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .required(false)
                 .global(true),
         )
+        .arg(
+            Arg::with_name("limit")
+                .long("limit")
+                .help(
+                    "Override a per-method rate limit as METHOD=BURST/PER_SECOND, e.g. AddCoffee=10/0.5",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("print-config")
+                .long("print-config")
+                .help("Print the effective configuration and exit")
+                .required(false)
+                .global(true),
+        )
         .get_matches();
 
-    let addr = matches.value_of("addr").unwrap_or(DEFAULT_ADDR).parse()?;
-    let db = matches.value_of("db").unwrap_or(DEFAULT_DB);
+    let defaults = ServerConfig {
+        addr: DEFAULT_ADDR.into(),
+        db: DEFAULT_DB.into(),
+        ..ServerConfig::default()
+    };
+    let mut config = ServerConfig::load(defaults, matches.value_of("config").map(Path::new))?;
+    if let Some(addr) = matches.value_of("addr") {
+        config.addr = addr.into();
+    }
+    if let Some(db) = matches.value_of("db") {
+        config.db = db.into();
+    }
+    for l in matches.values_of("limit").into_iter().flatten() {
+        let mut parts = l.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(method), Some(limit)) => {
+                config.limits.insert(method.into(), limit.into());
+            }
+            _ => return Err(format!("Expected METHOD=BURST/PER_SECOND, got: {}", l).into()),
+        }
+    }
+
+    if matches.is_present("print-config") {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+
+    logging::init(&config)?;
 
-    let coffee = CoffeeService::new(Db::new(db).await?);
+    let addr: SocketAddr = config.addr.parse()?;
+    let db = Db::new(&config.db).await?;
+    let mut listener = TcpListener::bind(addr).await?;
 
-    Server::builder()
-        .add_service(CoffeeServer::new(coffee))
-        .serve(addr)
-        .await?;
+    server::run(&config, db, listener.incoming(), server::shutdown_signal()).await?;
 
     Ok(())
 }
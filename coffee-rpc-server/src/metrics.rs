@@ -0,0 +1,84 @@
+// Per-RPC metrics, and a small HTTP server on a side port for Prometheus to
+// scrape them along with the shared ones from coffee_common::metrics.
+
+use coffee_common::db::Db;
+use coffee_common::limit::Method;
+use coffee_common::metrics;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use prometheus::{register_histogram_vec, register_int_counter_vec, HistogramVec, IntCounterVec};
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use tonic::Status;
+
+lazy_static! {
+    static ref RPC_DURATION: HistogramVec = register_histogram_vec!(
+        "coffee_rpc_duration_seconds",
+        "Time taken to handle each RPC",
+        &["method"]
+    )
+    .unwrap();
+    static ref RPC_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "coffee_rpc_errors_total",
+        "RPCs that returned an error, by status code",
+        &["method", "code"]
+    )
+    .unwrap();
+}
+
+/// Times an RPC handler, counting it as an error by status code if it fails.
+pub async fn track<T, F>(method: Method, handler: F) -> Result<T, Status>
+where
+    F: Future<Output = Result<T, Status>>,
+{
+    let name = method.to_string();
+    let timer = RPC_DURATION.with_label_values(&[&name]).start_timer();
+    let res = handler.await;
+    timer.observe_duration();
+
+    if let Err(status) = &res {
+        let code = format!("{:?}", status.code());
+        RPC_ERRORS.with_label_values(&[&name, &code]).inc();
+    }
+    res
+}
+
+async fn handle(req: Request<Body>, db: Db) -> Result<Response<Body>, Infallible> {
+    if req.uri().path() != "/metrics" {
+        let mut resp = Response::new(Body::from("Not found"));
+        *resp.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(resp);
+    }
+
+    let resp = match metrics::render(&db).await {
+        Ok(body) => Response::builder()
+            .header(hyper::header::CONTENT_TYPE, metrics::content_type())
+            .body(Body::from(body))
+            .unwrap(),
+        Err(e) => {
+            let mut resp = Response::new(Body::from(format!("Error: {}", e)));
+            *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            resp
+        }
+    };
+    Ok(resp)
+}
+
+/// Serves `/metrics` on `addr` until `signal` resolves.
+pub async fn serve<F>(addr: SocketAddr, db: Db, signal: F) -> Result<(), hyper::Error>
+where
+    F: Future<Output = ()>,
+{
+    let make_svc = make_service_fn(move |_| {
+        let db = db.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(req, db.clone()))) }
+    });
+
+    hyper::Server::bind(&addr)
+        .serve(make_svc)
+        .with_graceful_shutdown(signal)
+        .await
+}
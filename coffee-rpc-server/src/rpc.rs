@@ -1,29 +1,85 @@
 use coffee_common::coffee::coffee_server::Coffee;
+use coffee_common::coffee::undo_last_response::Action;
+use coffee_common::coffee::watch_coffees_event::Event;
 use coffee_common::coffee::{
-    AddCoffeeRequest, AddCoffeeResponse, CoffeeItem, ListCoffeeRequest, ListCoffeeResponse,
-    RegisterRequest, RegisterResponse,
+    AddCoffeeRequest, AddCoffeeResponse, CoffeeItem, DeleteDrinkRequest, DeleteDrinkResponse,
+    Drink, Heartbeat, ListCoffeeRequest, ListCoffeeResponse, ListDrinksRequest, ListDrinksResponse,
+    RegisterRequest, RegisterResponse, SaveDrinkRequest, SaveDrinkResponse, UndoLastRequest,
+    UndoLastResponse, VerifyKeyRequest, VerifyKeyResponse, WatchCoffeesEvent, WatchCoffeesRequest,
 };
-use coffee_common::db::Db;
+use coffee_common::config::{Features, ServerConfig};
+use coffee_common::db::{self, Db, DbError};
+use coffee_common::logging::{self, REQUEST_ID_HEADER};
 
+use crate::bus::{CoffeeBus, CoffeeEvent};
+use crate::metrics;
+use coffee_common::limit::{Caller, Method, RateLimiter};
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::RecvError;
+use tokio::sync::mpsc;
+use tonic::metadata::MetadataValue;
 use tonic::{Request, Response, Status};
+use tracing::{debug, info, info_span, warn, Instrument, Span};
+
+// How many events can queue up for a watcher before we wait on it.
+const WATCH_BUFFER: usize = 64;
 
 #[derive(Debug)]
 pub struct CoffeeService {
     db: Db,
+    limiter: RateLimiter,
+    bus: CoffeeBus,
+    features: Features,
+    watch_heartbeat: Duration,
+    undo_window: Duration,
 }
 
 impl CoffeeService {
-    pub fn new(db: Db) -> Self {
-        CoffeeService { db }
+    pub fn new(db: Db, limiter: RateLimiter, bus: CoffeeBus, config: &ServerConfig) -> Self {
+        CoffeeService {
+            db,
+            limiter,
+            bus,
+            features: config.features.clone(),
+            watch_heartbeat: Duration::from_secs(config.watch_heartbeat.max(1)),
+            undo_window: Duration::from_secs(config.undo_window),
+        }
     }
 }
 
-#[tonic::async_trait]
-impl Coffee for CoffeeService {
-    async fn register(
+impl CoffeeService {
+    // Takes a token for the key's user, or for the peer if it isn't a key.
+    async fn limit<T>(
+        &self,
+        method: Method,
+        req: &Request<T>,
+        api_key: &str,
+    ) -> Result<(), Status> {
+        let caller = match self.db.user_id(api_key).await {
+            Ok(id) => Caller::User(id),
+            Err(DbError::UnknownApiKey) => Caller::Peer(peer(req)),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(self.limiter.check(method, &caller)?)
+    }
+
+    async fn handle_register(
         &self,
         req: Request<RegisterRequest>,
     ) -> Result<Response<RegisterResponse>, Status> {
+        if !self.features.registration {
+            return Err(Status::permission_denied(
+                "Registration is disabled on this server",
+            ));
+        }
+
+        // There's no API key yet, so registrations are limited per peer.
+        self.limiter
+            .check(Method::Register, &Caller::Peer(peer(&req)))?;
+
         let email = &req.get_ref().email;
         let user = self.db.register_user(email).await?;
         let resp = RegisterResponse {
@@ -33,43 +89,503 @@ impl Coffee for CoffeeService {
         Ok(Response::new(resp))
     }
 
-    async fn add_coffee(
+    async fn handle_add_coffee(
         &self,
         req: Request<AddCoffeeRequest>,
     ) -> Result<Response<AddCoffeeResponse>, Status> {
         let api_key = &req.get_ref().api_key;
+        self.limit(Method::AddCoffee, &req, api_key).await?;
+
         let coffee = match &req.get_ref().coffee {
-            Some(c) => coffee_common::db::Coffee {
+            Some(c) => db::Coffee {
                 shots: c.shots,
                 utctime: c.utc_time,
+                drink: Some(c.drink.clone()).filter(|d| !d.is_empty()),
             },
             None => {
                 return Err(Status::invalid_argument("No coffee provided..."));
             }
         };
+        coffee
+            .validate(utc_now())
+            .map_err(Status::invalid_argument)?;
 
-        self.db.add_coffee(api_key, &coffee).await?;
+        let client_id = Some(req.get_ref().client_id.as_str()).filter(|id| !id.is_empty());
+        // A retry of something we already have is still a success, but
+        // watchers have already heard about it.
+        if let Some(id) = self.db.add_coffee(api_key, &coffee, client_id).await? {
+            self.bus.publish(CoffeeEvent {
+                user_id: self.db.user_id(api_key).await?,
+                id,
+                coffee: coffee_item(coffee),
+            });
+        }
         let resp = AddCoffeeResponse { success: true };
         Ok(Response::new(resp))
     }
 
-    async fn list_coffee(
+    async fn handle_list_coffee(
         &self,
         req: Request<ListCoffeeRequest>,
     ) -> Result<Response<ListCoffeeResponse>, Status> {
         let api_key = &req.get_ref().api_key;
-        let db_coffees = self.db.get_coffees(api_key).await?;
-        let mut coffees = Vec::with_capacity(db_coffees.len());
+        self.limit(Method::ListCoffee, &req, api_key).await?;
 
-        for c in db_coffees {
-            coffees.push(CoffeeItem {
-                utc_time: c.utctime,
-                shots: c.shots,
-            });
-        }
+        let (start, end) = (req.get_ref().start_utc_time, req.get_ref().end_utc_time);
+        db::validate_range(start, end).map_err(Status::invalid_argument)?;
+
+        let db_coffees = self.db.get_coffees_between(api_key, start, end).await?;
+        let coffees = db_coffees.into_iter().map(coffee_item).collect();
 
         let resp = ListCoffeeResponse { coffees };
 
         Ok(Response::new(resp))
     }
+
+    async fn handle_verify_key(
+        &self,
+        req: Request<VerifyKeyRequest>,
+    ) -> Result<Response<VerifyKeyResponse>, Status> {
+        self.limiter
+            .check(Method::VerifyKey, &Caller::Peer(peer(&req)))?;
+        self.db.user_id(&req.get_ref().api_key).await?;
+        Ok(Response::new(VerifyKeyResponse {
+            server_utc_time: utc_now(),
+        }))
+    }
+
+    async fn handle_list_drinks(
+        &self,
+        req: Request<ListDrinksRequest>,
+    ) -> Result<Response<ListDrinksResponse>, Status> {
+        let api_key = &req.get_ref().api_key;
+        self.limit(Method::ListDrinks, &req, api_key).await?;
+
+        let drinks = self.db.get_drinks(api_key).await?;
+        Ok(Response::new(ListDrinksResponse {
+            drinks: drinks.into_iter().map(drink_message).collect(),
+        }))
+    }
+
+    async fn handle_save_drink(
+        &self,
+        req: Request<SaveDrinkRequest>,
+    ) -> Result<Response<SaveDrinkResponse>, Status> {
+        let api_key = &req.get_ref().api_key;
+        self.limit(Method::SaveDrink, &req, api_key).await?;
+
+        let drink = match &req.get_ref().drink {
+            Some(d) => db_drink(d),
+            None => return Err(Status::invalid_argument("No drink provided...")),
+        };
+        drink.validate().map_err(Status::invalid_argument)?;
+        self.db.save_drink(api_key, &drink).await?;
+        Ok(Response::new(SaveDrinkResponse {}))
+    }
+
+    async fn handle_delete_drink(
+        &self,
+        req: Request<DeleteDrinkRequest>,
+    ) -> Result<Response<DeleteDrinkResponse>, Status> {
+        let api_key = &req.get_ref().api_key;
+        self.limit(Method::DeleteDrink, &req, api_key).await?;
+
+        let deleted = self.db.delete_drink(api_key, &req.get_ref().name).await?;
+        Ok(Response::new(DeleteDrinkResponse { deleted }))
+    }
+
+    async fn handle_undo_last(
+        &self,
+        req: Request<UndoLastRequest>,
+    ) -> Result<Response<UndoLastResponse>, Status> {
+        let api_key = &req.get_ref().api_key;
+        self.limit(Method::UndoLast, &req, api_key).await?;
+
+        let not_before = utc_now() - self.undo_window.as_secs() as i64;
+        let undone = self.db.undo_last(api_key, not_before).await?;
+        let mut resp = UndoLastResponse {
+            utc_time: undone.utctime,
+            ..UndoLastResponse::default()
+        };
+        match undone.action {
+            db::Action::AddCoffee(c) => {
+                resp.set_action(Action::AddCoffee);
+                resp.coffee = Some(coffee_item(c));
+            }
+            db::Action::UpdateCoffee(c) => {
+                resp.set_action(Action::UpdateCoffee);
+                resp.coffee = Some(coffee_item(c));
+            }
+            db::Action::DeleteCoffee(c) => {
+                resp.set_action(Action::DeleteCoffee);
+                resp.coffee = Some(coffee_item(c));
+            }
+            db::Action::SaveDrink(name) => {
+                resp.set_action(Action::SaveDrink);
+                resp.drink = name;
+            }
+            db::Action::DeleteDrink(name) => {
+                resp.set_action(Action::DeleteDrink);
+                resp.drink = name;
+            }
+        }
+        Ok(Response::new(resp))
+    }
+
+    async fn handle_watch_coffees(
+        &self,
+        req: Request<WatchCoffeesRequest>,
+    ) -> Result<Response<mpsc::Receiver<Result<WatchCoffeesEvent, Status>>>, Status> {
+        let api_key = &req.get_ref().api_key;
+        self.limit(Method::WatchCoffees, &req, api_key).await?;
+        let user_id = self.db.user_id(api_key).await?;
+
+        // Subscribe before the backfill so nothing logged in between is missed.
+        // Anything logged in between is in both, and only sent the once.
+        let mut live = self.bus.subscribe();
+        let since = req.get_ref().since_utc_time;
+        let backfill = if since > 0 {
+            self.db
+                .get_coffee_page(api_key, since, 0, i64::MAX, 0)
+                .await?
+        } else {
+            Vec::new()
+        };
+        let sent: HashSet<i64> = backfill.iter().map(|c| c.id).collect();
+
+        let (mut tx, rx) = mpsc::channel(WATCH_BUFFER);
+        let heartbeat = self.watch_heartbeat;
+        let closed = self.bus.closed();
+        let watcher = async move {
+            for c in backfill {
+                let event = coffee_event(coffee_item(c.coffee()));
+                if tx.send(Ok(event)).await.is_err() {
+                    return;
+                }
+            }
+
+            let mut ticks = tokio::time::interval(heartbeat);
+            tokio::pin!(closed);
+            loop {
+                let event = tokio::select! {
+                    e = live.recv() => match e {
+                        Ok(e) if e.user_id == user_id && !sent.contains(&e.id) => {
+                            coffee_event(e.coffee)
+                        }
+                        Ok(_) => continue,
+                        Err(RecvError::Lagged(missed)) => {
+                            warn!(missed, "Watcher fell behind, coffees were skipped");
+                            continue;
+                        }
+                        Err(RecvError::Closed) => return,
+                    },
+                    _ = ticks.tick() => heartbeat_event(),
+                    // Ending the stream lets the server finish shutting down.
+                    _ = &mut closed => {
+                        debug!("Server stopping, ending the watch");
+                        return;
+                    }
+                };
+                if tx.send(Ok(event)).await.is_err() {
+                    debug!("Watcher went away");
+                    return;
+                }
+            }
+        };
+        tokio::spawn(watcher.instrument(Span::current()));
+
+        Ok(Response::new(rx))
+    }
+}
+
+// The caller's IP address, for limiting calls made without an API key.
+fn peer<T>(req: &Request<T>) -> String {
+    req.remote_addr()
+        .map(|a| a.ip().to_string())
+        .unwrap_or_default()
+}
+
+fn coffee_item(c: db::Coffee) -> CoffeeItem {
+    CoffeeItem {
+        utc_time: c.utctime,
+        shots: c.shots,
+        drink: c.drink.unwrap_or_default(),
+    }
+}
+
+// Amounts that aren't known are sent as 0.
+fn drink_message(d: db::Drink) -> Drink {
+    Drink {
+        name: d.name,
+        shots: d.shots,
+        caffeine_mg: d.caffeine_mg.unwrap_or_default(),
+        size_ml: d.size_ml.unwrap_or_default(),
+        price_cents: d.price_cents.unwrap_or_default(),
+        global: d.global,
+    }
+}
+
+fn db_drink(d: &Drink) -> db::Drink {
+    let known = |v: i32| Some(v).filter(|v| *v != 0);
+    db::Drink {
+        name: d.name.clone(),
+        shots: d.shots,
+        caffeine_mg: known(d.caffeine_mg),
+        size_ml: known(d.size_ml),
+        price_cents: known(d.price_cents),
+        global: false,
+    }
+}
+
+fn coffee_event(coffee: CoffeeItem) -> WatchCoffeesEvent {
+    WatchCoffeesEvent {
+        event: Some(Event::Coffee(coffee)),
+    }
+}
+
+fn heartbeat_event() -> WatchCoffeesEvent {
+    WatchCoffeesEvent {
+        event: Some(Event::Heartbeat(Heartbeat {
+            server_utc_time: utc_now(),
+        })),
+    }
+}
+
+// Seconds from unix epoch.
+fn utc_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+// Runs an RPC handler in a span tagged with the caller's request id (or a new
+// one), timing it for the metrics and echoing the id back in the response.
+async fn traced<Req, Resp, F, Fut>(
+    method: Method,
+    req: Request<Req>,
+    handler: F,
+) -> Result<Response<Resp>, Status>
+where
+    F: FnOnce(Request<Req>) -> Fut,
+    Fut: Future<Output = Result<Response<Resp>, Status>>,
+{
+    let supplied = req
+        .metadata()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok());
+    let request_id = logging::request_id(supplied);
+    let span = info_span!("rpc", method = %method, request_id = %request_id);
+
+    let res = async {
+        let res = metrics::track(method, handler(req)).await;
+        match &res {
+            Ok(_) => info!("RPC succeeded"),
+            Err(status) => warn!(code = ?status.code(), message = status.message(), "RPC failed"),
+        }
+        res
+    }
+    .instrument(span)
+    .await;
+
+    res.map(|mut resp| {
+        if let Ok(v) = MetadataValue::from_str(&request_id) {
+            resp.metadata_mut().insert(REQUEST_ID_HEADER, v);
+        }
+        resp
+    })
+}
+
+#[tonic::async_trait]
+impl Coffee for CoffeeService {
+    async fn register(
+        &self,
+        req: Request<RegisterRequest>,
+    ) -> Result<Response<RegisterResponse>, Status> {
+        traced(Method::Register, req, |r| self.handle_register(r)).await
+    }
+
+    async fn add_coffee(
+        &self,
+        req: Request<AddCoffeeRequest>,
+    ) -> Result<Response<AddCoffeeResponse>, Status> {
+        traced(Method::AddCoffee, req, |r| self.handle_add_coffee(r)).await
+    }
+
+    async fn list_coffee(
+        &self,
+        req: Request<ListCoffeeRequest>,
+    ) -> Result<Response<ListCoffeeResponse>, Status> {
+        traced(Method::ListCoffee, req, |r| self.handle_list_coffee(r)).await
+    }
+
+    async fn verify_key(
+        &self,
+        req: Request<VerifyKeyRequest>,
+    ) -> Result<Response<VerifyKeyResponse>, Status> {
+        traced(Method::VerifyKey, req, |r| self.handle_verify_key(r)).await
+    }
+
+    async fn list_drinks(
+        &self,
+        req: Request<ListDrinksRequest>,
+    ) -> Result<Response<ListDrinksResponse>, Status> {
+        traced(Method::ListDrinks, req, |r| self.handle_list_drinks(r)).await
+    }
+
+    async fn save_drink(
+        &self,
+        req: Request<SaveDrinkRequest>,
+    ) -> Result<Response<SaveDrinkResponse>, Status> {
+        traced(Method::SaveDrink, req, |r| self.handle_save_drink(r)).await
+    }
+
+    async fn delete_drink(
+        &self,
+        req: Request<DeleteDrinkRequest>,
+    ) -> Result<Response<DeleteDrinkResponse>, Status> {
+        traced(Method::DeleteDrink, req, |r| self.handle_delete_drink(r)).await
+    }
+
+    async fn undo_last(
+        &self,
+        req: Request<UndoLastRequest>,
+    ) -> Result<Response<UndoLastResponse>, Status> {
+        traced(Method::UndoLast, req, |r| self.handle_undo_last(r)).await
+    }
+
+    type WatchCoffeesStream = mpsc::Receiver<Result<WatchCoffeesEvent, Status>>;
+
+    async fn watch_coffees(
+        &self,
+        req: Request<WatchCoffeesRequest>,
+    ) -> Result<Response<Self::WatchCoffeesStream>, Status> {
+        traced(Method::WatchCoffees, req, |r| self.handle_watch_coffees(r)).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Watching {
+        service: CoffeeService,
+        bus: CoffeeBus,
+        api_key: String,
+        user_id: i32,
+    }
+
+    async fn watching() -> Watching {
+        let db = Db::new("file::memory:").await.unwrap();
+        let api_key = db.register_user("watch@bar.com").await.unwrap().apikey;
+        let user_id = db.user_id(&api_key).await.unwrap();
+        let mut config = ServerConfig::default();
+        config.features.rate_limiting = false;
+        let bus = CoffeeBus::default();
+        let limiter = RateLimiter::from_config(&config).unwrap();
+        Watching {
+            service: CoffeeService::new(db, limiter, bus.clone(), &config),
+            bus,
+            api_key,
+            user_id,
+        }
+    }
+
+    impl Watching {
+        async fn add(&self, utc_time: i64) {
+            let req = AddCoffeeRequest {
+                api_key: self.api_key.clone(),
+                coffee: Some(CoffeeItem {
+                    utc_time,
+                    shots: 1,
+                    drink: String::new(),
+                }),
+                client_id: String::new(),
+            };
+            self.service.add_coffee(Request::new(req)).await.unwrap();
+        }
+
+        async fn watch(&self, since: i64) -> mpsc::Receiver<Result<WatchCoffeesEvent, Status>> {
+            let req = WatchCoffeesRequest {
+                api_key: self.api_key.clone(),
+                since_utc_time: since,
+            };
+            self.service
+                .watch_coffees(Request::new(req))
+                .await
+                .unwrap()
+                .into_inner()
+        }
+    }
+
+    // The time of the next coffee sent, skipping heartbeats, or None once the
+    // stream has ended.
+    async fn next_coffee(
+        rx: &mut mpsc::Receiver<Result<WatchCoffeesEvent, Status>>,
+    ) -> Option<i64> {
+        let wait = Duration::from_secs(5);
+        loop {
+            let event = tokio::time::timeout(wait, rx.recv())
+                .await
+                .expect("Nothing sent in time")?;
+            if let Some(Event::Coffee(c)) = event.unwrap().event {
+                return Some(c.utc_time);
+            }
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_watch() {
+        let w = watching().await;
+        w.add(100).await;
+        w.add(200).await;
+
+        // Coffees since the given time first, then new ones as they're added.
+        let mut rx = w.watch(150).await;
+        assert_eq!(next_coffee(&mut rx).await, Some(200));
+        let now = utc_now();
+        w.add(now).await;
+        assert_eq!(next_coffee(&mut rx).await, Some(now));
+    }
+
+    #[tokio::test]
+    pub async fn test_watch_sends_each_once() {
+        let w = watching().await;
+        w.add(100).await;
+        let mut rx = w.watch(50).await;
+        assert_eq!(next_coffee(&mut rx).await, Some(100));
+
+        // As it would be heard if it were added after subscribing but before
+        // the backfill was read, and so is in both.
+        let id = w
+            .service
+            .db
+            .get_coffee_page(&w.api_key, 0, 0, 1, 0)
+            .await
+            .unwrap()[0]
+            .id;
+        w.bus.publish(CoffeeEvent {
+            user_id: w.user_id,
+            id,
+            coffee: CoffeeItem {
+                utc_time: 100,
+                shots: 1,
+                drink: String::new(),
+            },
+        });
+        w.add(300).await;
+        assert_eq!(next_coffee(&mut rx).await, Some(300));
+    }
+
+    #[tokio::test]
+    pub async fn test_watch_ends_on_close() {
+        let w = watching().await;
+        let mut rx = w.watch(0).await;
+        w.bus.close();
+        assert_eq!(next_coffee(&mut rx).await, None);
+
+        // Watches started after closing end straight away.
+        let mut rx = w.watch(0).await;
+        assert_eq!(next_coffee(&mut rx).await, None);
+    }
 }
@@ -0,0 +1,364 @@
+// Serves the CoffeeService, and the health service alongside it, until told to
+// stop, then drains in-flight requests and closes the database so nothing the
+// client was told about gets dropped.
+
+use crate::bus::CoffeeBus;
+use crate::health::HealthService;
+use crate::metrics;
+use crate::rpc::CoffeeService;
+use coffee_common::limit::RateLimiter;
+
+use coffee_common::coffee::coffee_server::CoffeeServer;
+use coffee_common::config::ServerConfig;
+use coffee_common::db::Db;
+use coffee_common::health::health_server::HealthServer;
+
+use std::future::Future;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::stream::Stream;
+use tokio::sync::oneshot;
+use tonic::transport::server::Connected;
+use tonic::transport::{Identity, Server, ServerTlsConfig};
+use tracing::{info, warn};
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Resolves on the first SIGINT or SIGTERM.
+pub async fn shutdown_signal() {
+    let mut term = signal(SignalKind::terminate()).expect("Could not listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = term.recv() => {},
+    }
+    info!("Shutting down, draining in-flight requests");
+}
+
+/// Serves connections from `incoming` until `signal` resolves. Requests
+/// already in flight then get up to the configured shutdown timeout to finish
+/// before the database is closed.
+pub async fn run<I, IO, IE, F>(
+    config: &ServerConfig,
+    db: Db,
+    incoming: I,
+    signal: F,
+) -> Result<(), Error>
+where
+    I: Stream<Item = Result<IO, IE>>,
+    IO: AsyncRead + AsyncWrite + Connected + Unpin + Send + 'static,
+    IE: Into<Error>,
+    F: Future<Output = ()>,
+{
+    let limiter = RateLimiter::from_config(config)?;
+    let bus = CoffeeBus::default();
+    let coffee = CoffeeService::new(db.clone(), limiter, bus.clone(), config);
+
+    let mut server = Server::builder();
+    if config.tls.enabled() {
+        let cert = std::fs::read(config.tls.cert.as_ref().unwrap())?;
+        let key = std::fs::read(config.tls.key.as_ref().unwrap())?;
+        server = server.tls_config(ServerTlsConfig::new().identity(Identity::from_pem(cert, key)));
+    }
+
+    // Metrics are served on their own port, and stopped along with the rest.
+    let (metrics_stop_tx, metrics_stop_rx) = oneshot::channel::<()>();
+    let metrics_server = if config.features.metrics {
+        let addr = config.metrics_addr.parse()?;
+        let stop = async {
+            let _ = metrics_stop_rx.await;
+        };
+        Some(tokio::spawn(metrics::serve(addr, db.clone(), stop)))
+    } else {
+        None
+    };
+
+    // The drain timeout only starts once we've been asked to stop. Watches
+    // would never finish on their own, so they're ended then too.
+    let (draining_tx, draining_rx) = oneshot::channel();
+    let signal = async move {
+        signal.await;
+        let _ = draining_tx.send(());
+        bus.close();
+    };
+    let drain_timeout = Duration::from_secs(config.shutdown_timeout);
+    let drain_deadline = async move {
+        match draining_rx.await {
+            Ok(()) => tokio::time::delay_for(drain_timeout).await,
+            Err(_) => std::future::pending().await,
+        }
+    };
+
+    info!(addr = %config.addr, tls = config.tls.enabled(), "Serving");
+    let serving = server
+        .add_service(CoffeeServer::new(coffee))
+        .add_service(HealthServer::new(HealthService::new(db.clone())))
+        .serve_with_incoming_shutdown(incoming, signal);
+
+    tokio::select! {
+        res = serving => res?,
+        _ = drain_deadline => {
+            warn!(
+                timeout = config.shutdown_timeout,
+                "Requests still in flight after the shutdown timeout, stopping anyway"
+            );
+        },
+    }
+
+    let _ = metrics_stop_tx.send(());
+    if let Some(m) = metrics_server {
+        m.await??;
+    }
+
+    db.close().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use coffee_common::coffee::coffee_client::CoffeeClient;
+    use coffee_common::coffee::{AddCoffeeRequest, CoffeeItem, WatchCoffeesRequest};
+    use std::io;
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll, Waker};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::stream::StreamExt;
+
+    // Lets the test hold back everything the server writes, so its answers
+    // are kept from the client until the test lets them go. The server is
+    // told its writes went through, so it carries on as normal meanwhile.
+    #[derive(Clone, Default)]
+    struct Gate(Arc<Mutex<(bool, Vec<Waker>)>>);
+
+    impl Gate {
+        fn hold(&self) {
+            self.0.lock().unwrap().0 = true;
+        }
+
+        fn release(&self) {
+            let mut gate = self.0.lock().unwrap();
+            gate.0 = false;
+            gate.1.drain(..).for_each(Waker::wake);
+        }
+
+        // Whether writes are held, waking the caller once they aren't.
+        fn held(&self, cx: &Context<'_>) -> bool {
+            let mut gate = self.0.lock().unwrap();
+            if gate.0 {
+                gate.1.push(cx.waker().clone());
+            }
+            gate.0
+        }
+    }
+
+    struct Gated {
+        io: TcpStream,
+        gate: Gate,
+        held: Vec<u8>,
+    }
+
+    impl Gated {
+        fn poll_send_held(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            while !self.held.is_empty() {
+                let n = match Pin::new(&mut self.io).poll_write(cx, &self.held) {
+                    Poll::Ready(Ok(n)) => n,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                };
+                self.held.drain(..n);
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl Connected for Gated {
+        fn remote_addr(&self) -> Option<SocketAddr> {
+            self.io.peer_addr().ok()
+        }
+    }
+
+    impl AsyncRead for Gated {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.io).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Gated {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.gate.held(cx) {
+                self.held.extend_from_slice(buf);
+                return Poll::Ready(Ok(buf.len()));
+            }
+            match self.poll_send_held(cx) {
+                Poll::Ready(Ok(())) => Pin::new(&mut self.io).poll_write(cx, buf),
+                other => other.map(|res| res.map(|_| 0)),
+            }
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            if self.gate.held(cx) {
+                return Poll::Ready(Ok(()));
+            }
+            match self.poll_send_held(cx) {
+                Poll::Ready(Ok(())) => Pin::new(&mut self.io).poll_flush(cx),
+                other => other,
+            }
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            match self.as_mut().poll_flush(cx) {
+                Poll::Ready(Ok(())) => Pin::new(&mut self.io).poll_shutdown(cx),
+                other => other,
+            }
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_shutdown_loses_no_writes() {
+        let mut db_file = std::env::temp_dir();
+        db_file.push(format!("coffee_shutdown_test_{}", std::process::id()));
+        let db_file = db_file.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&db_file);
+
+        let db = Db::new(&db_file).await.unwrap();
+        let api_key = db.register_user("drain@test.com").await.unwrap().apikey;
+
+        let mut config = ServerConfig::default();
+        config.features.rate_limiting = false;
+        config.features.metrics = false;
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let gate = Gate::default();
+        let server_gate = gate.clone();
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let mut server = tokio::spawn(async move {
+            let stop = async {
+                let _ = stop_rx.await;
+            };
+            let incoming = listener.incoming().map(move |io| {
+                io.map(|io| Gated {
+                    io,
+                    gate: server_gate.clone(),
+                    held: Vec::new(),
+                })
+            });
+            run(&config, db, incoming, stop).await.unwrap();
+        });
+
+        let client = CoffeeClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+
+        let add = |i: i64| AddCoffeeRequest {
+            api_key: api_key.clone(),
+            coffee: Some(CoffeeItem {
+                utc_time: i,
+                shots: 1,
+                drink: String::new(),
+            }),
+            client_id: i.to_string(),
+        };
+        // The connection is only set up by the first call.
+        client.clone().add_coffee(add(1000)).await.unwrap();
+
+        // Fire off a batch of adds, with the server's answers held back so
+        // none of them can finish.
+        gate.hold();
+        let mut requests = Vec::new();
+        for i in 0..50 {
+            let mut client = client.clone();
+            let req = add(i);
+            requests.push(tokio::spawn(async move {
+                client.add_coffee(req).await.map(|_| i)
+            }));
+        }
+
+        // Give the server a moment to take them in, then tell it to stop while
+        // they're still waiting on their answers.
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        stop_tx.send(()).unwrap();
+        let waited = tokio::time::timeout(Duration::from_millis(100), &mut server).await;
+        assert!(waited.is_err(), "The server didn't wait for its answers");
+        gate.release();
+
+        let mut acknowledged = Vec::new();
+        for r in requests {
+            if let Ok(i) = r.await.unwrap() {
+                acknowledged.push(i);
+            }
+        }
+        server.await.unwrap();
+
+        // Every answer was held back until after the signal, so anything
+        // acknowledged was drained rather than cut off.
+        assert!(!acknowledged.is_empty(), "Nothing in flight was answered");
+
+        // Everything the server said it stored must be there once it's gone.
+        let db = Db::new(&db_file).await.unwrap();
+        let stored: Vec<i64> = db
+            .get_coffees(&api_key)
+            .await
+            .unwrap()
+            .iter()
+            .map(|c| c.utctime)
+            .collect();
+        for i in &acknowledged {
+            assert!(stored.contains(i), "Acknowledged coffee {} was lost", i);
+        }
+
+        db.close().await;
+        let _ = std::fs::remove_file(&db_file);
+    }
+
+    #[tokio::test]
+    pub async fn test_shutdown_ends_watches() {
+        let db = Db::new("file::memory:").await.unwrap();
+        let api_key = db.register_user("watcher@test.com").await.unwrap().apikey;
+
+        let mut config = ServerConfig::default();
+        config.features.metrics = false;
+        config.shutdown_timeout = 30;
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            let stop = async {
+                let _ = stop_rx.await;
+            };
+            run(&config, db, listener.incoming(), stop).await.unwrap();
+        });
+
+        let mut client = CoffeeClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+        let mut watch = client
+            .watch_coffees(WatchCoffeesRequest {
+                api_key,
+                since_utc_time: 0,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        watch.message().await.unwrap();
+
+        // The watch ends, rather than holding the server up for the whole
+        // shutdown timeout.
+        stop_tx.send(()).unwrap();
+        let stopped = tokio::time::timeout(Duration::from_secs(5), server).await;
+        assert!(stopped.is_ok(), "The server waited on the watch");
+    }
+}
@@ -0,0 +1,453 @@
+use crate::error::Error;
+use crate::retry::RetryPolicy;
+use crate::types::{Coffee, Drink, Undone, WatchEvent};
+
+use coffee_common::coffee::coffee_client::CoffeeClient;
+use coffee_common::coffee::{
+    AddCoffeeRequest, DeleteDrinkRequest, ListCoffeeRequest, ListDrinksRequest, RegisterRequest,
+    SaveDrinkRequest, UndoLastRequest, VerifyKeyRequest, WatchCoffeesEvent, WatchCoffeesRequest,
+};
+use coffee_common::health::health_check_response::ServingStatus;
+use coffee_common::health::health_client::HealthClient;
+use coffee_common::health::HealthCheckRequest;
+use coffee_common::logging::{self, REQUEST_ID_HEADER};
+
+use std::future::Future;
+use std::time::Duration;
+use tonic::metadata::MetadataValue;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint};
+use tonic::{Request, Status, Streaming};
+use tracing::warn;
+use uuid::Uuid;
+
+// Long enough for a slow network, short enough that a hung server doesn't
+// leave the caller waiting forever.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TlsOptions {
+    // A PEM file to trust the server's certificate with, for servers using
+    // their own CA.
+    pub ca_cert: Option<String>,
+    // The name to check the server's certificate against, if it isn't the
+    // host we connect to.
+    pub domain: Option<String>,
+}
+
+impl TlsOptions {
+    pub fn enabled(&self) -> bool {
+        self.ca_cert.is_some() || self.domain.is_some()
+    }
+}
+
+/// Where to find the server and how to talk to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Server {
+    // host:port, or a full http(s):// URL.
+    pub addr: String,
+    pub tls: TlsOptions,
+    // The deadline for connecting, and for each attempt at a call. None waits
+    // for as long as it takes.
+    pub timeout: Option<Duration>,
+    pub retry: RetryPolicy,
+}
+
+impl Server {
+    pub fn new(addr: impl Into<String>) -> Self {
+        Server {
+            addr: addr.into(),
+            tls: TlsOptions::default(),
+            timeout: Some(DEFAULT_TIMEOUT),
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// The address as a URL, https if there are TLS options and no scheme
+    /// was given.
+    pub fn url(&self) -> String {
+        if self.addr.contains("://") {
+            self.addr.clone()
+        } else if self.tls.enabled() {
+            format!("https://{}", self.addr)
+        } else {
+            format!("http://{}", self.addr)
+        }
+    }
+
+    fn endpoint(&self) -> Result<Endpoint, Error> {
+        let url = self.url();
+        let mut endpoint = Endpoint::from_shared(url.clone())
+            .map_err(|e| Error::InvalidAddress(format!("{}: {}", url, e)))?;
+        if url.starts_with("https://") {
+            let mut tls = ClientTlsConfig::new();
+            if let Some(ca) = &self.tls.ca_cert {
+                tls = tls.ca_certificate(Certificate::from_pem(std::fs::read(ca)?));
+            }
+            if let Some(domain) = &self.tls.domain {
+                tls = tls.domain_name(domain.clone());
+            }
+            endpoint = endpoint.tls_config(tls);
+        }
+        Ok(endpoint)
+    }
+}
+
+/// A connection to the server. Cheap to clone, clones share the connection,
+/// which is re-established by itself if it drops.
+#[derive(Debug, Clone)]
+pub struct Client {
+    inner: CoffeeClient<Channel>,
+    health: HealthClient<Channel>,
+    api_key: Option<String>,
+    timeout: Option<Duration>,
+    retry: RetryPolicy,
+}
+
+impl Client {
+    /// Connects to `server`, retrying as its retry policy allows.
+    pub async fn connect(server: &Server) -> Result<Self, Error> {
+        let endpoint = server.endpoint()?;
+        let mut attempt = 1;
+        let channel = loop {
+            let res = with_deadline(server.timeout, endpoint.connect()).await;
+            match res {
+                Ok(channel) => break channel,
+                // Not having connected yet, it's always safe to try again.
+                Err(e) if attempt < server.retry.max_attempts && e.is_offline() => {
+                    let wait = server.retry.wait(attempt, &Error::Timeout, true);
+                    tokio::time::delay_for(wait.unwrap_or_default()).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(gave_up(attempt, e)),
+            }
+        };
+        Ok(Client {
+            inner: CoffeeClient::new(channel.clone()),
+            health: HealthClient::new(channel),
+            api_key: None,
+            timeout: server.timeout,
+            retry: server.retry.clone(),
+        })
+    }
+
+    /// Authenticates the calls that need it with `api_key`.
+    pub fn with_api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    /// Gives each attempt at a call `timeout` rather than the server's, e.g.
+    /// after connecting with a shorter one.
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Retries calls as `retry` allows rather than the server's policy.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn api_key(&self) -> Option<&str> {
+        self.api_key.as_deref()
+    }
+
+    fn key(&self) -> Result<String, Error> {
+        self.api_key.clone().ok_or(Error::NoApiKey)
+    }
+
+    /// Registers `email`, returning its API key. The client isn't changed,
+    /// use `with_api_key` to start using the key.
+    pub async fn register(&self, email: &str) -> Result<String, Error> {
+        let msg = RegisterRequest {
+            email: email.into(),
+        };
+        let resp = self
+            .call(msg, true, |mut c, req| async move { c.register(req).await })
+            .await?;
+        if !resp.success {
+            return Err(Error::RegistrationFailed);
+        }
+        Ok(resp.api_key)
+    }
+
+    /// Checks the server knows the API key, returning the server's time in
+    /// seconds from unix epoch.
+    pub async fn verify_key(&self) -> Result<i64, Error> {
+        let msg = VerifyKeyRequest {
+            api_key: self.key()?,
+        };
+        let resp = self
+            .call(
+                msg,
+                true,
+                |mut c, req| async move { c.verify_key(req).await },
+            )
+            .await?;
+        Ok(resp.server_utc_time)
+    }
+
+    /// Asks the server's health service whether it's serving. This is for
+    /// diagnosing problems, so it's only tried the once.
+    pub async fn health(&self) -> Result<bool, Error> {
+        let mut health = self.health.clone();
+        let req = new_request(HealthCheckRequest {
+            service: String::new(),
+        });
+        let resp = with_deadline(self.timeout, health.check(req)).await?;
+        Ok(resp.into_inner().status == ServingStatus::Serving as i32)
+    }
+
+    /// Logs a coffee. Adds with the same `client_id` are only stored once, so
+    /// an add that may or may not have got through can safely be retried. One
+    /// is made up if it isn't given, which covers the retries made here.
+    pub async fn add_coffee(&self, coffee: &Coffee, client_id: Option<&str>) -> Result<(), Error> {
+        // The id is what makes retrying safe, so there's always one.
+        let client_id = match client_id {
+            Some(id) => id.to_string(),
+            None => Uuid::new_v4().to_string(),
+        };
+        let msg = AddCoffeeRequest {
+            api_key: self.key()?,
+            coffee: Some(coffee.into()),
+            client_id,
+        };
+        let resp = self
+            .call(
+                msg,
+                true,
+                |mut c, req| async move { c.add_coffee(req).await },
+            )
+            .await?;
+        if !resp.success {
+            return Err(Error::AddFailed);
+        }
+        Ok(())
+    }
+
+    /// Coffees logged from `start` up to but not including `end`, in seconds
+    /// from unix epoch, where 0 leaves that end open. Oldest first.
+    pub async fn list_coffees(&self, start: i64, end: i64) -> Result<Vec<Coffee>, Error> {
+        let msg = ListCoffeeRequest {
+            api_key: self.key()?,
+            start_utc_time: start,
+            end_utc_time: end,
+        };
+        let resp = self
+            .call(
+                msg,
+                true,
+                |mut c, req| async move { c.list_coffee(req).await },
+            )
+            .await?;
+        Ok(resp.coffees.into_iter().map(Coffee::from).collect())
+    }
+
+    /// The user's drinks along with the defaults they haven't replaced, by
+    /// name.
+    pub async fn list_drinks(&self) -> Result<Vec<Drink>, Error> {
+        let msg = ListDrinksRequest {
+            api_key: self.key()?,
+        };
+        let resp = self
+            .call(
+                msg,
+                true,
+                |mut c, req| async move { c.list_drinks(req).await },
+            )
+            .await?;
+        Ok(resp.drinks.into_iter().map(Drink::from).collect())
+    }
+
+    /// Looks up a drink by name, ignoring case.
+    pub async fn find_drink(&self, name: &str) -> Result<Option<Drink>, Error> {
+        let name = name.to_lowercase();
+        Ok(self
+            .list_drinks()
+            .await?
+            .into_iter()
+            .find(|d| d.name == name))
+    }
+
+    /// Adds or replaces one of the user's drinks.
+    pub async fn save_drink(&self, drink: &Drink) -> Result<(), Error> {
+        let msg = SaveDrinkRequest {
+            api_key: self.key()?,
+            drink: Some(drink.into()),
+        };
+        self.call(
+            msg,
+            // Each one is a change `undo_last` can take back, so a repeat
+            // isn't harmless.
+            false,
+            |mut c, req| async move { c.save_drink(req).await },
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Removes one of the user's drinks, returning whether they had one.
+    pub async fn delete_drink(&self, name: &str) -> Result<bool, Error> {
+        let msg = DeleteDrinkRequest {
+            api_key: self.key()?,
+            name: name.into(),
+        };
+        let resp = self
+            .call(
+                msg,
+                false,
+                |mut c, req| async move { c.delete_drink(req).await },
+            )
+            .await?;
+        Ok(resp.deleted)
+    }
+
+    /// Takes back the user's most recent change. The server refuses with
+    /// NotFound when there's nothing recent enough, and FailedPrecondition
+    /// when what it changed has changed again since.
+    pub async fn undo_last(&self) -> Result<Undone, Error> {
+        let msg = UndoLastRequest {
+            api_key: self.key()?,
+        };
+        let resp = self
+            .call(
+                msg,
+                false,
+                |mut c, req| async move { c.undo_last(req).await },
+            )
+            .await?;
+        Ok(resp.into())
+    }
+
+    /// Follows coffees as they're logged, starting with any logged at or
+    /// after `since` (0 for only new ones).
+    pub async fn watch(&self, since: i64) -> Result<Watch, Error> {
+        let msg = WatchCoffeesRequest {
+            api_key: self.key()?,
+            since_utc_time: since,
+        };
+        let stream = self
+            .call(
+                msg,
+                true,
+                |mut c, req| async move { c.watch_coffees(req).await },
+            )
+            .await?;
+        Ok(Watch { stream })
+    }
+
+    // Makes a call tagged with a fresh request id, so it can be found in the
+    // server's logs, retrying as the policy allows. Calls that aren't
+    // `idempotent` are only retried when the server didn't act on them.
+    async fn call<M, T, F, Fut>(&self, msg: M, idempotent: bool, rpc: F) -> Result<T, Error>
+    where
+        M: Clone,
+        F: Fn(CoffeeClient<Channel>, Request<M>) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<T>, Status>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let res = with_deadline(
+                self.timeout,
+                rpc(self.inner.clone(), new_request(msg.clone())),
+            )
+            .await;
+            let e = match res {
+                Ok(resp) => return Ok(resp.into_inner()),
+                Err(e) => e,
+            };
+            match self.retry.wait(attempt, &e, idempotent) {
+                Some(wait) => {
+                    warn!(
+                        attempt,
+                        wait_secs = wait.as_secs_f64(),
+                        error = %e,
+                        "Call failed, retrying"
+                    );
+                    tokio::time::delay_for(wait).await;
+                    attempt += 1;
+                }
+                None => return Err(gave_up(attempt, e)),
+            }
+        }
+    }
+}
+
+// Runs `fut`, failing with Error::Timeout if it takes longer than `timeout`.
+async fn with_deadline<T, E, Fut>(timeout: Option<Duration>, fut: Fut) -> Result<T, Error>
+where
+    Fut: Future<Output = Result<T, E>>,
+    Error: From<E>,
+{
+    match timeout {
+        Some(t) => match tokio::time::timeout(t, fut).await {
+            Ok(res) => Ok(res?),
+            Err(_) => Err(Error::Timeout),
+        },
+        None => Ok(fut.await?),
+    }
+}
+
+// The error to finish with after `attempts` tries, saying how many there were
+// if there was more than one.
+fn gave_up(attempts: u32, e: Error) -> Error {
+    if attempts > 1 {
+        Error::GaveUp {
+            attempts,
+            last: Box::new(e),
+        }
+    } else {
+        e
+    }
+}
+
+fn new_request<T>(msg: T) -> Request<T> {
+    let mut req = Request::new(msg);
+    if let Ok(id) = MetadataValue::from_str(&logging::new_request_id()) {
+        req.metadata_mut().insert(REQUEST_ID_HEADER, id);
+    }
+    req
+}
+
+/// The stream of events from `Client::watch`.
+pub struct Watch {
+    stream: Streaming<WatchCoffeesEvent>,
+}
+
+impl Watch {
+    /// The next event, or None once the server ends the stream.
+    pub async fn next(&mut self) -> Result<Option<WatchEvent>, Error> {
+        loop {
+            match self.stream.message().await? {
+                Some(e) => {
+                    if let Some(event) = WatchEvent::from_proto(e) {
+                        return Ok(Some(event));
+                    }
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_url() {
+        let mut server = Server::new("localhost:50051");
+        assert_eq!(server.url(), "http://localhost:50051");
+        server.tls.domain = Some("coffee.example.com".into());
+        assert_eq!(server.url(), "https://localhost:50051");
+        server.addr = "http://localhost:50051".into();
+        assert_eq!(server.url(), "http://localhost:50051");
+    }
+
+    #[test]
+    pub fn test_bad_address() {
+        let server = Server::new("not a host");
+        assert!(matches!(server.endpoint(), Err(Error::InvalidAddress(_))));
+    }
+}
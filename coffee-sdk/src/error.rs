@@ -0,0 +1,89 @@
+use tonic::Code;
+
+#[derive(Debug)]
+pub enum Error {
+    // The call needs an API key and the client wasn't given one.
+    NoApiKey,
+    InvalidAddress(String),
+    // Reading the CA certificate failed.
+    Io(std::io::Error),
+    // The server couldn't be reached in time.
+    Timeout,
+    // The server answered, but said it didn't register or add anything.
+    RegistrationFailed,
+    AddFailed,
+    Status(tonic::Status),
+    Transport(tonic::transport::Error),
+    // Retried until the retry policy ran out, with the last attempt's error.
+    GaveUp { attempts: u32, last: Box<Error> },
+}
+
+impl Error {
+    /// The gRPC status code the server answered with, if it got that far.
+    pub fn code(&self) -> Option<Code> {
+        match self {
+            Error::Status(s) => Some(s.code()),
+            Error::GaveUp { last, .. } => last.code(),
+            _ => None,
+        }
+    }
+
+    /// Whether the error just means the server couldn't be reached right now,
+    /// so trying again later might work.
+    pub fn is_offline(&self) -> bool {
+        match self {
+            Error::Transport(_) | Error::Timeout => true,
+            Error::Status(s) => s.code() == Code::Unavailable || s.code() == Code::Unknown,
+            Error::GaveUp { last, .. } => last.is_offline(),
+            _ => false,
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Status(e) => Some(e),
+            Error::Transport(e) => Some(e),
+            Error::GaveUp { last, .. } => Some(last.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        match self {
+            Error::NoApiKey => write!(f, "no API key was given"),
+            Error::InvalidAddress(e) => write!(f, "invalid server address {}", e),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Timeout => write!(f, "the server took too long to answer"),
+            Error::RegistrationFailed => write!(f, "the server didn't register the email"),
+            Error::AddFailed => write!(f, "the server didn't add the coffee"),
+            Error::Status(s) => write!(f, "{} ({:?})", s.message(), s.code()),
+            Error::Transport(e) => write!(f, "could not reach the server: {}", e),
+            Error::GaveUp { attempts, last } => {
+                write!(f, "gave up after {} attempts: {}", attempts, last)
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<tonic::Status> for Error {
+    fn from(e: tonic::Status) -> Self {
+        Error::Status(e)
+    }
+}
+
+impl From<tonic::transport::Error> for Error {
+    fn from(e: tonic::transport::Error) -> Self {
+        Error::Transport(e)
+    }
+}
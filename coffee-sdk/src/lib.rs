@@ -0,0 +1,34 @@
+//! A typed async client for the coffee server, for anything that wants to
+//! log or read coffees without going through the CLI.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), coffee_sdk::Error> {
+//! use coffee_sdk::{Client, Coffee, Server};
+//!
+//! let server = Server::new("coffee.example.com:50051");
+//! let client = Client::connect(&server).await?.with_api_key("<API KEY>");
+//! client
+//!     .add_coffee(&Coffee::new(1_591_689_600, 2), None)
+//!     .await?;
+//! for c in client.list_coffees(0, 0).await? {
+//!     println!("{}: {}", c.utc_time, c.shots);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+mod client;
+mod error;
+mod retry;
+mod types;
+
+pub use client::{Client, Server, TlsOptions, Watch};
+pub use error::Error;
+pub use retry::RetryPolicy;
+pub use types::{Change, Coffee, Drink, Undone, WatchEvent};
+
+// So callers can check status codes without depending on tonic themselves.
+pub use tonic::Code;
+// How far into the future the server lets a coffee be logged, to allow for
+// clocks that don't quite agree.
+pub use coffee_common::db::FUTURE_TOLERANCE_SECS;
@@ -0,0 +1,122 @@
+// When and how long to wait before trying a failed call again.
+
+use crate::error::Error;
+
+use coffee_common::retry;
+use rand::Rng;
+use std::time::Duration;
+use tonic::Code;
+
+// The longest we'll wait when the server asks us to slow down. Anything
+// longer is given up on straight away.
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(30);
+
+/// How calls that fail in a way worth retrying are retried. Waits grow
+/// exponentially from `initial_backoff` up to `max_backoff`, with jitter so a
+/// crowd of clients don't all come back at once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    // Attempts in all, including the first, so 1 never retries.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn never() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    // The most we'll wait after the `attempt`th attempt (counting from 1).
+    fn ceiling(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |d| d.min(self.max_backoff))
+    }
+
+    /// How long to wait after the `attempt`th attempt failed with `e`, or None
+    /// if it shouldn't be tried again. Calls that aren't `idempotent` are only
+    /// retried when the server turned them away without acting on them.
+    pub(crate) fn wait(&self, attempt: u32, e: &Error, idempotent: bool) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        let status = match e {
+            Error::Status(s) => s,
+            Error::Timeout if idempotent => return Some(self.jittered(attempt)),
+            _ => return None,
+        };
+        match status.code() {
+            // Rate limited, with the server's say on how long for.
+            Code::ResourceExhausted => match retry::retry_after(status) {
+                Some(wait) if wait <= MAX_RATE_LIMIT_WAIT => Some(wait),
+                Some(_) => None,
+                None => Some(self.jittered(attempt)),
+            },
+            // We can't tell whether these got as far as the server.
+            Code::Unavailable | Code::Unknown | Code::DeadlineExceeded | Code::Aborted
+                if idempotent =>
+            {
+                Some(self.jittered(attempt))
+            }
+            _ => None,
+        }
+    }
+
+    // Anywhere from nothing up to the ceiling, a.k.a. "full jitter".
+    fn jittered(&self, attempt: u32) -> Duration {
+        self.ceiling(attempt)
+            .mul_f64(rand::thread_rng().gen_range(0.0, 1.0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tonic::Status;
+
+    #[test]
+    pub fn test_backoff() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.ceiling(1), Duration::from_millis(200));
+        assert_eq!(policy.ceiling(3), Duration::from_millis(800));
+        assert_eq!(policy.ceiling(10), Duration::from_secs(5));
+        assert_eq!(policy.ceiling(100), Duration::from_secs(5));
+        for attempt in 1..4 {
+            let wait = policy.jittered(attempt);
+            assert!(wait <= policy.ceiling(attempt));
+        }
+    }
+
+    #[test]
+    pub fn test_what_is_retried() {
+        let policy = RetryPolicy::default();
+        let unavailable = Error::Status(Status::unavailable("down"));
+        assert!(policy.wait(1, &unavailable, true).is_some());
+        assert!(policy.wait(1, &unavailable, false).is_none());
+        assert!(policy.wait(4, &unavailable, true).is_none());
+        assert!(policy.wait(1, &Error::Timeout, true).is_some());
+
+        // Being rate limited is always safe to retry, as nothing was done.
+        let limited = Error::Status(Status::resource_exhausted("slow down"));
+        assert!(policy.wait(1, &limited, false).is_some());
+
+        let bad = Error::Status(Status::invalid_argument("no"));
+        assert!(policy.wait(1, &bad, true).is_none());
+        assert!(RetryPolicy::never().wait(1, &unavailable, true).is_none());
+    }
+}
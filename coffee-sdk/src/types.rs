@@ -0,0 +1,183 @@
+// The SDK's own view of what the server sends, so callers never have to deal
+// with the prost messages and their 0-means-missing fields.
+
+use coffee_common::coffee::undo_last_response::Action;
+use coffee_common::coffee::watch_coffees_event::Event;
+use coffee_common::coffee::{self as proto};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Coffee {
+    // Seconds from unix epoch.
+    pub utc_time: i64,
+    pub shots: i32,
+    // The drink it was added as, if any.
+    pub drink: Option<String>,
+}
+
+impl Coffee {
+    pub fn new(utc_time: i64, shots: i32) -> Self {
+        Coffee {
+            utc_time,
+            shots,
+            drink: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Drink {
+    pub name: String,
+    pub shots: i32,
+    pub caffeine_mg: Option<i32>,
+    pub size_ml: Option<i32>,
+    pub price_cents: Option<i32>,
+    // One of the server's defaults rather than the user's own.
+    pub global: bool,
+}
+
+// A change taken back by `Client::undo_last`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    AddCoffee(Coffee),
+    // The coffee as it's been put back to.
+    UpdateCoffee(Coffee),
+    DeleteCoffee(Coffee),
+    SaveDrink(String),
+    DeleteDrink(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Undone {
+    pub change: Change,
+    // When the change was made, in seconds from unix epoch.
+    pub utc_time: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WatchEvent {
+    Coffee(Coffee),
+    // Sent every so often so a quiet stream can be told from a dead one.
+    Heartbeat { server_utc_time: i64 },
+}
+
+// The server sends 0 for amounts it doesn't know, and "" for no drink.
+fn known(v: i32) -> Option<i32> {
+    Some(v).filter(|v| *v != 0)
+}
+
+fn named(s: String) -> Option<String> {
+    Some(s).filter(|s| !s.is_empty())
+}
+
+impl From<proto::CoffeeItem> for Coffee {
+    fn from(c: proto::CoffeeItem) -> Self {
+        Coffee {
+            utc_time: c.utc_time,
+            shots: c.shots,
+            drink: named(c.drink),
+        }
+    }
+}
+
+impl From<&Coffee> for proto::CoffeeItem {
+    fn from(c: &Coffee) -> Self {
+        proto::CoffeeItem {
+            utc_time: c.utc_time,
+            shots: c.shots,
+            drink: c.drink.clone().unwrap_or_default(),
+        }
+    }
+}
+
+impl From<proto::Drink> for Drink {
+    fn from(d: proto::Drink) -> Self {
+        Drink {
+            name: d.name,
+            shots: d.shots,
+            caffeine_mg: known(d.caffeine_mg),
+            size_ml: known(d.size_ml),
+            price_cents: known(d.price_cents),
+            global: d.global,
+        }
+    }
+}
+
+impl From<&Drink> for proto::Drink {
+    fn from(d: &Drink) -> Self {
+        proto::Drink {
+            name: d.name.clone(),
+            shots: d.shots,
+            caffeine_mg: d.caffeine_mg.unwrap_or_default(),
+            size_ml: d.size_ml.unwrap_or_default(),
+            price_cents: d.price_cents.unwrap_or_default(),
+            global: d.global,
+        }
+    }
+}
+
+impl From<proto::UndoLastResponse> for Undone {
+    fn from(r: proto::UndoLastResponse) -> Self {
+        let change = match r.action() {
+            Action::AddCoffee => Change::AddCoffee(r.coffee.unwrap_or_default().into()),
+            Action::UpdateCoffee => Change::UpdateCoffee(r.coffee.unwrap_or_default().into()),
+            Action::DeleteCoffee => Change::DeleteCoffee(r.coffee.unwrap_or_default().into()),
+            Action::SaveDrink => Change::SaveDrink(r.drink),
+            Action::DeleteDrink => Change::DeleteDrink(r.drink),
+        };
+        Undone {
+            change,
+            utc_time: r.utc_time,
+        }
+    }
+}
+
+impl WatchEvent {
+    // Events this version doesn't know about come out as None.
+    pub(crate) fn from_proto(e: proto::WatchCoffeesEvent) -> Option<Self> {
+        match e.event? {
+            Event::Coffee(c) => Some(WatchEvent::Coffee(c.into())),
+            Event::Heartbeat(h) => Some(WatchEvent::Heartbeat {
+                server_utc_time: h.server_utc_time,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_conversions() {
+        let c = Coffee::new(1000, 2);
+        let item = proto::CoffeeItem::from(&c);
+        assert_eq!(item.drink, "");
+        assert_eq!(Coffee::from(item), c);
+
+        let d = Drink {
+            name: "mocha".into(),
+            shots: 2,
+            caffeine_mg: None,
+            size_ml: Some(300),
+            price_cents: None,
+            global: false,
+        };
+        let msg = proto::Drink::from(&d);
+        assert_eq!((msg.caffeine_mg, msg.size_ml), (0, 300));
+        assert_eq!(Drink::from(msg), d);
+
+        let resp = proto::UndoLastResponse {
+            action: Action::DeleteDrink as i32,
+            utc_time: 10,
+            coffee: None,
+            drink: "mocha".into(),
+        };
+        assert_eq!(
+            Undone::from(resp),
+            Undone {
+                change: Change::DeleteDrink("mocha".into()),
+                utc_time: 10
+            }
+        );
+    }
+}
@@ -0,0 +1,887 @@
+// The JSON API under /api/v1, the coffee service for scripts and browser code
+// that can't speak gRPC. Everything but registering needs the API key as a
+// bearer token, and any error comes back as
+// {"error": {"code": "not_found", "message": "No such coffee"}}. Its routes
+// are described by the OpenAPI document in openapi.rs.
+
+use crate::chart;
+use crate::openapi::{doc_lines, documented, Endpoint};
+use coffee_common::config::Features;
+use coffee_common::db::{self, Coffee, Db, DbError, StoredCoffee};
+use coffee_common::limit::{self, Caller, Limited, RateLimiter};
+
+use actix_web::dev::Payload;
+use actix_web::error::{JsonPayloadError, PathError, QueryPayloadError};
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse, ResponseError, Scope};
+use chrono::{NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::{ready, Ready};
+use std::time::Duration;
+use tracing::warn;
+use uuid::Uuid;
+
+pub static PREFIX: &str = "/api/v1";
+
+// How many coffees a page has unless asked for fewer, and the most it can.
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+
+/// An error as the API reports it, with a status and a code to match on.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    // Sent as Retry-After, for rate limiting.
+    retry_after: Option<Duration>,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    pub fn invalid_argument(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::BAD_REQUEST, "invalid_argument", message)
+    }
+
+    pub fn unauthenticated(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::UNAUTHORIZED, "unauthenticated", message)
+    }
+
+    pub fn permission_denied(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::FORBIDDEN, "permission_denied", message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    pub fn method_not_allowed() -> Self {
+        ApiError::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method_not_allowed",
+            "That method isn't allowed here",
+        )
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", message)
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut resp = HttpResponse::build(self.status);
+        if self.status == StatusCode::UNAUTHORIZED {
+            resp.header(header::WWW_AUTHENTICATE, "Bearer");
+        }
+        if let Some(wait) = self.retry_after {
+            resp.header(header::RETRY_AFTER, retry_after_secs(wait));
+        }
+        resp.json(ErrorBody {
+            error: ErrorDetail {
+                code: self.code.into(),
+                message: self.message.clone(),
+            },
+        })
+    }
+}
+
+impl From<DbError> for ApiError {
+    fn from(e: DbError) -> Self {
+        match e {
+            DbError::UnknownApiKey => ApiError::unauthenticated("Unknown API key"),
+            _ => {
+                warn!(error = %e, "Database error");
+                ApiError::internal("Internal database error")
+            }
+        }
+    }
+}
+
+impl From<Limited> for ApiError {
+    fn from(l: Limited) -> Self {
+        ApiError {
+            retry_after: Some(l.retry_after),
+            ..ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "resource_exhausted",
+                l.to_string(),
+            )
+        }
+    }
+}
+
+/// The API key from an `Authorization: Bearer <API KEY>` header.
+pub struct ApiKey(String);
+
+impl FromRequest for ApiKey {
+    type Config = ();
+    type Error = ApiError;
+    type Future = Ready<Result<Self, ApiError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(bearer_token(req).map(ApiKey).ok_or_else(|| {
+            ApiError::unauthenticated("No API key, send it as `Authorization: Bearer <API KEY>`")
+        }))
+    }
+}
+
+pub(crate) fn bearer_token(req: &HttpRequest) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    let token = token.trim();
+    if !scheme.eq_ignore_ascii_case("bearer") || token.is_empty() {
+        return None;
+    }
+    Some(token.into())
+}
+
+documented! {
+    /// A coffee as the API shows it.
+    #[derive(Debug, Serialize)]
+    pub struct CoffeeBody {
+        pub id: i64,
+        /// When it was had, in seconds from the unix epoch.
+        pub utc_time: i64,
+        pub shots: i32,
+        /// The drink it was, if it was added as one.
+        pub drink: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct RegisterRequest {
+        pub email: String,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct RegisterResponse {
+        pub api_key: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct NewCoffee {
+        pub shots: i32,
+        /// Seconds from the unix epoch, now if not given.
+        pub utc_time: Option<i64>,
+        pub drink: Option<String>,
+        /// Sending the same id again won't add the coffee twice.
+        pub client_id: Option<String>,
+    }
+
+    /// The parts of a coffee to change.
+    #[derive(Debug, Deserialize)]
+    pub struct CoffeeChanges {
+        pub shots: Option<i32>,
+        pub utc_time: Option<i64>,
+        /// An empty name takes the drink away.
+        pub drink: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct RangeQuery {
+        /// Seconds from the unix epoch, from the start of time if not given.
+        pub start: Option<i64>,
+        /// Seconds from the unix epoch, not included, with no end if 0 or not given.
+        pub end: Option<i64>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct ListQuery {
+        /// Seconds from the unix epoch, from the start of time if not given.
+        pub start: Option<i64>,
+        /// Seconds from the unix epoch, not included, with no end if 0 or not given.
+        pub end: Option<i64>,
+        /// How many coffees a page has, 100 unless given and at most 1000.
+        pub limit: Option<i64>,
+        /// How many coffees to skip, from the last page's `next_offset`.
+        pub offset: Option<i64>,
+    }
+
+    /// Coffees in the order they were had.
+    #[derive(Debug, Serialize)]
+    pub struct CoffeePage {
+        pub coffees: Vec<CoffeeBody>,
+        /// Where the next page starts, or null on the last.
+        pub next_offset: Option<i64>,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct Stats {
+        pub coffees: usize,
+        pub shots: i64,
+        /// Each day from the first coffee to the end of the range, or today.
+        pub days: Vec<DayStats>,
+    }
+
+    /// Shots on one UTC day.
+    #[derive(Debug, Serialize)]
+    pub struct DayStats {
+        /// As YYYY-MM-DD.
+        pub date: String,
+        pub shots: i64,
+    }
+
+    /// What every error looks like.
+    #[derive(Debug, Serialize)]
+    pub struct ErrorBody {
+        pub error: ErrorDetail,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct ErrorDetail {
+        /// One of invalid_argument, unauthenticated, permission_denied,
+        /// not_found, method_not_allowed, resource_exhausted or internal.
+        pub code: String,
+        pub message: String,
+    }
+}
+
+impl From<StoredCoffee> for CoffeeBody {
+    fn from(c: StoredCoffee) -> Self {
+        CoffeeBody {
+            id: c.id,
+            utc_time: c.utctime,
+            shots: c.shots,
+            drink: c.drink,
+        }
+    }
+}
+
+fn utc_now() -> i64 {
+    Utc::now().timestamp()
+}
+
+// Whole seconds to send as Retry-After, rounded up so a retry isn't turned
+// away again.
+pub fn retry_after_secs(wait: Duration) -> u64 {
+    wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+}
+
+pub fn peer(req: &HttpRequest) -> Caller {
+    Caller::Peer(
+        req.peer_addr()
+            .map(|a| a.ip().to_string())
+            .unwrap_or_default(),
+    )
+}
+
+// Takes a token for the key's user, or for the peer if it isn't a key, the
+// same as the RPC server does.
+async fn check_limit(
+    limiter: &RateLimiter,
+    db: &Db,
+    req: &HttpRequest,
+    method: limit::Method,
+    key: &ApiKey,
+) -> Result<(), ApiError> {
+    let caller = match db.user_id(&key.0).await {
+        Ok(id) => Caller::User(id),
+        Err(DbError::UnknownApiKey) => peer(req),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(limiter.check(method, &caller)?)
+}
+
+async fn register(
+    db: web::Data<Db>,
+    features: web::Data<Features>,
+    limiter: web::Data<RateLimiter>,
+    req: HttpRequest,
+    body: web::Json<RegisterRequest>,
+) -> Result<HttpResponse, ApiError> {
+    if !features.registration {
+        return Err(ApiError::permission_denied(
+            "Registration is disabled on this server",
+        ));
+    }
+    // There's no API key yet, so registrations are limited per peer.
+    limiter.check(limit::Method::Register, &peer(&req))?;
+    if body.email.is_empty() {
+        return Err(ApiError::invalid_argument("No email provided"));
+    }
+    let user = db.register_user(&body.email).await?;
+    Ok(HttpResponse::Ok().json(RegisterResponse {
+        api_key: user.apikey,
+    }))
+}
+
+async fn add_coffee(
+    db: web::Data<Db>,
+    limiter: web::Data<RateLimiter>,
+    req: HttpRequest,
+    key: ApiKey,
+    body: web::Json<NewCoffee>,
+) -> Result<HttpResponse, ApiError> {
+    check_limit(&limiter, &db, &req, limit::Method::AddCoffee, &key).await?;
+    let body = body.into_inner();
+    let coffee = Coffee {
+        shots: body.shots,
+        utctime: body.utc_time.unwrap_or_else(utc_now),
+        drink: body.drink.filter(|d| !d.is_empty()),
+    };
+    coffee
+        .validate(utc_now())
+        .map_err(ApiError::invalid_argument)?;
+
+    // The coffee is found again by its client id, so it needs one either way.
+    let client_id = body
+        .client_id
+        .filter(|id| !id.is_empty())
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let added = db.add_coffee(&key.0, &coffee, Some(&client_id)).await?;
+    let stored = db
+        .find_coffee(&key.0, &client_id)
+        .await?
+        .ok_or_else(|| ApiError::internal("The coffee wasn't stored"))?;
+    // A retry gets back what the first try stored.
+    let mut resp = if added.is_some() {
+        HttpResponse::Created()
+    } else {
+        HttpResponse::Ok()
+    };
+    Ok(resp.json(CoffeeBody::from(stored)))
+}
+
+async fn list_coffees(
+    db: web::Data<Db>,
+    limiter: web::Data<RateLimiter>,
+    req: HttpRequest,
+    key: ApiKey,
+    query: web::Query<ListQuery>,
+) -> Result<HttpResponse, ApiError> {
+    check_limit(&limiter, &db, &req, limit::Method::ListCoffee, &key).await?;
+    let (start, end) = (query.start.unwrap_or(0), query.end.unwrap_or(0));
+    db::validate_range(start, end).map_err(ApiError::invalid_argument)?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    let offset = query.offset.unwrap_or(0);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(ApiError::invalid_argument(format!(
+            "The limit must be from 1 to {}",
+            MAX_LIMIT
+        )));
+    }
+    if offset < 0 {
+        return Err(ApiError::invalid_argument("The offset can't be negative"));
+    }
+
+    // One more than asked for says whether there's another page.
+    let mut coffees = db
+        .get_coffee_page(&key.0, start, end, limit + 1, offset)
+        .await?;
+    let next_offset = if coffees.len() as i64 > limit {
+        coffees.truncate(limit as usize);
+        Some(offset + limit)
+    } else {
+        None
+    };
+    Ok(HttpResponse::Ok().json(CoffeePage {
+        coffees: coffees.into_iter().map(CoffeeBody::from).collect(),
+        next_offset,
+    }))
+}
+
+async fn stats(
+    db: web::Data<Db>,
+    limiter: web::Data<RateLimiter>,
+    req: HttpRequest,
+    key: ApiKey,
+    query: web::Query<RangeQuery>,
+) -> Result<HttpResponse, ApiError> {
+    check_limit(&limiter, &db, &req, limit::Method::ListCoffee, &key).await?;
+    let (start, end) = (query.start.unwrap_or(0), query.end.unwrap_or(0));
+    db::validate_range(start, end).map_err(ApiError::invalid_argument)?;
+    let coffees = db.get_coffees_between(&key.0, start, end).await?;
+
+    // Days run from the first coffee to the end of the range, or today if
+    // that's sooner.
+    let now = utc_now();
+    let last = if end == 0 || end > now { now } else { end - 1 };
+    let last = NaiveDateTime::from_timestamp_opt(last, 0)
+        .ok_or_else(|| ApiError::invalid_argument("The end time is out of range"))?
+        .date();
+    let days = chart::days(&coffees, None, last);
+    Ok(HttpResponse::Ok().json(Stats {
+        coffees: coffees.len(),
+        shots: coffees.iter().map(|c| i64::from(c.shots)).sum(),
+        days: days
+            .into_iter()
+            .map(|d| DayStats {
+                date: d.date.to_string(),
+                shots: d.shots,
+            })
+            .collect(),
+    }))
+}
+
+async fn update_coffee(
+    db: web::Data<Db>,
+    limiter: web::Data<RateLimiter>,
+    req: HttpRequest,
+    key: ApiKey,
+    id: web::Path<i64>,
+    body: web::Json<CoffeeChanges>,
+) -> Result<HttpResponse, ApiError> {
+    check_limit(&limiter, &db, &req, limit::Method::UpdateCoffee, &key).await?;
+    let id = id.into_inner();
+    let mut stored = db
+        .get_coffee(&key.0, id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("No such coffee"))?;
+    let changes = body.into_inner();
+    if let Some(shots) = changes.shots {
+        stored.shots = shots;
+    }
+    if let Some(utc_time) = changes.utc_time {
+        stored.utctime = utc_time;
+    }
+    if let Some(drink) = changes.drink {
+        stored.drink = Some(drink).filter(|d| !d.is_empty());
+    }
+    let coffee = stored.coffee();
+    coffee
+        .validate(utc_now())
+        .map_err(ApiError::invalid_argument)?;
+
+    if !db.update_coffee(&key.0, id, &coffee).await? {
+        return Err(ApiError::not_found("No such coffee"));
+    }
+    Ok(HttpResponse::Ok().json(CoffeeBody::from(stored)))
+}
+
+async fn delete_coffee(
+    db: web::Data<Db>,
+    limiter: web::Data<RateLimiter>,
+    req: HttpRequest,
+    key: ApiKey,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    check_limit(&limiter, &db, &req, limit::Method::DeleteCoffee, &key).await?;
+    if !db.delete_coffee(&key.0, id.into_inner()).await? {
+        return Err(ApiError::not_found("No such coffee"));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn method_not_allowed() -> Result<HttpResponse, ApiError> {
+    Err(ApiError::method_not_allowed())
+}
+
+async fn no_such_endpoint() -> Result<HttpResponse, ApiError> {
+    Err(ApiError::not_found("No such endpoint"))
+}
+
+// Bodies, queries and paths that don't parse get the same error bodies as
+// everything else.
+fn json_error(e: JsonPayloadError, _: &HttpRequest) -> actix_web::Error {
+    ApiError::invalid_argument(format!("Invalid JSON body: {}", e)).into()
+}
+
+fn query_error(e: QueryPayloadError, _: &HttpRequest) -> actix_web::Error {
+    ApiError::invalid_argument(format!("Invalid query: {}", e)).into()
+}
+
+fn path_error(_: PathError, _: &HttpRequest) -> actix_web::Error {
+    ApiError::not_found("No such coffee").into()
+}
+
+/// The API's routes, which are both what's served and what the OpenAPI
+/// document describes.
+pub fn endpoints() -> Vec<Endpoint> {
+    let bad_request = "The request doesn't make sense";
+    vec![
+        Endpoint::new(
+            Method::POST,
+            "/register",
+            "register",
+            "Register an email address",
+            register,
+        )
+        .limited()
+        .body::<RegisterRequest>()
+        .response::<RegisterResponse>(StatusCode::OK, "The address's API key")
+        .error(StatusCode::BAD_REQUEST, bad_request)
+        .error(StatusCode::FORBIDDEN, "Registration is disabled"),
+        Endpoint::new(
+            Method::POST,
+            "/coffees",
+            "add_coffee",
+            "Add a coffee",
+            add_coffee,
+        )
+        .limited()
+        .auth()
+        .body::<NewCoffee>()
+        .response::<CoffeeBody>(StatusCode::CREATED, "The coffee as it was stored")
+        .response::<CoffeeBody>(
+            StatusCode::OK,
+            "A retry, with the coffee the first try stored",
+        )
+        .error(StatusCode::BAD_REQUEST, bad_request),
+        Endpoint::new(
+            Method::GET,
+            "/coffees",
+            "list_coffees",
+            "List coffees a page at a time",
+            list_coffees,
+        )
+        .limited()
+        .auth()
+        .query::<ListQuery>()
+        .response::<CoffeePage>(StatusCode::OK, "A page of coffees")
+        .error(StatusCode::BAD_REQUEST, bad_request),
+        Endpoint::new(
+            Method::PATCH,
+            "/coffees/{id}",
+            "update_coffee",
+            "Change a coffee",
+            update_coffee,
+        )
+        .limited()
+        .auth()
+        .path_param::<i64>("id", "The coffee's id")
+        .body::<CoffeeChanges>()
+        .response::<CoffeeBody>(StatusCode::OK, "The coffee as it is now")
+        .error(StatusCode::BAD_REQUEST, bad_request)
+        .error(StatusCode::NOT_FOUND, "There's no such coffee of yours"),
+        Endpoint::new(
+            Method::DELETE,
+            "/coffees/{id}",
+            "delete_coffee",
+            "Delete a coffee",
+            delete_coffee,
+        )
+        .limited()
+        .auth()
+        .path_param::<i64>("id", "The coffee's id")
+        .empty_response(StatusCode::NO_CONTENT, "The coffee is gone")
+        .error(StatusCode::NOT_FOUND, "There's no such coffee of yours"),
+        Endpoint::new(Method::GET, "/stats", "stats", "Shots each day", stats)
+            .limited()
+            .auth()
+            .query::<RangeQuery>()
+            .response::<Stats>(
+                StatusCode::OK,
+                "Totals over the range, and each day's shots",
+            )
+            .error(StatusCode::BAD_REQUEST, bad_request),
+    ]
+}
+
+/// The API's routes to serve. A scope with data of its own doesn't see the
+/// app's, so it takes the database, features and rate limiter here.
+pub fn scope(
+    db: web::Data<Db>,
+    features: web::Data<Features>,
+    limiter: web::Data<RateLimiter>,
+) -> Scope {
+    let mut resources = BTreeMap::new();
+    for e in endpoints() {
+        resources
+            .entry(e.path)
+            .or_insert_with(Vec::new)
+            .push(e.into_route());
+    }
+    resources.into_iter().fold(
+        web::scope(PREFIX)
+            .app_data(db)
+            .app_data(features)
+            .app_data(limiter)
+            .app_data(web::JsonConfig::default().error_handler(json_error))
+            .app_data(web::QueryConfig::default().error_handler(query_error))
+            .app_data(web::PathConfig::default().error_handler(path_error))
+            .default_service(web::route().to(no_such_endpoint)),
+        |scope, (path, routes)| {
+            let resource = routes
+                .into_iter()
+                .fold(web::resource(path), |r, route| r.route(route));
+            scope.service(resource.default_service(web::route().to(method_not_allowed)))
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use actix_web::dev::{Service, ServiceResponse};
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+    use coffee_common::limit::Limit;
+    use serde_json::Value;
+
+    async fn call<S, R>(app: &mut S, req: R) -> (StatusCode, Value)
+    where
+        S: Service<Request = R, Response = ServiceResponse, Error = actix_web::Error>,
+    {
+        let resp = test::call_service(app, req).await;
+        let status = resp.status();
+        let body = test::read_body(resp).await;
+        let json = if body.is_empty() {
+            Value::Null
+        } else {
+            serde_json::from_slice(&body).unwrap()
+        };
+        (status, json)
+    }
+
+    fn authed(method: Method, uri: &str, key: &str) -> TestRequest {
+        TestRequest::with_uri(uri)
+            .method(method)
+            .header(header::AUTHORIZATION, format!("Bearer {}", key))
+    }
+
+    #[actix_rt::test]
+    pub async fn test_coffees() {
+        let db = Db::new("file::memory:").await.unwrap();
+        let features = Features::default();
+        let mut app = test::init_service(App::new().service(scope(
+            web::Data::new(db),
+            web::Data::new(features),
+            web::Data::new(RateLimiter::default()),
+        )))
+        .await;
+
+        let register = TestRequest::post()
+            .uri("/api/v1/register")
+            .set_json(&json!({"email": "api@bar.com"}));
+        let (status, body) = call(&mut app, register.to_request()).await;
+        assert_eq!(status, StatusCode::OK);
+        let key = body["api_key"].as_str().unwrap().to_string();
+
+        for (time, shots) in &[(100, 1), (200, 2), (86400 + 100, 3)] {
+            let add = authed(Method::POST, "/api/v1/coffees", &key).set_json(
+                &json!({"shots": shots, "utc_time": time, "client_id": format!("c{}", time)}),
+            );
+            let (status, body) = call(&mut app, add.to_request()).await;
+            assert_eq!(status, StatusCode::CREATED);
+            assert_eq!(body["shots"], json!(shots));
+        }
+        // A retry is fine, and gets the same coffee back.
+        let retry = authed(Method::POST, "/api/v1/coffees", &key)
+            .set_json(&json!({"shots": 1, "utc_time": 100, "client_id": "c100"}));
+        let (status, first) = call(&mut app, retry.to_request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(first["utc_time"], json!(100));
+
+        let (status, page) = call(
+            &mut app,
+            authed(Method::GET, "/api/v1/coffees?limit=2", &key).to_request(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(page["coffees"].as_array().unwrap().len(), 2);
+        assert_eq!(page["next_offset"], json!(2));
+        let (_, page) = call(
+            &mut app,
+            authed(Method::GET, "/api/v1/coffees?limit=2&offset=2", &key).to_request(),
+        )
+        .await;
+        assert_eq!(page["coffees"][0]["shots"], json!(3));
+        assert_eq!(page["next_offset"], Value::Null);
+
+        let (_, stats) = call(
+            &mut app,
+            authed(Method::GET, "/api/v1/stats?end=172800", &key).to_request(),
+        )
+        .await;
+        assert_eq!(stats["coffees"], json!(3));
+        assert_eq!(stats["shots"], json!(6));
+        assert_eq!(
+            stats["days"],
+            json!([{"date": "1970-01-01", "shots": 3}, {"date": "1970-01-02", "shots": 3}])
+        );
+
+        let uri = format!("/api/v1/coffees/{}", first["id"]);
+        let (status, changed) = call(
+            &mut app,
+            authed(Method::PATCH, &uri, &key)
+                .set_json(&json!({"shots": 4, "drink": "latte"}))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(changed["shots"], json!(4));
+        assert_eq!(changed["utc_time"], json!(100));
+        assert_eq!(changed["drink"], json!("latte"));
+
+        let (status, _) = call(&mut app, authed(Method::DELETE, &uri, &key).to_request()).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, body) = call(&mut app, authed(Method::DELETE, &uri, &key).to_request()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"]["code"], json!("not_found"));
+    }
+
+    #[actix_rt::test]
+    pub async fn test_errors() {
+        let db = Db::new("file::memory:").await.unwrap();
+        let key = db.register_user("errors@bar.com").await.unwrap().apikey;
+        let features = Features {
+            registration: false,
+            ..Features::default()
+        };
+        let mut app = test::init_service(App::new().service(scope(
+            web::Data::new(db),
+            web::Data::new(features),
+            web::Data::new(RateLimiter::default()),
+        )))
+        .await;
+
+        let cases = vec![
+            (
+                TestRequest::get().uri("/api/v1/coffees"),
+                StatusCode::UNAUTHORIZED,
+                "unauthenticated",
+            ),
+            (
+                authed(Method::GET, "/api/v1/coffees", "nope"),
+                StatusCode::UNAUTHORIZED,
+                "unauthenticated",
+            ),
+            (
+                TestRequest::post()
+                    .uri("/api/v1/register")
+                    .set_json(&json!({"email": "new@bar.com"})),
+                StatusCode::FORBIDDEN,
+                "permission_denied",
+            ),
+            (
+                authed(Method::POST, "/api/v1/coffees", &key).set_json(&json!({"shots": -1})),
+                StatusCode::BAD_REQUEST,
+                "invalid_argument",
+            ),
+            (
+                authed(Method::POST, "/api/v1/coffees", &key)
+                    .set_json(&json!({"shots": 1, "utc_time": i64::MIN})),
+                StatusCode::BAD_REQUEST,
+                "invalid_argument",
+            ),
+            (
+                authed(Method::POST, "/api/v1/coffees", &key).set_json(&json!({"cups": 1})),
+                StatusCode::BAD_REQUEST,
+                "invalid_argument",
+            ),
+            (
+                authed(Method::GET, "/api/v1/coffees?start=200&end=100", &key),
+                StatusCode::BAD_REQUEST,
+                "invalid_argument",
+            ),
+            (
+                authed(Method::GET, "/api/v1/coffees?limit=5000", &key),
+                StatusCode::BAD_REQUEST,
+                "invalid_argument",
+            ),
+            (
+                authed(Method::GET, "/api/v1/coffees?limit=many", &key),
+                StatusCode::BAD_REQUEST,
+                "invalid_argument",
+            ),
+            (
+                authed(Method::DELETE, "/api/v1/coffees/latte", &key),
+                StatusCode::NOT_FOUND,
+                "not_found",
+            ),
+            (
+                authed(Method::PATCH, "/api/v1/coffees/1", &key).set_json(&json!({"shots": 2})),
+                StatusCode::NOT_FOUND,
+                "not_found",
+            ),
+            (
+                authed(Method::PUT, "/api/v1/coffees", &key),
+                StatusCode::METHOD_NOT_ALLOWED,
+                "method_not_allowed",
+            ),
+            (
+                authed(Method::GET, "/api/v1/teas", &key),
+                StatusCode::NOT_FOUND,
+                "not_found",
+            ),
+        ];
+        for (req, status, code) in cases {
+            let (got, body) = call(&mut app, req.to_request()).await;
+            assert_eq!((got, body["error"]["code"].as_str()), (status, Some(code)));
+            assert!(body["error"]["message"].is_string());
+        }
+
+        let resp = test::call_service(
+            &mut app,
+            TestRequest::get().uri("/api/v1/stats").to_request(),
+        )
+        .await;
+        assert_eq!(
+            resp.headers().get(header::WWW_AUTHENTICATE).unwrap(),
+            "Bearer"
+        );
+    }
+
+    #[actix_rt::test]
+    pub async fn test_rate_limited() {
+        let db = Db::new("file::memory:").await.unwrap();
+        let mut limiter = RateLimiter::default();
+        let one = Limit {
+            burst: 1,
+            per_second: 0.5,
+        };
+        limiter.set_limit(limit::Method::Register, one);
+        limiter.set_limit(limit::Method::DeleteCoffee, one);
+        let mut app = test::init_service(App::new().service(scope(
+            web::Data::new(db),
+            web::Data::new(Features::default()),
+            web::Data::new(limiter),
+        )))
+        .await;
+
+        let register = |email: &str| {
+            TestRequest::post()
+                .uri("/api/v1/register")
+                .set_json(&json!({ "email": email }))
+                .to_request()
+        };
+        let (status, body) = call(&mut app, register("limit@bar.com")).await;
+        assert_eq!(status, StatusCode::OK);
+        let key = body["api_key"].as_str().unwrap().to_string();
+        let resp = test::call_service(&mut app, register("limit2@bar.com")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get(header::RETRY_AFTER).unwrap(), "2");
+        let body: Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(body["error"]["code"], json!("resource_exhausted"));
+
+        // Keyed routes are limited per user, and each route has its own.
+        let delete = |key: &str| authed(Method::DELETE, "/api/v1/coffees/1", key).to_request();
+        let (status, _) = call(&mut app, delete(&key)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call(&mut app, delete(&key)).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let (status, _) = call(
+            &mut app,
+            authed(Method::GET, "/api/v1/coffees", &key).to_request(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Made up keys share the peer's bucket rather than getting their own.
+        let (status, _) = call(&mut app, delete("nope")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = call(&mut app, delete("nope2")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+}
@@ -0,0 +1,315 @@
+// The coffee-over-time chart on the coffee page, drawn here as an SVG so the
+// page needs no JavaScript. Days are UTC days, the server has no way of knowing
+// the reader's timezone without a script to ask.
+
+use coffee_common::db::Coffee;
+
+use chrono::prelude::*;
+use chrono::Duration;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+// How many days the rolling average is over, today included.
+pub const AVERAGE_DAYS: i64 = 7;
+// Most days there can be, ten years' worth. All time goes back to the first
+// coffee, which could be decades ago.
+pub const MAX_DAYS: i64 = 3653;
+
+const WIDTH: f64 = 720.0;
+const HEIGHT: f64 = 260.0;
+// Room for the axis labels.
+const LEFT: f64 = 40.0;
+const RIGHT: f64 = 10.0;
+const TOP: f64 = 10.0;
+const BOTTOM: f64 = 30.0;
+const Y_TICKS: i64 = 4;
+const X_LABELS: usize = 6;
+
+const SHOTS_COLOUR: &str = "#a0522d";
+const AVERAGE_COLOUR: &str = "#1f77b4";
+
+/// How far back the chart goes, from `?range=` on the page.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+pub enum Range {
+    #[serde(rename = "7d")]
+    Week,
+    #[default]
+    #[serde(rename = "30d")]
+    Month,
+    #[serde(rename = "1y")]
+    Year,
+    #[serde(rename = "all")]
+    All,
+}
+
+impl Range {
+    pub const ALL: &'static [Range] = &[Range::Week, Range::Month, Range::Year, Range::All];
+
+    /// What it's called in the query string.
+    pub fn param(self) -> &'static str {
+        match self {
+            Range::Week => "7d",
+            Range::Month => "30d",
+            Range::Year => "1y",
+            Range::All => "all",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Range::Week => "7 days",
+            Range::Month => "30 days",
+            Range::Year => "1 year",
+            Range::All => "All time",
+        }
+    }
+
+    /// The first day shown when it's `today`, or None for all of them.
+    pub fn first_day(self, today: NaiveDate) -> Option<NaiveDate> {
+        let days = match self {
+            Range::Week => 7,
+            Range::Month => 30,
+            Range::Year => 365,
+            Range::All => return None,
+        };
+        Some(today - Duration::days(days - 1))
+    }
+}
+
+/// One day on the chart.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Day {
+    pub date: NaiveDate,
+    pub shots: i64,
+    // The mean over the AVERAGE_DAYS up to and including this one.
+    pub average: f64,
+}
+
+/// Shots for each day from `first` (or the first coffee) to `last`, empty days
+/// included, but no more than the last MAX_DAYS. `coffees` should go back
+/// AVERAGE_DAYS - 1 days before `first` so the average is right from the start.
+pub fn days(coffees: &[Coffee], first: Option<NaiveDate>, last: NaiveDate) -> Vec<Day> {
+    let mut totals = BTreeMap::new();
+    for c in coffees {
+        // Times too far out to be a date can't be charted, so are left out.
+        if let Some(time) = Utc.timestamp_opt(c.utctime, 0).single() {
+            *totals.entry(time.date().naive_utc()).or_insert(0) += i64::from(c.shots);
+        }
+    }
+    let first = match first.or_else(|| totals.keys().next().copied()) {
+        Some(f) => f.max(last - Duration::days(MAX_DAYS - 1)),
+        None => return Vec::new(),
+    };
+
+    let mut days = Vec::new();
+    let mut date = first;
+    while date <= last {
+        let window = totals.range(date - Duration::days(AVERAGE_DAYS - 1)..=date);
+        let average = window.map(|(_, s)| *s).sum::<i64>() as f64 / AVERAGE_DAYS as f64;
+        days.push(Day {
+            date,
+            shots: totals.get(&date).copied().unwrap_or(0),
+            average,
+        });
+        date = date.succ();
+    }
+    days
+}
+
+// A round number of shots to go up to, so the gridlines land on whole shots.
+fn y_max(days: &[Day]) -> i64 {
+    let most = days
+        .iter()
+        .map(|d| d.shots.max(d.average.ceil() as i64))
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let step = (most + Y_TICKS - 1) / Y_TICKS;
+    step * Y_TICKS
+}
+
+/// Draws `days` as bars of daily shots with the rolling average over them.
+pub fn render(days: &[Day]) -> String {
+    let mut svg = String::new();
+    let plot_w = WIDTH - LEFT - RIGHT;
+    let plot_h = HEIGHT - TOP - BOTTOM;
+    let _ = write!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 {w} {h}" width="{w}" height="{h}" role="img" aria-labelledby="chart-title" font-family="sans-serif" font-size="11">"#,
+        w = WIDTH,
+        h = HEIGHT
+    );
+    let _ = write!(
+        svg,
+        r#"<title id="chart-title">Shots a day, with the {}-day average</title>"#,
+        AVERAGE_DAYS
+    );
+
+    if days.is_empty() {
+        let _ = write!(
+            svg,
+            r#"<text x="{}" y="{}" text-anchor="middle">No coffee yet</text></svg>"#,
+            WIDTH / 2.0,
+            HEIGHT / 2.0
+        );
+        return svg;
+    }
+
+    let max = y_max(days);
+    let y = |shots: f64| TOP + plot_h - shots / max as f64 * plot_h;
+    for tick in 0..=Y_TICKS {
+        let shots = max / Y_TICKS * tick;
+        let ty = y(shots as f64);
+        let _ = write!(
+            svg,
+            r##"<line x1="{x1}" y1="{y:.1}" x2="{x2}" y2="{y:.1}" stroke="#ddd"/><text x="{tx}" y="{ty:.1}" text-anchor="end">{shots}</text>"##,
+            x1 = LEFT,
+            x2 = WIDTH - RIGHT,
+            y = ty,
+            tx = LEFT - 6.0,
+            ty = ty + 4.0,
+            shots = shots
+        );
+    }
+
+    let slot = plot_w / days.len() as f64;
+    // A gap between bars while there's room for one.
+    let bar = if slot > 3.0 { slot * 0.8 } else { slot };
+    for (i, d) in days.iter().enumerate() {
+        if d.shots == 0 {
+            continue;
+        }
+        let top = y(d.shots as f64);
+        let _ = write!(
+            svg,
+            r#"<rect x="{:.2}" y="{:.1}" width="{:.2}" height="{:.1}" fill="{}"><title>{}: {} shot(s)</title></rect>"#,
+            LEFT + slot * i as f64 + (slot - bar) / 2.0,
+            top,
+            bar,
+            TOP + plot_h - top,
+            SHOTS_COLOUR,
+            d.date,
+            d.shots
+        );
+    }
+
+    let points: Vec<String> = days
+        .iter()
+        .enumerate()
+        .map(|(i, d)| format!("{:.2},{:.1}", LEFT + slot * (i as f64 + 0.5), y(d.average)))
+        .collect();
+    let _ = write!(
+        svg,
+        r#"<polyline points="{}" fill="none" stroke="{}" stroke-width="2"/>"#,
+        points.join(" "),
+        AVERAGE_COLOUR
+    );
+
+    // Long ranges are labelled by month, short ones by day.
+    let format = if days.len() > 60 { "%b %Y" } else { "%b %d" };
+    let every = days.len().div_ceil(X_LABELS);
+    for (i, d) in days.iter().enumerate().step_by(every.max(1)) {
+        let _ = write!(
+            svg,
+            r#"<text x="{:.2}" y="{}" text-anchor="middle">{}</text>"#,
+            LEFT + slot * (i as f64 + 0.5),
+            HEIGHT - BOTTOM + 16.0,
+            d.date.format(format)
+        );
+    }
+    let _ = write!(
+        svg,
+        r#"<text x="{x}" y="{y}" text-anchor="end"><tspan fill="{s}">■ shots</tspan> <tspan fill="{a}">— {n}-day average</tspan></text>"#,
+        x = WIDTH - RIGHT,
+        y = TOP + 10.0,
+        s = SHOTS_COLOUR,
+        a = AVERAGE_COLOUR,
+        n = AVERAGE_DAYS
+    );
+    svg.push_str("</svg>");
+    svg
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn coffee(date: &str, hour: u32, shots: i32) -> Coffee {
+        let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap();
+        Coffee {
+            shots,
+            utctime: date.and_hms(hour, 0, 0).timestamp(),
+            drink: None,
+        }
+    }
+
+    #[test]
+    pub fn test_days() {
+        let coffees = [
+            coffee("2020-06-01", 8, 7),
+            coffee("2020-06-08", 8, 2),
+            coffee("2020-06-08", 14, 1),
+            coffee("2020-06-10", 9, 4),
+        ];
+        let first = NaiveDate::from_ymd(2020, 6, 7);
+        let last = NaiveDate::from_ymd(2020, 6, 10);
+        let days = days(&coffees, Some(first), last);
+
+        let shots: Vec<_> = days.iter().map(|d| d.shots).collect();
+        assert_eq!(shots, vec![0, 3, 0, 4]);
+        // The 1st is in the week up to the 7th, but not the one up to the 8th.
+        assert_eq!(days[0].average, 1.0);
+        assert_eq!(days[1].average, 3.0 / 7.0);
+        assert_eq!(days[3].average, 1.0);
+
+        // All time starts at the first coffee.
+        let all = super::days(&coffees, None, last);
+        assert_eq!(all[0].date, NaiveDate::from_ymd(2020, 6, 1));
+        assert_eq!(all.len(), 10);
+        assert!(super::days(&[], None, last).is_empty());
+    }
+
+    #[test]
+    pub fn test_days_bounded() {
+        let last = NaiveDate::from_ymd(2020, 6, 10);
+        let out_of_range = Coffee {
+            shots: 1,
+            utctime: i64::MIN,
+            drink: None,
+        };
+        let coffees = [
+            out_of_range,
+            coffee("1970-01-01", 8, 2),
+            coffee("2020-06-10", 9, 4),
+        ];
+
+        // The bad time is skipped, and the ancient one is too far back to show.
+        let days = days(&coffees, None, last);
+        assert_eq!(days.len() as i64, MAX_DAYS);
+        assert_eq!(days.last().unwrap().date, last);
+        assert_eq!(days.iter().map(|d| d.shots).sum::<i64>(), 4);
+    }
+
+    #[test]
+    pub fn test_render() {
+        let coffees = [coffee("2020-06-08", 8, 3), coffee("2020-06-10", 9, 5)];
+        let last = NaiveDate::from_ymd(2020, 6, 10);
+        let days = days(&coffees, Range::Week.first_day(last), last);
+        let svg = render(&days);
+
+        assert!(svg.starts_with("<svg") && svg.ends_with("</svg>"));
+        // A bar for each day with coffee, and a point on the average for every day.
+        assert_eq!(svg.matches("<rect").count(), 2);
+        assert!(svg.contains("<title>2020-06-10: 5 shot(s)</title>"));
+        let points = svg
+            .split("points=\"")
+            .nth(1)
+            .unwrap()
+            .split('"')
+            .next()
+            .unwrap();
+        assert_eq!(points.split(' ').count(), 7);
+        assert!(render(&[]).contains("No coffee yet"));
+    }
+}
@@ -1,11 +1,32 @@
 #[macro_use]
 extern crate serde_json;
 
-use coffee_common::db::Db;
+mod api;
+mod chart;
+mod openapi;
+mod session;
 
-use actix_web::{get, web, HttpResponse, HttpServer};
+use chart::Range;
+use coffee_common::config::{ServerConfig, TlsConfig};
+use coffee_common::db::{Db, DbError};
+use coffee_common::limit::RateLimiter;
+use coffee_common::logging::{self, Redacted, REQUEST_ID_HEADER};
+use coffee_common::metrics;
+use session::{Session, SessionError, Sessions};
+
+use actix_web::dev::Service;
+use actix_web::http::{header, HeaderName, HeaderValue};
+use actix_web::{get, web, HttpRequest, HttpResponse, HttpServer, ResponseError};
+use chrono::{Duration, Utc};
 use clap::{AppSettings, Arg};
 use handlebars::Handlebars;
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::NoClientAuth;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use tracing::{debug, info, info_span, warn, Instrument};
 
 static DEFAULT_ADDR: &str = "[::1]:8080";
 static DEFAULT_DB: &str = "coffee_db";
@@ -15,25 +36,101 @@ async fn index() -> HttpResponse {
     HttpResponse::Ok().body("index!")
 }
 
-#[get("/c/{api_key}")]
+// The token /metrics wants, as it's served alongside the public pages.
+struct MetricsToken(String);
+
+// Serves /metrics when there's a token to ask for.
+fn metrics_config(cfg: &mut web::ServiceConfig, token: Option<String>) {
+    if let Some(token) = token {
+        cfg.data(MetricsToken(token)).service(get_metrics);
+    }
+}
+
+#[get("/metrics")]
+async fn get_metrics(
+    req: HttpRequest,
+    db: web::Data<Db>,
+    token: web::Data<MetricsToken>,
+) -> HttpResponse {
+    match api::bearer_token(&req) {
+        Some(t) if session::same(&t, &token.0) => {}
+        _ => {
+            return HttpResponse::Unauthorized()
+                .header(header::WWW_AUTHENTICATE, "Bearer")
+                .finish()
+        }
+    }
+    match metrics::render(&db).await {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(metrics::content_type())
+            .body(body),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CoffeeQuery {
+    #[serde(default)]
+    range: Range,
+}
+
+#[get("/me")]
+async fn get_me() -> HttpResponse {
+    session::redirect(session::HOME_PATH)
+}
+
+#[get("/me/coffee")]
 async fn get_coffee(
     db: web::Data<Db>,
     hb: web::Data<Handlebars<'_>>,
-    api_key: web::Path<String>,
+    sessions: web::Data<Sessions>,
+    session: Session,
+    query: web::Query<CoffeeQuery>,
 ) -> HttpResponse {
-    let key = api_key.to_string();
+    let range = query.range;
 
-    println!("Key: {}", &key);
+    debug!(range = range.param(), "Rendering coffee page");
 
-    let coffees = match db.get_coffees(&key).await {
+    let today = Utc::today().naive_utc();
+    let first = range.first_day(today);
+    // The days before the first are only there for its rolling average.
+    let start = first
+        .map(|f| {
+            (f - Duration::days(chart::AVERAGE_DAYS - 1))
+                .and_hms(0, 0, 0)
+                .timestamp()
+        })
+        .unwrap_or(0);
+    let coffees = match db.get_coffees_between(&session.api_key, start, 0).await {
         Ok(c) => c,
+        // The key has stopped working since they signed in.
+        Err(DbError::UnknownApiKey) => {
+            let mut resp = SessionError::SignedOut.error_response();
+            let _ = resp.add_cookie(&sessions.end());
+            return resp;
+        }
         Err(e) => {
             return HttpResponse::InternalServerError().body(format!("Error: {}", e));
         }
     };
+    let days = chart::days(&coffees, first, today);
+    let shown_from = first.map(|f| f.and_hms(0, 0, 0).timestamp()).unwrap_or(0);
+    let ranges: Vec<_> = Range::ALL
+        .iter()
+        .map(|r| {
+            json!({
+                "param": r.param(),
+                "label": r.label(),
+                "current": *r == range,
+            })
+        })
+        .collect();
     let data = json!({
-        "api_key": format!("{}", api_key),
-        "coffee_count": coffees.len(),
+        "csrf": session.csrf,
+        "coffee_count": coffees.iter().filter(|c| c.utctime >= shown_from).count(),
+        "shots": days.iter().map(|d| d.shots).sum::<i64>(),
+        "ranges": ranges,
+        "chart": chart::render(&days),
     });
 
     match hb.render("coffee", &data) {
@@ -42,6 +139,49 @@ async fn get_coffee(
     }
 }
 
+// Where the coffee page used to be, with the API key in the path. Old links
+// only send you to sign in: following one mustn't sign you in, or any page
+// could sign its visitors in to someone else's account with one.
+#[get("/c/{api_key}")]
+async fn get_legacy_coffee() -> HttpResponse {
+    warn!("Deprecated /c/<API KEY> link used, it'll be removed in favour of /login");
+    HttpResponse::SeeOther()
+        .header(header::LOCATION, session::LOGIN_PATH)
+        .header("Deprecation", "true")
+        .finish()
+}
+
+// Loads the certificate chain and private key for serving over TLS.
+fn rustls_config(tls: &TlsConfig) -> Result<rustls::ServerConfig, Box<dyn std::error::Error>> {
+    let cert_file = tls.cert.as_ref().ok_or("No TLS certificate configured")?;
+    let key_file = tls.key.as_ref().ok_or("No TLS key configured")?;
+
+    let cert_chain = certs(&mut BufReader::new(File::open(cert_file)?))
+        .map_err(|_| format!("Could not read certificates from {}", cert_file))?;
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(File::open(key_file)?))
+        .map_err(|_| format!("Could not read private key from {}", key_file))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut BufReader::new(File::open(key_file)?))
+            .map_err(|_| format!("Could not read private key from {}", key_file))?;
+    }
+    let key = keys
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("No private key found in {}", key_file))?;
+
+    let mut config = rustls::ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(cert_chain, key)?;
+    Ok(config)
+}
+
+// Paths with an API key in them are logged with the key redacted.
+fn loggable_path(path: &str) -> String {
+    match path.strip_prefix("/c/") {
+        Some(key) => format!("/c/{}", Redacted(key)),
+        None => path.into(),
+    }
+}
+
 #[actix_rt::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let matches = clap::App::new(env!("CARGO_PKG_NAME"))
@@ -64,6 +204,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .required(false)
                 .global(true),
         )
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .help("Specify a config file")
+                .takes_value(true)
+                .required(false)
+                .global(true),
+        )
         .arg(
             Arg::with_name("db")
                 .short("d")
@@ -79,30 +228,179 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .required(false)
                 .global(true),
         )
+        .arg(
+            Arg::with_name("print-config")
+                .long("print-config")
+                .help("Print the effective configuration and exit")
+                .required(false)
+                .global(true),
+        )
         .get_matches();
-    let addr = matches.value_of("addr").unwrap_or(DEFAULT_ADDR);
-    let db_file = matches.value_of("db").unwrap_or(DEFAULT_DB);
 
-    println!("Address: {}", addr);
-    println!("Database: {}", db_file);
+    let defaults = ServerConfig {
+        addr: DEFAULT_ADDR.into(),
+        db: DEFAULT_DB.into(),
+        ..ServerConfig::default()
+    };
+    let mut config = ServerConfig::load(defaults, matches.value_of("config").map(Path::new))?;
+    if let Some(addr) = matches.value_of("addr") {
+        config.addr = addr.into();
+    }
+    if let Some(db) = matches.value_of("db") {
+        config.db = db.into();
+    }
+
+    if matches.is_present("print-config") {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+
+    logging::init(&config)?;
+    info!(addr = %config.addr, db = %config.db, tls = config.tls.enabled(), "Serving");
 
     let mut handlebars = Handlebars::new();
     handlebars.register_templates_directory(".html", "./templates")?;
     let hb_ref = web::Data::new(handlebars);
 
-    let db = Db::new(db_file).await?;
-    let db_ref = web::Data::new(db);
+    let db = Db::new(&config.db).await?;
+    let db_ref = web::Data::new(db.clone());
+
+    let features_ref = web::Data::new(config.features.clone());
+    // Shared by every worker, so a caller's limit doesn't depend on which
+    // one they land on.
+    let limiter_ref = web::Data::new(RateLimiter::from_config(&config)?);
+    let sessions_ref = web::Data::new(Sessions::new(&config.session, config.tls.enabled())?);
+
+    let metrics_token = match (config.features.metrics, &config.metrics_token) {
+        (true, Some(token)) => Some(token.clone()),
+        (true, None) => {
+            warn!("No metrics_token set, not serving /metrics");
+            None
+        }
+        (false, _) => None,
+    };
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         actix_web::App::new()
+            // Every request gets a span tagged with its request id, which is
+            // echoed back in the response headers.
+            .wrap_fn(|req, srv| {
+                let supplied = req
+                    .headers()
+                    .get(REQUEST_ID_HEADER)
+                    .and_then(|v| v.to_str().ok());
+                let request_id = logging::request_id(supplied);
+                let span = info_span!(
+                    "http",
+                    method = %req.method(),
+                    path = %loggable_path(req.path()),
+                    request_id = %request_id
+                );
+                let fut = srv.call(req).instrument(span.clone());
+                async move {
+                    let mut res = fut.await?;
+                    span.in_scope(|| info!(status = res.status().as_u16(), "Request handled"));
+                    if let Ok(v) = HeaderValue::from_str(&request_id) {
+                        res.headers_mut()
+                            .insert(HeaderName::from_static(REQUEST_ID_HEADER), v);
+                    }
+                    Ok(res)
+                }
+            })
             .app_data(hb_ref.clone())
             .app_data(db_ref.clone())
+            .app_data(sessions_ref.clone())
+            .app_data(limiter_ref.clone())
             .service(index)
+            .configure(session::config)
+            .service(get_me)
             .service(get_coffee)
+            .service(get_legacy_coffee)
+            .service(api::scope(
+                db_ref.clone(),
+                features_ref.clone(),
+                limiter_ref.clone(),
+            ))
+            .service(openapi::get_document)
+            .configure(|cfg| metrics_config(cfg, metrics_token.clone()))
     })
-    .bind(addr)?
-    .run()
-    .await?;
+    // actix stops accepting on SIGINT/SIGTERM and gives workers this long to
+    // finish what they're doing.
+    .shutdown_timeout(config.shutdown_timeout);
+    let server = if config.tls.enabled() {
+        server.bind_rustls(&config.addr, rustls_config(&config.tls)?)?
+    } else {
+        server.bind(&config.addr)?
+    };
+    server.run().await?;
+
+    db.close().await;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+
+    #[actix_rt::test]
+    pub async fn test_legacy_link() {
+        let mut app = test::init_service(App::new().service(get_legacy_coffee)).await;
+        let req = TestRequest::get().uri("/c/somekey?range=1y").to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        // Sent to sign in, without being signed in as the key's owner.
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            resp.headers().get(header::LOCATION).unwrap(),
+            session::LOGIN_PATH
+        );
+        assert_eq!(resp.headers().get("Deprecation").unwrap(), "true");
+        assert_eq!(resp.response().cookies().count(), 0);
+    }
+
+    #[actix_rt::test]
+    pub async fn test_metrics() {
+        let db = Db::new("file::memory:").await.unwrap();
+        let user = db.register_user("metrics@bar.com").await.unwrap();
+        db.user_id(&user.apikey).await.unwrap();
+        db.user_id(&user.apikey).await.unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(db)
+                .configure(|cfg| metrics_config(cfg, Some("sekrit".into()))),
+        )
+        .await;
+
+        for auth in &[None, Some("Bearer wrong")] {
+            let mut req = TestRequest::get().uri("/metrics");
+            if let Some(auth) = auth {
+                req = req.header(header::AUTHORIZATION, *auth);
+            }
+            let resp = test::call_service(&mut app, req.to_request()).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        let req = TestRequest::get()
+            .uri("/metrics")
+            .header(header::AUTHORIZATION, "Bearer sekrit")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = test::read_body(resp).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("coffee_key_cache_hits_total"));
+        assert!(body.contains("coffee_key_cache_misses_total"));
+
+        // Without a token it isn't there at all.
+        let mut app =
+            test::init_service(App::new().configure(|cfg| metrics_config(cfg, None))).await;
+        let req = TestRequest::get().uri("/metrics").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,691 @@
+// The OpenAPI 3 document for the JSON API, served at /api/openapi.json. The
+// API's scope is built from the same endpoints the document describes, and
+// the schemas come from the structs themselves through `documented!`, so the
+// document can't leave a route out or get a field wrong.
+
+use crate::api::{self, ErrorBody};
+
+use actix_web::dev::Factory;
+use actix_web::http::{Method, StatusCode};
+use actix_web::{get, web, FromRequest, HttpResponse, Responder, Route};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::future::Future;
+
+/// The named schemas a document refers to, by name.
+pub type Definitions = BTreeMap<String, Value>;
+
+/// A type as the document describes it.
+pub trait Schema {
+    /// Its schema, or a reference to it if it's one of the named ones.
+    fn schema() -> Value;
+
+    /// Whether a field of this type has to be given.
+    fn required() -> bool {
+        true
+    }
+
+    /// Adds the named schemas it needs to `defs`, its own included.
+    fn define(_defs: &mut Definitions) {}
+}
+
+/// A struct described by `documented!`, with fields that can be properties of
+/// its schema or parameters in a query.
+pub trait Object: Schema {
+    fn description() -> String;
+    fn properties() -> Vec<Property>;
+}
+
+/// One of a struct's fields.
+pub struct Property {
+    pub name: &'static str,
+    pub description: String,
+    pub schema: Value,
+    pub required: bool,
+}
+
+impl Property {
+    pub fn new<T: Schema>(name: &'static str, description: String) -> Self {
+        Property {
+            name,
+            description,
+            schema: T::schema(),
+            required: T::required(),
+        }
+    }
+
+    // Its schema with the description, which a reference can't carry.
+    fn described_schema(&self) -> Value {
+        let mut schema = self.schema.clone();
+        if !self.description.is_empty() && schema.get("$ref").is_none() {
+            schema["description"] = self.description.clone().into();
+        }
+        schema
+    }
+}
+
+macro_rules! primitive {
+    ($($ty:ty => $schema:tt),* $(,)?) => {$(
+        impl Schema for $ty {
+            fn schema() -> Value {
+                json!($schema)
+            }
+        }
+    )*};
+}
+
+primitive! {
+    bool => {"type": "boolean"},
+    i32 => {"type": "integer", "format": "int32"},
+    i64 => {"type": "integer", "format": "int64"},
+    usize => {"type": "integer", "minimum": 0},
+    String => {"type": "string"},
+}
+
+// Optional fields can be left out, or null.
+impl<T: Schema> Schema for Option<T> {
+    fn schema() -> Value {
+        let schema = T::schema();
+        if schema.get("$ref").is_some() {
+            json!({"allOf": [schema], "nullable": true})
+        } else {
+            let mut schema = schema;
+            schema["nullable"] = true.into();
+            schema
+        }
+    }
+
+    fn required() -> bool {
+        false
+    }
+
+    fn define(defs: &mut Definitions) {
+        T::define(defs)
+    }
+}
+
+impl<T: Schema> Schema for Vec<T> {
+    fn schema() -> Value {
+        json!({"type": "array", "items": T::schema()})
+    }
+
+    fn define(defs: &mut Definitions) {
+        T::define(defs)
+    }
+}
+
+/// The schema `T` is named for, rather than a reference to it.
+pub fn object_schema<T: Object>() -> Value {
+    let properties = T::properties();
+    let mut schema = json!({
+        "type": "object",
+        "properties": properties
+            .iter()
+            .map(|p| (p.name.to_string(), p.described_schema()))
+            .collect::<Map<_, _>>(),
+    });
+    let required: Vec<_> = properties
+        .iter()
+        .filter(|p| p.required)
+        .map(|p| p.name)
+        .collect();
+    if !required.is_empty() {
+        schema["required"] = json!(required);
+    }
+    let description = T::description();
+    if !description.is_empty() {
+        schema["description"] = description.into();
+    }
+    schema
+}
+
+/// Doc comments as one line of text.
+pub fn doc_text(lines: &[&str]) -> String {
+    lines
+        .iter()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// The text of the doc comments among some attributes.
+macro_rules! doc_lines {
+    (@docs [$($acc:literal),*]) => {
+        &[$($acc),*] as &[&str]
+    };
+    (@docs [$($acc:literal),*] #[doc = $doc:literal] $($rest:tt)*) => {
+        doc_lines!(@docs [$($acc,)* $doc] $($rest)*)
+    };
+    (@docs [$($acc:literal),*] #[$($attr:tt)*] $($rest:tt)*) => {
+        doc_lines!(@docs [$($acc),*] $($rest)*)
+    };
+    ($($attrs:tt)*) => {
+        doc_lines!(@docs [] $($attrs)*)
+    };
+}
+
+/// Declares structs that are part of the API, making each a named schema
+/// from its fields and doc comments.
+macro_rules! documented {
+    ($(
+        $(#[$($attr:tt)*])*
+        pub struct $name:ident {
+            $(
+                $(#[$($field_attr:tt)*])*
+                pub $field:ident: $ty:ty,
+            )*
+        }
+    )*) => {$(
+        $(#[$($attr)*])*
+        pub struct $name {
+            $(
+                $(#[$($field_attr)*])*
+                pub $field: $ty,
+            )*
+        }
+
+        impl $crate::openapi::Schema for $name {
+            fn schema() -> serde_json::Value {
+                json!({"$ref": format!("#/components/schemas/{}", stringify!($name))})
+            }
+
+            fn define(defs: &mut $crate::openapi::Definitions) {
+                if !defs.contains_key(stringify!($name)) {
+                    defs.insert(
+                        stringify!($name).into(),
+                        $crate::openapi::object_schema::<$name>(),
+                    );
+                    $(<$ty as $crate::openapi::Schema>::define(defs);)*
+                }
+            }
+        }
+
+        impl $crate::openapi::Object for $name {
+            fn description() -> String {
+                $crate::openapi::doc_text(doc_lines!($(#[$($attr)*])*))
+            }
+
+            fn properties() -> Vec<$crate::openapi::Property> {
+                vec![$(
+                    $crate::openapi::Property::new::<$ty>(
+                        stringify!($field),
+                        $crate::openapi::doc_text(doc_lines!($(#[$($field_attr)*])*)),
+                    ),
+                )*]
+            }
+        }
+    )*};
+}
+
+pub(crate) use doc_lines;
+pub(crate) use documented;
+
+// A schema along with what it refers to.
+struct Typed {
+    schema: Value,
+    define: fn(&mut Definitions),
+}
+
+impl Typed {
+    fn of<T: Schema>() -> Self {
+        Typed {
+            schema: T::schema(),
+            define: T::define,
+        }
+    }
+}
+
+struct Parameter {
+    location: &'static str,
+    property: Property,
+}
+
+struct Response {
+    status: StatusCode,
+    description: &'static str,
+    body: Option<Typed>,
+}
+
+/// One of the API's routes, and what the document says about it.
+pub struct Endpoint {
+    pub method: Method,
+    // Under api::PREFIX, with parameters as `{id}` in both actix and OpenAPI.
+    pub path: &'static str,
+    summary: &'static str,
+    operation_id: &'static str,
+    auth: bool,
+    params: Vec<Parameter>,
+    body: Option<Typed>,
+    responses: Vec<Response>,
+    route: Route,
+}
+
+impl Endpoint {
+    /// `handler` serving `method` on `path`. Clients generated from the
+    /// document name their calls after `operation_id`, so it mustn't change.
+    pub fn new<F, T, R, U>(
+        method: Method,
+        path: &'static str,
+        operation_id: &'static str,
+        summary: &'static str,
+        handler: F,
+    ) -> Self
+    where
+        F: Factory<T, R, U>,
+        T: FromRequest + 'static,
+        R: Future<Output = U> + 'static,
+        U: Responder + 'static,
+    {
+        Endpoint {
+            route: web::method(method.clone()).to(handler),
+            method,
+            path,
+            summary,
+            operation_id,
+            auth: false,
+            params: Vec::new(),
+            body: None,
+            responses: Vec::new(),
+        }
+    }
+
+    /// Needs the API key, and so can answer 401.
+    pub fn auth(mut self) -> Self {
+        self.auth = true;
+        self.error(StatusCode::UNAUTHORIZED, "No API key, or an unknown one")
+    }
+
+    /// Rate limited, and so can answer 429.
+    pub fn limited(self) -> Self {
+        self.error(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many calls, try again after Retry-After seconds",
+        )
+    }
+
+    pub fn path_param<T: Schema>(mut self, name: &'static str, description: &str) -> Self {
+        self.params.push(Parameter {
+            location: "path",
+            property: Property::new::<T>(name, description.into()),
+        });
+        self
+    }
+
+    /// Takes `T`'s fields as query parameters.
+    pub fn query<T: Object>(mut self) -> Self {
+        for property in T::properties() {
+            self.params.push(Parameter {
+                location: "query",
+                property,
+            });
+        }
+        self
+    }
+
+    /// Takes a `T` as its JSON body.
+    pub fn body<T: Schema>(mut self) -> Self {
+        self.body = Some(Typed::of::<T>());
+        self
+    }
+
+    /// Answers `status` with a `T`.
+    pub fn response<T: Schema>(mut self, status: StatusCode, description: &'static str) -> Self {
+        self.responses.push(Response {
+            status,
+            description,
+            body: Some(Typed::of::<T>()),
+        });
+        self
+    }
+
+    /// Answers `status` with no body.
+    pub fn empty_response(mut self, status: StatusCode, description: &'static str) -> Self {
+        self.responses.push(Response {
+            status,
+            description,
+            body: None,
+        });
+        self
+    }
+
+    /// Answers `status` with the usual error body.
+    pub fn error(self, status: StatusCode, description: &'static str) -> Self {
+        self.response::<ErrorBody>(status, description)
+    }
+
+    /// The actix route that serves it.
+    pub fn into_route(self) -> Route {
+        self.route
+    }
+
+    fn operation(&self, defs: &mut Definitions) -> Value {
+        let mut responses = Map::new();
+        for r in &self.responses {
+            let mut response = json!({"description": r.description});
+            if let Some(body) = &r.body {
+                (body.define)(defs);
+                response["content"] = json!({"application/json": {"schema": body.schema}});
+            }
+            responses.insert(r.status.as_str().into(), response);
+        }
+        // Anything else that goes wrong still has the usual body.
+        ErrorBody::define(defs);
+        responses.insert(
+            "default".into(),
+            json!({
+                "description": "Something else went wrong",
+                "content": {"application/json": {"schema": ErrorBody::schema()}},
+            }),
+        );
+
+        let mut op = json!({
+            "summary": self.summary,
+            "operationId": self.operation_id,
+            "responses": responses,
+        });
+        if !self.params.is_empty() {
+            op["parameters"] = self
+                .params
+                .iter()
+                .map(|p| {
+                    let mut param = json!({
+                        "name": p.property.name,
+                        "in": p.location,
+                        // Path parameters always have to be there.
+                        "required": p.property.required || p.location == "path",
+                        "schema": p.property.schema,
+                    });
+                    if !p.property.description.is_empty() {
+                        param["description"] = p.property.description.clone().into();
+                    }
+                    param
+                })
+                .collect();
+        }
+        if let Some(body) = &self.body {
+            (body.define)(defs);
+            op["requestBody"] = json!({
+                "required": true,
+                "content": {"application/json": {"schema": body.schema}},
+            });
+        }
+        if self.auth {
+            op["security"] = json!([{"bearer": []}]);
+        }
+        op
+    }
+}
+
+/// The OpenAPI document describing `endpoints`.
+pub fn document(endpoints: &[Endpoint]) -> Value {
+    let mut defs = Definitions::new();
+    let mut paths = Map::new();
+    for e in endpoints {
+        let item = paths
+            .entry(format!("{}{}", api::PREFIX, e.path))
+            .or_insert_with(|| json!({}));
+        item[e.method.as_str().to_lowercase()] = e.operation(&mut defs);
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Coffee",
+            "description": "Log and look back over the coffee you drink.",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {
+            "schemas": defs,
+            "securitySchemes": {
+                "bearer": {"type": "http", "scheme": "bearer", "description": "The API key"},
+            },
+        },
+    })
+}
+
+#[get("/api/openapi.json")]
+pub async fn get_document() -> HttpResponse {
+    HttpResponse::Ok().json(document(&api::endpoints()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use coffee_common::config::Features;
+    use coffee_common::db::{Coffee, Db};
+    use coffee_common::limit::RateLimiter;
+
+    use actix_web::http::header;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+
+    fn resolve<'a>(schema: &'a Value, doc: &'a Value) -> &'a Value {
+        match schema["$ref"].as_str() {
+            Some(r) => {
+                let name = r.trim_start_matches("#/components/schemas/");
+                &doc["components"]["schemas"][name]
+            }
+            None => match schema["allOf"].get(0) {
+                Some(inner) => resolve(inner, doc),
+                None => schema,
+            },
+        }
+    }
+
+    // A value `schema` accepts, with every property filled in.
+    fn example(schema: &Value, doc: &Value) -> Value {
+        let schema = resolve(schema, doc);
+        match schema["type"].as_str() {
+            Some("object") => schema["properties"]
+                .as_object()
+                .unwrap()
+                .iter()
+                .map(|(name, s)| (name.clone(), example(s, doc)))
+                .collect::<Map<_, _>>()
+                .into(),
+            Some("array") => json!([example(&schema["items"], doc)]),
+            Some("integer") => json!(1),
+            Some("boolean") => json!(true),
+            _ => json!("example"),
+        }
+    }
+
+    // Whether `value` is what `schema` says, and nothing more.
+    fn check(value: &Value, schema: &Value, doc: &Value, at: &str) -> Result<(), String> {
+        let nullable = schema["nullable"] == json!(true);
+        let schema = resolve(schema, doc);
+        if value.is_null() {
+            return if nullable || schema["nullable"] == json!(true) {
+                Ok(())
+            } else {
+                Err(format!("{} is null", at))
+            };
+        }
+        let ok = match schema["type"].as_str() {
+            Some("object") => {
+                let object = value.as_object().ok_or(format!("{} isn't an object", at))?;
+                let properties = schema["properties"].as_object().unwrap();
+                for (name, v) in object {
+                    let s = properties
+                        .get(name)
+                        .ok_or(format!("{}.{} isn't documented", at, name))?;
+                    check(v, s, doc, &format!("{}.{}", at, name))?;
+                }
+                for name in schema["required"].as_array().into_iter().flatten() {
+                    if !object.contains_key(name.as_str().unwrap()) {
+                        return Err(format!("{}.{} is missing", at, name));
+                    }
+                }
+                true
+            }
+            Some("array") => {
+                let items = value.as_array().ok_or(format!("{} isn't an array", at))?;
+                for (i, v) in items.iter().enumerate() {
+                    check(v, &schema["items"], doc, &format!("{}[{}]", at, i))?;
+                }
+                true
+            }
+            Some("integer") => value.is_i64() || value.is_u64(),
+            Some("boolean") => value.is_boolean(),
+            Some("string") => value.is_string(),
+            other => return Err(format!("{} has an unknown type {:?}", at, other)),
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(format!("{} should be {}", at, schema["type"]))
+        }
+    }
+
+    // Every route in the document is served, answers as documented, and takes
+    // the body it says. Routes are built from the same endpoints, so the API
+    // has none the document leaves out.
+    #[actix_rt::test]
+    pub async fn test_document_matches_routes() {
+        let db = Db::new("file::memory:").await.unwrap();
+        let key = db.register_user("openapi@bar.com").await.unwrap().apikey;
+        let mut app = test::init_service(App::new().service(get_document).service(api::scope(
+            web::Data::new(db.clone()),
+            web::Data::new(Features::default()),
+            web::Data::new(RateLimiter::default()),
+        )))
+        .await;
+        let doc: Value = test::read_response_json(
+            &mut app,
+            TestRequest::get().uri("/api/openapi.json").to_request(),
+        )
+        .await;
+        assert_eq!(doc["openapi"], json!("3.0.3"));
+
+        let methods = [
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+        ];
+        let mut operations = 0;
+        for (path, item) in doc["paths"].as_object().unwrap() {
+            for method in &methods {
+                let op = &item[method.as_str().to_lowercase()];
+                // Each call on a coffee gets a coffee of its own.
+                let id = format!("c{}{}", method, path);
+                db.add_coffee(
+                    &key,
+                    &Coffee {
+                        shots: 1,
+                        utctime: 100,
+                        drink: None,
+                    },
+                    Some(&id),
+                )
+                .await
+                .unwrap();
+                let id = db.find_coffee(&key, &id).await.unwrap().unwrap().id;
+                let uri = path.replace("{id}", &id.to_string());
+                let mut req = TestRequest::with_uri(&uri).method(method.clone());
+
+                if op.is_null() {
+                    let resp = test::call_service(&mut app, req.to_request()).await;
+                    assert_eq!(
+                        resp.status(),
+                        StatusCode::METHOD_NOT_ALLOWED,
+                        "{} {}",
+                        method,
+                        path
+                    );
+                    continue;
+                }
+                operations += 1;
+                if let Some(body) = op.get("requestBody") {
+                    let schema = &body["content"]["application/json"]["schema"];
+                    req = req.set_json(&example(schema, &doc));
+                }
+                if op.get("security").is_some() {
+                    let resp = test::call_service(&mut app, req.to_request()).await;
+                    assert_eq!(
+                        resp.status(),
+                        StatusCode::UNAUTHORIZED,
+                        "{} {}",
+                        method,
+                        path
+                    );
+                    assert!(op["responses"].get("401").is_some(), "{} {}", method, path);
+                    req = TestRequest::with_uri(&uri)
+                        .method(method.clone())
+                        .header(header::AUTHORIZATION, format!("Bearer {}", key));
+                    if let Some(body) = op.get("requestBody") {
+                        let schema = &body["content"]["application/json"]["schema"];
+                        req = req.set_json(&example(schema, &doc));
+                    }
+                }
+
+                let resp = test::call_service(&mut app, req.to_request()).await;
+                let status = resp.status();
+                let body = test::read_body(resp).await;
+                let response = &op["responses"][status.as_str()];
+                assert!(
+                    !response.is_null(),
+                    "{} {} answered {}, which isn't documented: {:?}",
+                    method,
+                    path,
+                    status,
+                    body
+                );
+                assert!(
+                    status.is_success(),
+                    "{} {} answered {}",
+                    method,
+                    path,
+                    status
+                );
+                match response.get("content") {
+                    Some(content) => {
+                        let value: Value = serde_json::from_slice(&body).unwrap();
+                        let schema = &content["application/json"]["schema"];
+                        let at = format!("{} {}", method, path);
+                        check(&value, schema, &doc, &at).unwrap();
+                    }
+                    None => assert!(body.is_empty(), "{} {}", method, path),
+                }
+            }
+        }
+        assert_eq!(operations, api::endpoints().len());
+    }
+
+    #[test]
+    pub fn test_schemas() {
+        let doc = document(&api::endpoints());
+        let coffee = &doc["components"]["schemas"]["CoffeeBody"];
+        assert_eq!(coffee["type"], json!("object"));
+        assert_eq!(
+            coffee["description"],
+            json!("A coffee as the API shows it.")
+        );
+        assert_eq!(
+            coffee["properties"]["drink"],
+            json!({"type": "string", "nullable": true, "description": "The drink it was, if it was added as one."})
+        );
+        assert_eq!(coffee["required"], json!(["id", "utc_time", "shots"]));
+
+        let list = &doc["paths"]["/api/v1/coffees"]["get"];
+        assert_eq!(list["operationId"], json!("list_coffees"));
+        // Operation ids have to be unique across the document.
+        let mut ids: Vec<_> = api::endpoints().iter().map(|e| e.operation_id).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), api::endpoints().len());
+        let params: Vec<_> = list["parameters"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| p["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(params, vec!["start", "end", "limit", "offset"]);
+        assert_eq!(
+            doc["paths"]["/api/v1/coffees/{id}"]["delete"]["parameters"][0]["required"],
+            json!(true)
+        );
+    }
+}
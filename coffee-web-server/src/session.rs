@@ -0,0 +1,509 @@
+// Signing in to the web pages. The API key is given once, to /login, and
+// swapped for a session cookie so it's never in a URL. The cookie is encrypted
+// and signed with the server's session key, and is HttpOnly so nothing on the
+// page can read it. Forms that change anything carry the session's CSRF token,
+// and the login form one of its own from a cookie that goes with it.
+
+use crate::api;
+use coffee_common::config::SessionConfig;
+use coffee_common::db::{Db, DbError};
+use coffee_common::limit::{self, RateLimiter};
+
+use actix_web::cookie::{Cookie, CookieJar, Key, SameSite};
+use actix_web::dev::Payload;
+use actix_web::http::{header, StatusCode};
+use actix_web::ResponseError;
+use actix_web::{get, post, web, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use chrono::Utc;
+use handlebars::Handlebars;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::future::{ready, Ready};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+static SESSION_COOKIE: &str = "coffee_session";
+static LOGIN_CSRF_COOKIE: &str = "coffee_login_csrf";
+// The shortest session key Key::from_master takes.
+const MIN_KEY_LEN: usize = 32;
+
+pub static LOGIN_PATH: &str = "/login";
+pub static HOME_PATH: &str = "/me/coffee";
+
+/// Makes and checks session cookies.
+pub struct Sessions {
+    key: Key,
+    max_age: i64,
+    // Whether cookies are only sent over HTTPS.
+    secure: bool,
+}
+
+impl Sessions {
+    pub fn new(config: &SessionConfig, secure: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        let key = match &config.key_file {
+            Some(f) => {
+                let master = std::fs::read(f)?;
+                if master.len() < MIN_KEY_LEN {
+                    return Err(format!(
+                        "The session key in {} needs at least {} bytes",
+                        f, MIN_KEY_LEN
+                    )
+                    .into());
+                }
+                Key::from_master(&master)
+            }
+            None => {
+                warn!("No session key file, everyone will be signed out when the server restarts");
+                Key::generate()
+            }
+        };
+        Ok(Sessions {
+            key,
+            max_age: config.max_age as i64,
+            secure,
+        })
+    }
+
+    fn cookie(&self, name: &'static str, value: String, max_age: i64) -> Cookie<'static> {
+        Cookie::build(name, value)
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax)
+            .max_age(max_age)
+            .finish()
+    }
+
+    // `cookie` with its value encrypted and signed.
+    fn seal(&self, cookie: Cookie<'static>) -> Cookie<'static> {
+        let name = cookie.name().to_string();
+        let mut jar = CookieJar::new();
+        jar.private(&self.key).add(cookie);
+        jar.get(&name).cloned().unwrap()
+    }
+
+    // The value of a sealed cookie, if it's there and hasn't been tampered with.
+    fn open(&self, req: &HttpRequest, name: &str) -> Option<String> {
+        let mut jar = CookieJar::new();
+        jar.add_original(req.cookie(name)?);
+        let cookie = jar.private(&self.key).get(name)?;
+        Some(cookie.value().to_string())
+    }
+
+    /// A session for `api_key`, as the cookie that carries it.
+    pub fn start(&self, api_key: &str) -> Cookie<'static> {
+        let session = Session {
+            api_key: api_key.into(),
+            csrf: token(),
+            expires: Utc::now().timestamp() + self.max_age,
+        };
+        let value = serde_json::to_string(&session).unwrap();
+        self.seal(self.cookie(SESSION_COOKIE, value, self.max_age))
+    }
+
+    /// The cookie that ends a session.
+    pub fn end(&self) -> Cookie<'static> {
+        self.cookie(SESSION_COOKIE, String::new(), 0)
+    }
+
+    /// The session the request is part of, if it's still going.
+    pub fn session(&self, req: &HttpRequest) -> Option<Session> {
+        let session: Session = serde_json::from_str(&self.open(req, SESSION_COOKIE)?).ok()?;
+        if session.expires <= Utc::now().timestamp() {
+            return None;
+        }
+        Some(session)
+    }
+}
+
+/// Someone signed in, from their session cookie.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub api_key: String,
+    // What forms in this session have to send back.
+    pub csrf: String,
+    expires: i64,
+}
+
+impl Session {
+    pub fn check_csrf(&self, token: &str) -> Result<(), SessionError> {
+        if same(&self.csrf, token) {
+            Ok(())
+        } else {
+            Err(SessionError::BadCsrf)
+        }
+    }
+}
+
+impl FromRequest for Session {
+    type Config = ();
+    type Error = SessionError;
+    type Future = Ready<Result<Self, SessionError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let session = req
+            .app_data::<web::Data<Sessions>>()
+            .and_then(|s| s.session(req));
+        ready(session.ok_or(SessionError::SignedOut))
+    }
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    // Not signed in, or the session has expired.
+    SignedOut,
+    // A form came back without its CSRF token.
+    BadCsrf,
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl ResponseError for SessionError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SessionError::SignedOut => StatusCode::SEE_OTHER,
+            SessionError::BadCsrf => StatusCode::FORBIDDEN,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            SessionError::SignedOut => HttpResponse::SeeOther()
+                .header(header::LOCATION, LOGIN_PATH)
+                .finish(),
+            SessionError::BadCsrf => HttpResponse::Forbidden()
+                .body("That form has expired, go back, reload it and try again."),
+        }
+    }
+}
+
+// A fresh random token.
+fn token() -> String {
+    Uuid::new_v4().to_simple().to_string()
+}
+
+// Compares in the same time however much of the two match.
+pub(crate) fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginForm {
+    api_key: String,
+    csrf: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CsrfForm {
+    pub csrf: String,
+}
+
+// The login page, with an `error` if the last try didn't work. A new CSRF
+// token is made for it unless the login cookie already has one.
+fn login_page(
+    sessions: &Sessions,
+    hb: &Handlebars,
+    req: &HttpRequest,
+    status: StatusCode,
+    error: Option<&str>,
+) -> HttpResponse {
+    let csrf = sessions.open(req, LOGIN_CSRF_COOKIE).unwrap_or_else(token);
+    let data = json!({"csrf": csrf, "error": error});
+    match hb.render("login", &data) {
+        Ok(body) => HttpResponse::build(status)
+            .cookie(sessions.seal(sessions.cookie(LOGIN_CSRF_COOKIE, csrf, sessions.max_age)))
+            .content_type("text/html; charset=utf-8")
+            .body(body),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+}
+
+/// Sends the browser on to `to`.
+pub fn redirect(to: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .header(header::LOCATION, to)
+        .finish()
+}
+
+#[get("/login")]
+async fn get_login(
+    sessions: web::Data<Sessions>,
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if sessions.session(&req).is_some() {
+        return redirect(HOME_PATH);
+    }
+    login_page(&sessions, &hb, &req, StatusCode::OK, None)
+}
+
+#[post("/login")]
+async fn post_login(
+    sessions: web::Data<Sessions>,
+    hb: web::Data<Handlebars<'_>>,
+    db: web::Data<Db>,
+    limiter: web::Data<RateLimiter>,
+    req: HttpRequest,
+    form: web::Form<LoginForm>,
+) -> Result<HttpResponse, SessionError> {
+    let expected = sessions.open(&req, LOGIN_CSRF_COOKIE).unwrap_or_default();
+    if expected.is_empty() || !same(&expected, &form.csrf) {
+        return Err(SessionError::BadCsrf);
+    }
+    // Limited per peer the same as the RPC server's VerifyKey, or the form
+    // would be a way round it to guess keys.
+    if let Err(l) = limiter.check(limit::Method::VerifyKey, &api::peer(&req)) {
+        let mut resp = login_page(
+            &sessions,
+            &hb,
+            &req,
+            StatusCode::TOO_MANY_REQUESTS,
+            Some("Too many tries, wait a while and try again."),
+        );
+        resp.headers_mut().insert(
+            header::RETRY_AFTER,
+            api::retry_after_secs(l.retry_after).into(),
+        );
+        return Ok(resp);
+    }
+    let api_key = form.api_key.trim();
+    let resp = match db.user_id(api_key).await {
+        Ok(_) => {
+            info!("Signed in");
+            HttpResponse::SeeOther()
+                .header(header::LOCATION, HOME_PATH)
+                .cookie(sessions.start(api_key))
+                .cookie(sessions.cookie(LOGIN_CSRF_COOKIE, String::new(), 0))
+                .finish()
+        }
+        Err(DbError::UnknownApiKey) => login_page(
+            &sessions,
+            &hb,
+            &req,
+            StatusCode::UNAUTHORIZED,
+            Some("That API key isn't one we know."),
+        ),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    };
+    Ok(resp)
+}
+
+#[post("/logout")]
+async fn post_logout(
+    sessions: web::Data<Sessions>,
+    session: Session,
+    form: web::Form<CsrfForm>,
+) -> Result<HttpResponse, SessionError> {
+    session.check_csrf(&form.csrf)?;
+    info!("Signed out");
+    Ok(HttpResponse::SeeOther()
+        .header(header::LOCATION, LOGIN_PATH)
+        .cookie(sessions.end())
+        .finish())
+}
+
+/// Signing in and out.
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_login)
+        .service(post_login)
+        .service(post_logout);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+
+    fn new_sessions() -> Sessions {
+        Sessions::new(&SessionConfig::default(), false).unwrap()
+    }
+
+    fn handlebars() -> Handlebars<'static> {
+        let mut hb = Handlebars::new();
+        hb.register_templates_directory(".html", "./templates")
+            .unwrap();
+        hb
+    }
+
+    #[test]
+    pub fn test_session_cookie() {
+        let sessions = new_sessions();
+        let cookie = sessions.start("key");
+        assert!(cookie.http_only().unwrap());
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+        // The key can't be read from the cookie, or the cookie changed.
+        assert!(!cookie.value().contains("key"));
+
+        let req = TestRequest::default()
+            .cookie(cookie.clone())
+            .to_http_request();
+        let session = sessions.session(&req).unwrap();
+        assert_eq!(session.api_key, "key");
+        assert!(session.check_csrf(&session.csrf).is_ok());
+        assert!(session.check_csrf("guess").is_err());
+
+        let mut tampered = cookie.clone();
+        tampered.set_value(format!("{}x", cookie.value()));
+        let req = TestRequest::default().cookie(tampered).to_http_request();
+        assert!(sessions.session(&req).is_none());
+        // Nor is it any good to a server with another key.
+        let req = TestRequest::default().cookie(cookie).to_http_request();
+        assert!(new_sessions().session(&req).is_none());
+    }
+
+    // The CSRF token in a page's form.
+    fn form_csrf(body: &str) -> String {
+        body.split("name=\"csrf\" value=\"")
+            .nth(1)
+            .unwrap()
+            .split('"')
+            .next()
+            .unwrap()
+            .to_string()
+    }
+
+    #[actix_rt::test]
+    pub async fn test_login() {
+        let db = Db::new("file::memory:").await.unwrap();
+        let key = db.register_user("login@bar.com").await.unwrap().apikey;
+        let sessions = web::Data::new(new_sessions());
+        let mut app = test::init_service(
+            App::new()
+                .app_data(sessions.clone())
+                .app_data(web::Data::new(handlebars()))
+                .app_data(web::Data::new(db))
+                .app_data(web::Data::new(RateLimiter::default()))
+                .configure(config),
+        )
+        .await;
+
+        let resp =
+            test::call_service(&mut app, TestRequest::get().uri(LOGIN_PATH).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let csrf_cookie = resp
+            .response()
+            .cookies()
+            .find(|c| c.name() == LOGIN_CSRF_COOKIE)
+            .unwrap()
+            .into_owned();
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        let csrf = form_csrf(&body);
+
+        let login = |api_key: &str, csrf: &str, cookie: Option<Cookie<'static>>| {
+            let mut req = TestRequest::post()
+                .uri(LOGIN_PATH)
+                .set_form(&json!({"api_key": api_key, "csrf": csrf}));
+            if let Some(c) = cookie {
+                req = req.cookie(c);
+            }
+            req.to_request()
+        };
+
+        // Without the login cookie, as a cross-site form would be sent.
+        let resp = test::call_service(&mut app, login(&key, &csrf, None)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp =
+            test::call_service(&mut app, login("nope", &csrf, Some(csrf_cookie.clone()))).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = test::call_service(&mut app, login(&key, &csrf, Some(csrf_cookie))).await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(resp.headers().get(header::LOCATION).unwrap(), HOME_PATH);
+        let session_cookie = resp
+            .response()
+            .cookies()
+            .find(|c| c.name() == SESSION_COOKIE)
+            .unwrap()
+            .into_owned();
+
+        let logout = |csrf: &str| {
+            TestRequest::post()
+                .uri("/logout")
+                .cookie(session_cookie.clone())
+                .set_form(&json!({"csrf": csrf}))
+                .to_request()
+        };
+        let resp = test::call_service(&mut app, logout("guess")).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = TestRequest::default()
+            .cookie(session_cookie.clone())
+            .to_http_request();
+        let csrf = sessions.session(&req).unwrap().csrf;
+        let resp = test::call_service(&mut app, logout(&csrf)).await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(resp.headers().get(header::LOCATION).unwrap(), LOGIN_PATH);
+        let ended = resp
+            .response()
+            .cookies()
+            .find(|c| c.name() == SESSION_COOKIE)
+            .unwrap();
+        assert_eq!(ended.value(), "");
+        assert_eq!(ended.max_age(), Some(chrono::Duration::zero()));
+    }
+
+    #[actix_rt::test]
+    pub async fn test_login_limited() {
+        let db = Db::new("file::memory:").await.unwrap();
+        let key = db.register_user("limited@bar.com").await.unwrap().apikey;
+        let mut limiter = RateLimiter::default();
+        limiter.set_limit(
+            limit::Method::VerifyKey,
+            limit::Limit {
+                burst: 3,
+                per_second: 0.01,
+            },
+        );
+        let mut app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(new_sessions()))
+                .app_data(web::Data::new(handlebars()))
+                .app_data(web::Data::new(db))
+                .app_data(web::Data::new(limiter))
+                .configure(config),
+        )
+        .await;
+
+        let resp =
+            test::call_service(&mut app, TestRequest::get().uri(LOGIN_PATH).to_request()).await;
+        let csrf_cookie = resp
+            .response()
+            .cookies()
+            .find(|c| c.name() == LOGIN_CSRF_COOKIE)
+            .unwrap()
+            .into_owned();
+        let body = test::read_body(resp).await;
+        let csrf = form_csrf(std::str::from_utf8(&body).unwrap());
+        let login = |api_key: &str, peer: &str| {
+            TestRequest::post()
+                .uri(LOGIN_PATH)
+                .peer_addr(peer.parse().unwrap())
+                .cookie(csrf_cookie.clone())
+                .set_form(&json!({"api_key": api_key, "csrf": csrf}))
+                .to_request()
+        };
+
+        for _ in 0..3 {
+            let resp = test::call_service(&mut app, login("guess", "10.0.0.1:1000")).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+        // Even the right key is turned away once the peer has run out.
+        let resp = test::call_service(&mut app, login(&key, "10.0.0.1:1001")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get(header::RETRY_AFTER).unwrap(), "100");
+
+        // Others still get their tries.
+        let resp = test::call_service(&mut app, login(&key, "10.0.0.2:1000")).await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    }
+}